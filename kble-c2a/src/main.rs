@@ -1,20 +1,34 @@
 use anyhow::Result;
-use bytes::BytesMut;
 use clap::{Parser, Subcommand};
 use futures::{SinkExt, StreamExt};
 use kble_c2a::{spacepacket, tfsync};
+use kble_plug::{codec, ListenArgs, LogArgs, Plug, PlugArgs, SocketSink, SocketStream};
 use notalawyer_clap::*;
-use tokio_util::codec::Decoder;
-use tracing_subscriber::{prelude::*, EnvFilter};
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
+    #[clap(flatten)]
+    log: LogArgs,
+
+    #[clap(flatten)]
+    listen: ListenArgs,
+
     #[clap(subcommand)]
     command: Commands,
 }
 
-#[derive(Subcommand, Debug)]
+impl PlugArgs for Args {
+    fn log_args(&self) -> &LogArgs {
+        &self.log
+    }
+
+    fn listen_args(&self) -> &ListenArgs {
+        &self.listen
+    }
+}
+
+#[derive(Subcommand, Debug, Clone)]
 enum Commands {
     /// Split contiguous AOS Transfer Frames into each frame heuristically
     Tfsync,
@@ -25,7 +39,7 @@ enum Commands {
     },
 }
 
-#[derive(Subcommand, Debug)]
+#[derive(Subcommand, Debug, Clone)]
 enum Spacepacket {
     /// Unwrap Space Packet from TC Transfer Frame (adhoc)
     FromTcTf,
@@ -33,49 +47,34 @@ enum Spacepacket {
     ToAosTf,
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::fmt::layer()
-                .with_ansi(false)
-                .with_writer(std::io::stderr),
-        )
-        .with(EnvFilter::from_default_env())
-        .init();
+fn main() -> Result<()> {
+    kble_plug::main(include_notice!(), |args: &Args| args.command.clone())
+}
 
-    let args = Args::parse_with_license_notice(include_notice!());
-    match args.command {
-        Commands::Tfsync => run_tfsync().await,
-        Commands::Spacepacket { command } => run_spacepacket(command).await,
+impl Plug for Commands {
+    async fn run(self, tx: SocketSink, rx: SocketStream) -> Result<()> {
+        match self {
+            Commands::Tfsync => run_tfsync(tx, rx).await,
+            Commands::Spacepacket { command } => command.run(tx, rx).await,
+        }
     }
 }
 
-async fn run_tfsync() -> Result<()> {
-    let (mut tx, mut rx) = kble_socket::from_stdio().await;
-    let mut buf = BytesMut::new();
-    let mut codec = tfsync::AosTransferFrameCodec::new();
-    loop {
-        let Some(chunk) = rx.next().await else {
-            break;
-        };
-        buf.extend_from_slice(&chunk?);
-        while let Some(frame) = codec.decode(&mut buf)? {
-            tx.send(frame).await?;
+impl Plug for Spacepacket {
+    async fn run(self, tx: SocketSink, rx: SocketStream) -> Result<()> {
+        match self {
+            Spacepacket::FromTcTf => run_sp_from_tc_tf(tx, rx).await,
+            Spacepacket::ToAosTf => run_sp_to_aos_tf(tx, rx).await,
         }
     }
-    Ok(())
 }
 
-async fn run_spacepacket(command: Spacepacket) -> Result<()> {
-    match command {
-        Spacepacket::FromTcTf => run_sp_from_tc_tf().await,
-        Spacepacket::ToAosTf => run_sp_to_aos_tf().await,
-    }
+async fn run_tfsync(mut tx: SocketSink, rx: SocketStream) -> Result<()> {
+    let mut frames = codec::decode(rx, tfsync::AosTransferFrameCodec::new());
+    tx.send_all(&mut frames).await
 }
 
-async fn run_sp_from_tc_tf() -> Result<()> {
-    let (mut tx, mut rx) = kble_socket::from_stdio().await;
+async fn run_sp_from_tc_tf(mut tx: SocketSink, mut rx: SocketStream) -> Result<()> {
     loop {
         let Some(tc_tf) = rx.next().await else {
             break;
@@ -86,8 +85,7 @@ async fn run_sp_from_tc_tf() -> Result<()> {
     Ok(())
 }
 
-async fn run_sp_to_aos_tf() -> Result<()> {
-    let (mut tx, mut rx) = kble_socket::from_stdio().await;
+async fn run_sp_to_aos_tf(mut tx: SocketSink, mut rx: SocketStream) -> Result<()> {
     let mut frame_count = 0;
     while let Some(spacepacket) = rx.next().await {
         for tf in spacepacket::to_aos_tfs(&mut frame_count, spacepacket?)? {
@@ -1,66 +1,119 @@
 use anyhow::Result;
 use clap::{Parser, Subcommand};
 use futures::{SinkExt, StreamExt};
+use kble_plug::{
+    ControlSink, ControlStream, Envelope, EnvelopeSink, EnvelopeStream, Enveloped, EnvelopedPlug,
+    ListenArgs, LogArgs, PlugArgs,
+};
 use notalawyer_clap::*;
-use tracing_subscriber::{prelude::*, EnvFilter};
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
+    #[clap(flatten)]
+    log: LogArgs,
+
+    #[clap(flatten)]
+    listen: ListenArgs,
+
     #[clap(subcommand)]
     command: Commands,
 }
 
+impl PlugArgs for Args {
+    fn log_args(&self) -> &LogArgs {
+        &self.log
+    }
+
+    fn listen_args(&self) -> &ListenArgs {
+        &self.listen
+    }
+}
+
 use std::path::PathBuf;
 
-#[derive(Subcommand, Debug)]
+#[derive(Subcommand, Debug, Clone)]
 enum Commands {
     Record { output_dir: PathBuf },
     Replay { input_file: PathBuf },
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::fmt::layer()
-                .with_ansi(false)
-                .with_writer(std::io::stderr),
-        )
-        .with(EnvFilter::from_default_env())
-        .init();
-
-    let args = Args::parse_with_license_notice(include_notice!());
+fn main() -> Result<()> {
+    kble_plug::main_enveloped(include_notice!(), |args: &Args| args.command.clone())
+}
+
+impl EnvelopedPlug for Commands {
     // `replay` finishes when its input file is exhausted while a spawned task is
     // still reading stdin. With the orchestrator/test-harness's piped stdio,
     // `kble_socket::from_stdio` reads it cancellably, so that no longer hangs
     // shutdown; both subcommands just return and let the runtime shut down
     // normally (which also flushes `record`'s dump-file writes). A non-pipe stdin
     // (e.g. a terminal) still uses the blocking reader.
-    match args.command {
-        Commands::Record { output_dir } => run_record(&output_dir).await,
-        Commands::Replay { input_file } => run_replay(&input_file).await,
+    async fn run(
+        self,
+        tx: EnvelopeSink,
+        rx: EnvelopeStream,
+        _: ControlSink,
+        _: ControlStream,
+    ) -> Result<()> {
+        match self {
+            Commands::Record { output_dir } => run_record(&output_dir, tx, rx).await,
+            Commands::Replay { input_file } => run_replay(&input_file, tx, rx).await,
+        }
     }
 }
 
 use std::borrow::Cow;
 struct DumpRecord<'a> {
     timestamp: std::time::SystemTime,
+    /// The sequence number and origin of the message's envelope, if it had one
+    envelope: Option<(u64, Option<String>)>,
     data: Cow<'a, [u8]>,
 }
 
+/// Set in the subsecond nanos (always below 10^9) of a record with an envelope
+const ENVELOPE_FLAG: u32 = 1 << 31;
+
 impl<'a> DumpRecord<'a> {
-    fn now(data: &'a [u8]) -> Self {
+    /// A record of `data`, timestamped when it was first received if its
+    /// envelope says, else now
+    fn new(envelope: Option<&Envelope>, data: &'a [u8]) -> Self {
         Self {
-            timestamp: std::time::SystemTime::now(),
+            timestamp: envelope
+                .map_or_else(std::time::SystemTime::now, |envelope| envelope.received_at),
+            envelope: envelope.map(|envelope| (envelope.sequence, envelope.origin.clone())),
             data: Cow::Borrowed(data),
         }
     }
 
+    /// The envelope to replay the record with
+    fn envelope(&self) -> Option<Envelope> {
+        let (sequence, origin) = self.envelope.clone()?;
+        Some(Envelope {
+            received_at: self.timestamp,
+            sequence,
+            origin,
+        })
+    }
+
     fn write_to(&self, mut writer: impl std::io::Write) -> anyhow::Result<()> {
         let since_epoch = self.timestamp.duration_since(std::time::UNIX_EPOCH)?;
         writer.write_all(&since_epoch.as_secs().to_le_bytes())?;
-        writer.write_all(&since_epoch.subsec_nanos().to_le_bytes())?;
+        let Some((sequence, origin)) = &self.envelope else {
+            writer.write_all(&since_epoch.subsec_nanos().to_le_bytes())?;
+            writer.write_all(&self.data[..])?;
+            return Ok(());
+        };
+        writer.write_all(&(since_epoch.subsec_nanos() | ENVELOPE_FLAG).to_le_bytes())?;
+        writer.write_all(&sequence.to_le_bytes())?;
+        // Cut on a char boundary to fit its u16 length
+        let origin = origin.as_deref().unwrap_or_default();
+        let mut origin_len = origin.len().min(u16::MAX as usize);
+        while !origin.is_char_boundary(origin_len) {
+            origin_len -= 1;
+        }
+        writer.write_all(&(origin_len as u16).to_le_bytes())?;
+        writer.write_all(&origin.as_bytes()[..origin_len])?;
         writer.write_all(&self.data[..])?;
         Ok(())
     }
@@ -72,28 +125,48 @@ impl<'a> DumpRecord<'a> {
         let mut nanos = [0u8; 4];
         reader.read_exact(&mut nanos)?;
         let nanos = u32::from_le_bytes(nanos);
-        let timestamp = std::time::UNIX_EPOCH + std::time::Duration::new(secs, nanos);
+        let timestamp =
+            std::time::UNIX_EPOCH + std::time::Duration::new(secs, nanos & !ENVELOPE_FLAG);
+        let envelope = if nanos & ENVELOPE_FLAG != 0 {
+            let mut sequence = [0u8; 8];
+            reader.read_exact(&mut sequence)?;
+            let mut origin_len = [0u8; 2];
+            reader.read_exact(&mut origin_len)?;
+            let mut origin = vec![0u8; u16::from_le_bytes(origin_len) as usize];
+            reader.read_exact(&mut origin)?;
+            let origin = String::from_utf8(origin)?;
+            Some((
+                u64::from_le_bytes(sequence),
+                (!origin.is_empty()).then_some(origin),
+            ))
+        } else {
+            None
+        };
         let mut data = vec![];
         reader.read_to_end(&mut data)?;
         Ok(Self {
             timestamp,
+            envelope,
             data: Cow::Owned(data),
         })
     }
 }
 
-async fn run_record(output_dir: &PathBuf) -> Result<()> {
+async fn run_record(
+    output_dir: &PathBuf,
+    mut tx: EnvelopeSink,
+    mut rx: EnvelopeStream,
+) -> Result<()> {
     tokio::fs::create_dir_all(output_dir).await?;
     let time = chrono::Local::now().format("%Y%m%d_%H%M%S_%f");
     let path = output_dir.join(format!("dump_{time}.bin"));
     tracing::info!("Recording to {:?}", path);
     let mut file = tokio::fs::File::create(&path).await?;
 
-    let (mut tx, mut rx) = kble_socket::from_stdio().await;
-    while let Some(data) = rx.next().await {
-        let data = data?;
+    while let Some(msg) = rx.next().await {
+        let msg = msg?;
         let mut buf = vec![];
-        let record = DumpRecord::now(&data[..]);
+        let record = DumpRecord::new(msg.envelope.as_ref(), &msg.data[..]);
         record.write_to(&mut buf)?;
 
         use miniz_oxide::deflate::compress_to_vec;
@@ -101,18 +174,17 @@ async fn run_record(output_dir: &PathBuf) -> Result<()> {
         let bin = rmp_serde::encode::to_vec(&compressed)?;
         use tokio::io::AsyncWriteExt;
         file.write_all(&bin).await?;
-        tx.send(data).await?;
+        tx.send(msg).await?;
     }
     Ok(())
 }
 
-async fn run_replay(input_file: &PathBuf) -> Result<()> {
+async fn run_replay(input_file: &PathBuf, mut tx: EnvelopeSink, rx: EnvelopeStream) -> Result<()> {
     let file = std::fs::File::open(input_file)?;
     let mut file = std::io::BufReader::new(file);
 
     let mut replay_time_offset = None;
 
-    let (mut tx, rx) = kble_socket::from_stdio().await;
     tokio::spawn(/* just consume everything */ rx.count());
     while let Ok(compressed) = rmp_serde::decode::from_read::<_, Vec<u8>>(&mut file) {
         let decompressed =
@@ -132,7 +204,11 @@ async fn run_replay(input_file: &PathBuf) -> Result<()> {
             // Err if the time is in the past
             .unwrap_or_else(|_| std::time::Duration::from_secs(0));
         tokio::time::sleep(sleep_time).await;
-        tx.send(record.data.into_owned().into()).await?;
+        tx.send(Enveloped {
+            envelope: record.envelope(),
+            data: record.data.into_owned().into(),
+        })
+        .await?;
     }
 
     Ok(())
@@ -13,9 +13,13 @@
 //! coverage is generated by `proptest`. Because each round-trip spawns two real
 //! processes, the case counts are deliberately small.
 
+use std::process::Stdio;
+
 use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
 use kble_test_support::Plug;
 use proptest::prelude::*;
+use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::process::Command;
 use tokio::runtime::Runtime;
 
@@ -130,3 +134,85 @@ proptest! {
         prop_assert_eq!(decoded.as_ref(), payload.as_slice());
     }
 }
+
+/// SIGTERM ends a plug cleanly: it closes the WebSocket with Normal Closure
+/// rather than with an error, and exits successfully instead of being killed.
+#[cfg(unix)]
+#[test]
+fn closes_normally_on_sigterm() {
+    runtime().block_on(async {
+        let mut enc = Plug::spawn(eb90("encode"))
+            .await
+            .expect("spawn kble-eb90 encode");
+        // A frame first, so the plug is running with its signal handlers
+        enc.send(Bytes::from_static(b"payload"))
+            .await
+            .expect("send to encode");
+        enc.recv().await.expect("encode produced a frame");
+        let pid = enc.id().expect("encode is running").to_string();
+        let status = Command::new("kill")
+            .args(["-TERM", &pid])
+            .status()
+            .await
+            .expect("run kill");
+        assert!(status.success());
+        // The plug exits right after its Close frame, so the end of the
+        // stream may be the pipe breaking under the client's reply
+        let err = enc
+            .recv()
+            .await
+            .expect_err("the stream ends after the signal");
+        assert!(
+            err.downcast_ref::<kble_socket::CloseError>().is_none(),
+            "the plug closed with an error: {err}"
+        );
+        enc.shutdown().await.expect("encode exits cleanly");
+    });
+}
+
+/// `--listen` serves the plug over WebSocket instead of stdio, making a fresh
+/// plug for each connection, and keeps serving after one ends
+#[test]
+fn serves_over_websocket_with_listen() {
+    runtime().block_on(async {
+        let mut child = eb90("encode")
+            .args(["--listen", "ws://127.0.0.1:0"])
+            .env("RUST_LOG", "info")
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .expect("spawn kble-eb90 encode --listen");
+        let stderr = child.stderr.take().expect("stderr is piped");
+        let mut lines = BufReader::new(stderr).lines();
+        let url = loop {
+            let line = lines
+                .next_line()
+                .await
+                .expect("read stderr")
+                .expect("the plug logs where it listens");
+            if let Some((_, url)) = line.split_once("Listening at ") {
+                break url.trim().to_string();
+            }
+        };
+        for _ in 0..2 {
+            let (ws, _) = tokio_tungstenite::connect_async(&url)
+                .await
+                .expect("connect to the plug");
+            let (mut tx, mut rx) = kble_socket::from_tungstenite(ws);
+            tx.send(Bytes::from_static(b"payload"))
+                .await
+                .expect("send to encode");
+            let encoded = rx
+                .next()
+                .await
+                .expect("encode produced a frame")
+                .expect("encode produced a frame");
+            assert_eq!(decode(encoded).await.as_ref(), b"payload");
+            tx.close().await.expect("close the connection");
+        }
+        assert!(
+            child.try_wait().expect("poll the plug").is_none(),
+            "the plug keeps listening after its connections end"
+        );
+    });
+}
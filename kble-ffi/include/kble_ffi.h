@@ -0,0 +1,71 @@
+#ifndef KBLE_FFI_H
+#define KBLE_FFI_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+// The call succeeded
+#define KBLE_OK 0
+
+// The call failed; see `kble_last_error`
+#define KBLE_ERROR -1
+
+// No message arrived within the timeout
+#define KBLE_TIMEOUT -2
+
+// The peer closed the socket normally; no more messages will arrive
+#define KBLE_CLOSED -3
+
+// The message is longer than the buffer. Its length is stored, and the
+// message is kept for the next `kble_socket_recv`.
+#define KBLE_TRUNCATED -4
+
+// A socket over this process's stdin/stdout, opened by
+// `kble_socket_open_stdio` and freed by `kble_socket_close`
+typedef struct KbleSocket KbleSocket;
+
+// The error of the last call on this thread that returned `KBLE_ERROR` (or
+// `NULL`), or `NULL` if there was none. The string is valid until the next
+// failing call on this thread.
+const char *kble_last_error(void);
+
+// Open a socket over this process's stdin/stdout, for a plug `kble` runs as
+// an `exec:` plug. Returns `NULL` on failure. Call once per process.
+KbleSocket *kble_socket_open_stdio(void);
+
+// Send the `len` bytes at `data` as a message.
+//
+// # Safety
+//
+// `socket` must come from `kble_socket_open_stdio` and not be closed, and
+// `data` must point to `len` readable bytes (or be `NULL` when `len` is 0).
+int kble_socket_send(KbleSocket *socket, const uint8_t *data, size_t len);
+
+// Receive a message into the `capacity` bytes at `buf`, storing its length
+// at `len`. Waits up to `timeout_ms` milliseconds, or indefinitely when
+// negative. Returns `KBLE_TIMEOUT` if none arrived, `KBLE_CLOSED` once the
+// peer closed the socket, and `KBLE_TRUNCATED` for a message longer than
+// `capacity`.
+//
+// # Safety
+//
+// `socket` must come from `kble_socket_open_stdio` and not be closed, `buf`
+// must point to `capacity` writable bytes (or be `NULL` when `capacity` is
+// 0), and `len` must be writable.
+int kble_socket_recv(KbleSocket *socket,
+                     uint8_t *buf,
+                     size_t capacity,
+                     size_t *len,
+                     int32_t timeout_ms);
+
+// Close the socket and free it: normally when `error` is `NULL`, else with
+// Internal Error (1011) and `error` as the reason, as for a plug that failed.
+// `kble` logs and reports the reason.
+//
+// # Safety
+//
+// `socket` must come from `kble_socket_open_stdio` and not be closed, and
+// `error` must be `NULL` or a NUL-terminated string.
+int kble_socket_close(KbleSocket *socket, const char *error);
+
+#endif  /* KBLE_FFI_H */
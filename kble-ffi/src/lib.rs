@@ -0,0 +1,257 @@
+//! A C library for writing plugs, for programs that cannot link
+//! `kble-socket`, such as flight software and simulators written in C. The
+//! API is declared in `include/kble_ffi.h`.
+//!
+//! [`kble_socket_open_stdio`] opens the socket `kble` talks to an `exec:` plug
+//! over, as `kble_socket::run_stdio` does in a Rust plug: WebSocket, or the
+//! length-prefixed framing when `kble` offers it. The socket runs on a
+//! background thread, which also answers `kble`'s Pings, so a plug can block
+//! on its own work between calls.
+//!
+//! ```c
+//! KbleSocket *socket = kble_socket_open_stdio();
+//! uint8_t buf[4096];
+//! size_t len;
+//! while (kble_socket_recv(socket, buf, sizeof buf, &len, -1) == KBLE_OK) {
+//!     kble_socket_send(socket, buf, len);
+//! }
+//! kble_socket_close(socket, NULL);
+//! ```
+//!
+//! Functions return a status, `KBLE_OK` or one of the negative codes below,
+//! and [`kble_last_error`] describes a `KBLE_ERROR`.
+
+use std::{
+    cell::RefCell,
+    ffi::{c_char, c_int, CStr, CString},
+    ptr,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context, Result};
+use bytes::Bytes;
+use futures_util::{SinkExt, StreamExt};
+use kble_socket::{SocketSink, SocketStream};
+use tokio::{
+    runtime::{self, Runtime},
+    sync::oneshot,
+    task::JoinHandle,
+};
+
+/// The call succeeded
+pub const KBLE_OK: c_int = 0;
+/// The call failed; see `kble_last_error`
+pub const KBLE_ERROR: c_int = -1;
+/// No message arrived within the timeout
+pub const KBLE_TIMEOUT: c_int = -2;
+/// The peer closed the socket normally; no more messages will arrive
+pub const KBLE_CLOSED: c_int = -3;
+/// The message is longer than the buffer. Its length is stored, and the
+/// message is kept for the next `kble_socket_recv`.
+pub const KBLE_TRUNCATED: c_int = -4;
+
+/// A socket over this process's stdin/stdout, opened by
+/// `kble_socket_open_stdio` and freed by `kble_socket_close`
+pub struct KbleSocket {
+    runtime: Runtime,
+    sink: SocketSink,
+    stream: SocketStream,
+    /// The stream ended
+    closed: bool,
+    /// A message that did not fit the buffer it was received into
+    pending: Option<Bytes>,
+    /// How the plug ended, for `run_stdio` to close the socket with
+    done: oneshot::Sender<Result<()>>,
+    plug: JoinHandle<Result<()>>,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Record `err` for `kble_last_error`, returning `KBLE_ERROR`
+fn fail(err: anyhow::Error) -> c_int {
+    let message = format!("{err:#}").replace('\0', "");
+    let message = CString::new(message).expect("NUL bytes were removed");
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+    KBLE_ERROR
+}
+
+/// The error of the last call on this thread that returned `KBLE_ERROR` (or
+/// `NULL`), or `NULL` if there was none. The string is valid until the next
+/// failing call on this thread.
+#[no_mangle]
+pub extern "C" fn kble_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |e| e.as_ptr()))
+}
+
+/// Open a socket over this process's stdin/stdout, for a plug `kble` runs as
+/// an `exec:` plug. Returns `NULL` on failure. Call once per process.
+#[no_mangle]
+pub extern "C" fn kble_socket_open_stdio() -> *mut KbleSocket {
+    match open_stdio() {
+        Ok(socket) => Box::into_raw(Box::new(socket)),
+        Err(e) => {
+            fail(e);
+            ptr::null_mut()
+        }
+    }
+}
+
+fn open_stdio() -> Result<KbleSocket> {
+    let runtime = runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .enable_all()
+        .build()
+        .context("Failed to start the socket's runtime")?;
+    let (parts_tx, parts_rx) = oneshot::channel();
+    let (done, done_rx) = oneshot::channel();
+    let plug = runtime.spawn(kble_socket::run_stdio(move |sink, stream| async move {
+        let _ = parts_tx.send((sink, stream));
+        done_rx.await.unwrap_or(Ok(()))
+    }));
+    let (sink, stream) = runtime
+        .block_on(parts_rx)
+        .map_err(|_| anyhow!("Failed to open the socket over stdio"))?;
+    Ok(KbleSocket {
+        runtime,
+        sink,
+        stream,
+        closed: false,
+        pending: None,
+        done,
+        plug,
+    })
+}
+
+/// Send the `len` bytes at `data` as a message.
+///
+/// # Safety
+///
+/// `socket` must come from `kble_socket_open_stdio` and not be closed, and
+/// `data` must point to `len` readable bytes (or be `NULL` when `len` is 0).
+#[no_mangle]
+pub unsafe extern "C" fn kble_socket_send(
+    socket: *mut KbleSocket,
+    data: *const u8,
+    len: usize,
+) -> c_int {
+    let Some(socket) = socket.as_mut() else {
+        return fail(anyhow!("The socket is NULL"));
+    };
+    let data = match len {
+        0 => Bytes::new(),
+        _ => Bytes::copy_from_slice(std::slice::from_raw_parts(data, len)),
+    };
+    match socket.runtime.block_on(socket.sink.send(data)) {
+        Ok(()) => KBLE_OK,
+        Err(e) => fail(e),
+    }
+}
+
+/// Receive a message into the `capacity` bytes at `buf`, storing its length
+/// at `len`. Waits up to `timeout_ms` milliseconds, or indefinitely when
+/// negative. Returns `KBLE_TIMEOUT` if none arrived, `KBLE_CLOSED` once the
+/// peer closed the socket, and `KBLE_TRUNCATED` for a message longer than
+/// `capacity`.
+///
+/// # Safety
+///
+/// `socket` must come from `kble_socket_open_stdio` and not be closed, `buf`
+/// must point to `capacity` writable bytes (or be `NULL` when `capacity` is
+/// 0), and `len` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn kble_socket_recv(
+    socket: *mut KbleSocket,
+    buf: *mut u8,
+    capacity: usize,
+    len: *mut usize,
+    timeout_ms: i32,
+) -> c_int {
+    let Some(socket) = socket.as_mut() else {
+        return fail(anyhow!("The socket is NULL"));
+    };
+    let data = match socket.pending.take() {
+        Some(data) => data,
+        None => match socket.recv(timeout_ms) {
+            Ok(Some(data)) => data,
+            Ok(None) => return KBLE_CLOSED,
+            Err(status) => return status,
+        },
+    };
+    *len = data.len();
+    if data.len() > capacity {
+        socket.pending = Some(data);
+        return KBLE_TRUNCATED;
+    }
+    if !data.is_empty() {
+        ptr::copy_nonoverlapping(data.as_ptr(), buf, data.len());
+    }
+    KBLE_OK
+}
+
+impl KbleSocket {
+    /// The next message, `None` at the end, or the status to fail with
+    fn recv(&mut self, timeout_ms: i32) -> Result<Option<Bytes>, c_int> {
+        if self.closed {
+            return Ok(None);
+        }
+        let next = self.stream.next();
+        let next = match u64::try_from(timeout_ms) {
+            Ok(ms) => {
+                let timeout = async { tokio::time::timeout(Duration::from_millis(ms), next).await };
+                self.runtime.block_on(timeout).map_err(|_| KBLE_TIMEOUT)?
+            }
+            Err(_) => self.runtime.block_on(next),
+        };
+        match next {
+            Some(Ok(data)) => Ok(Some(data)),
+            Some(Err(e)) => {
+                self.closed = true;
+                Err(fail(e))
+            }
+            None => {
+                self.closed = true;
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Close the socket and free it: normally when `error` is `NULL`, else with
+/// Internal Error (1011) and `error` as the reason, as for a plug that failed.
+/// `kble` logs and reports the reason.
+///
+/// # Safety
+///
+/// `socket` must come from `kble_socket_open_stdio` and not be closed, and
+/// `error` must be `NULL` or a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn kble_socket_close(socket: *mut KbleSocket, error: *const c_char) -> c_int {
+    if socket.is_null() {
+        return fail(anyhow!("The socket is NULL"));
+    }
+    let socket = Box::from_raw(socket);
+    let result = match error.as_ref() {
+        None => Ok(()),
+        Some(_) => Err(anyhow!("{}", CStr::from_ptr(error).to_string_lossy())),
+    };
+    let KbleSocket {
+        runtime,
+        sink,
+        stream,
+        done,
+        plug,
+        ..
+    } = *socket;
+    let _ = done.send(result);
+    // `run_stdio` returns the error it closed with, which is no failure here
+    let joined = runtime.block_on(plug);
+    drop((sink, stream));
+    // Everything was flushed with the Close, so nothing is left to wait for
+    runtime.shutdown_background();
+    match joined {
+        Ok(_) => KBLE_OK,
+        Err(e) => fail(e.into()),
+    }
+}
@@ -0,0 +1,86 @@
+//! End-to-end tests of the C API: `tests/echo.c` is compiled against
+//! `include/kble_ffi.h` and the freshly built `libkble_ffi`, and the test
+//! talks to it as `kble` talks to an `exec:` plug (see
+//! `kble-test-support`'s `Plug`).
+//!
+//! The C compiler is `$CC`, or `cc`.
+
+use std::path::{Path, PathBuf};
+
+use bytes::Bytes;
+use kble_test_support::Plug;
+use tokio::process::Command;
+
+/// The directory cargo built `libkble_ffi` into for this test: the test
+/// binary's (`target/<profile>/deps`)
+fn lib_dir() -> PathBuf {
+    let exe = std::env::current_exe().expect("locate the test binary");
+    exe.parent()
+        .expect("the test binary is in a directory")
+        .to_path_buf()
+}
+
+/// Compile `tests/echo.c` once, returning the binary
+fn echo_plug() -> &'static Path {
+    static ECHO: std::sync::OnceLock<PathBuf> = std::sync::OnceLock::new();
+    ECHO.get_or_init(|| {
+        let manifest = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let lib_dir = lib_dir();
+        let out = Path::new(env!("CARGO_TARGET_TMPDIR")).join("kble-ffi-echo");
+        let cc = std::env::var("CC").unwrap_or_else(|_| "cc".to_string());
+        let status = std::process::Command::new(cc)
+            .args(["-std=c99", "-Wall", "-Wextra", "-Werror", "-I"])
+            .arg(manifest.join("include"))
+            .arg(manifest.join("tests/echo.c"))
+            .arg("-L")
+            .arg(&lib_dir)
+            .arg("-lkble_ffi")
+            .arg(format!("-Wl,-rpath,{}", lib_dir.display()))
+            .arg("-o")
+            .arg(&out)
+            .status()
+            .expect("run the C compiler");
+        assert!(status.success(), "compiling tests/echo.c failed");
+        out
+    })
+}
+
+async fn spawn_echo() -> Plug {
+    Plug::spawn(Command::new(echo_plug()))
+        .await
+        .expect("spawn the C echo plug")
+}
+
+/// Receiving times out while nothing is sent, and messages then echo back:
+/// the empty one into no buffer at all, the others after growing the buffer
+/// they did not fit
+#[tokio::test]
+async fn echoes_from_c() {
+    let mut plug = spawn_echo().await;
+    assert_eq!(plug.recv().await.expect("timeout report"), "timeout");
+
+    for payload in [&b""[..], b"abc", &[0x5a; 64 * 1024]] {
+        let payload = Bytes::copy_from_slice(payload);
+        plug.send(payload.clone()).await.expect("send to the plug");
+        assert_eq!(plug.recv().await.expect("echo"), payload);
+    }
+
+    plug.shutdown().await.expect("the plug exits cleanly");
+}
+
+/// A plug closing with an error reason reaches the peer as a `CloseError`
+#[tokio::test]
+async fn closes_with_the_error_from_c() {
+    let mut plug = spawn_echo().await;
+    assert_eq!(plug.recv().await.expect("timeout report"), "timeout");
+
+    plug.send(Bytes::from_static(b"fail"))
+        .await
+        .expect("send to the plug");
+    let err = plug.recv().await.expect_err("the plug fails");
+    let close = err
+        .downcast_ref::<kble_socket::CloseError>()
+        .unwrap_or_else(|| panic!("not a Close with an error: {err:#}"));
+    assert_eq!(close.code, 1011);
+    assert_eq!(close.reason, "asked to fail");
+}
@@ -0,0 +1,58 @@
+// An `exec:` plug written against kble_ffi.h, for the e2e tests: it reports
+// the first receive timing out as a "timeout" message, then echoes each
+// message back (starting without a buffer, and growing it for a long one)
+// until the peer closes the socket, and fails on a message reading "fail".
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "kble_ffi.h"
+
+static int fail(KbleSocket *socket, const char *what) {
+    fprintf(stderr, "%s: %s\n", what, kble_last_error());
+    kble_socket_close(socket, what);
+    return 1;
+}
+
+int main(void) {
+    KbleSocket *socket = kble_socket_open_stdio();
+    if (socket == NULL) {
+        fprintf(stderr, "open: %s\n", kble_last_error());
+        return 1;
+    }
+    size_t capacity = 0;
+    uint8_t *buf = NULL;
+    size_t len;
+    int32_t timeout_ms = 200;
+    for (;;) {
+        int status = kble_socket_recv(socket, buf, capacity, &len, timeout_ms);
+        if (status == KBLE_TIMEOUT) {
+            timeout_ms = -1;
+            if (kble_socket_send(socket, (const uint8_t *)"timeout", 7) != KBLE_OK) {
+                return fail(socket, "send");
+            }
+            continue;
+        }
+        if (status == KBLE_TRUNCATED) {
+            capacity = len;
+            buf = realloc(buf, capacity);
+            continue;
+        }
+        if (status == KBLE_CLOSED) {
+            break;
+        }
+        if (status != KBLE_OK) {
+            return fail(socket, "recv");
+        }
+        if (len == 4 && memcmp(buf, "fail", 4) == 0) {
+            kble_socket_close(socket, "asked to fail");
+            return 1;
+        }
+        if (kble_socket_send(socket, buf, len) != KBLE_OK) {
+            return fail(socket, "send");
+        }
+    }
+    free(buf);
+    return kble_socket_close(socket, NULL) == KBLE_OK ? 0 : 1;
+}
@@ -0,0 +1,38 @@
+//! The smallest plug: it sends back whatever it receives. The end-to-end tests
+//! run it as a process of its own, e.g. to signal it.
+
+use anyhow::Result;
+use clap::Parser;
+use futures::{SinkExt, StreamExt};
+use kble_plug::{ListenArgs, LogArgs, PlugArgs, SocketSink, SocketStream};
+
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct Args {
+    #[clap(flatten)]
+    log: LogArgs,
+
+    #[clap(flatten)]
+    listen: ListenArgs,
+}
+
+impl PlugArgs for Args {
+    fn log_args(&self) -> &LogArgs {
+        &self.log
+    }
+
+    fn listen_args(&self) -> &ListenArgs {
+        &self.listen
+    }
+}
+
+fn main() -> Result<()> {
+    kble_plug::main("", |_: &Args| {
+        |mut tx: SocketSink, mut rx: SocketStream| async move {
+            while let Some(data) = rx.next().await {
+                tx.send(data?).await?;
+            }
+            Ok(())
+        }
+    })
+}
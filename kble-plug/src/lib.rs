@@ -0,0 +1,373 @@
+//! A framework for writing plugs: the binaries `kble` runs as `exec:` plugs,
+//! which speak a WebSocket over their stdin/stdout.
+//!
+//! Every plug needs the same things around what it does with the data:
+//! `--license-notice` and `--log-format` on its command line, logging, the
+//! socket over stdio, a clean shutdown on SIGINT or SIGTERM, and an error
+//! handed to `kble` in the Close frame when it fails. [`main`] does all of it,
+//! so a plug is its command line ([`PlugArgs`]) and a [`Plug`]. With
+//! `--listen ws://host:port`, the same binary serves the plug over WebSocket
+//! instead, for `kble` to connect to as a `ws://` plug.
+//!
+//! ```no_run
+//! use anyhow::Result;
+//! use clap::Parser;
+//! use futures::{SinkExt, StreamExt};
+//! use kble_plug::{ListenArgs, LogArgs, PlugArgs, SocketSink, SocketStream};
+//!
+//! /// The license notice: `notalawyer_clap::include_notice!()` in a plug built
+//! /// with notalawyer-build
+//! const NOTICE: &str = "";
+//!
+//! #[derive(Parser, Debug)]
+//! #[clap(author, version, about, long_about = None)]
+//! struct Args {
+//!     #[clap(flatten)]
+//!     log: LogArgs,
+//!
+//!     #[clap(flatten)]
+//!     listen: ListenArgs,
+//! }
+//!
+//! impl PlugArgs for Args {
+//!     fn log_args(&self) -> &LogArgs {
+//!         &self.log
+//!     }
+//!
+//!     fn listen_args(&self) -> &ListenArgs {
+//!         &self.listen
+//!     }
+//! }
+//!
+//! fn main() -> Result<()> {
+//!     kble_plug::main(NOTICE, |_: &Args| {
+//!         |mut tx: SocketSink, mut rx: SocketStream| async move {
+//!             while let Some(data) = rx.next().await {
+//!                 tx.send(data?).await?;
+//!             }
+//!             Ok(())
+//!         }
+//!     })
+//! }
+//! ```
+
+use std::{future::Future, io, time::Duration};
+
+use anyhow::Result;
+use kble_socket::Listener;
+use notalawyer_clap::ParseExt;
+use tokio::{net::TcpStream, sync::watch};
+use tokio_tungstenite::WebSocketStream;
+use tracing::{info, warn};
+
+/// How long `serve` waits after failing to accept a connection, e.g. out of
+/// file descriptors, before trying again
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+pub use kble_socket::{
+    batch::{self, BatchArgs},
+    codec,
+    logging::LogArgs,
+    Builder, ControlSink, ControlStream, Envelope, EnvelopeSink, EnvelopeStream, Enveloped,
+    ListenAddr, ListenArgs, SocketSink, SocketStream,
+};
+
+/// The command line of a plug binary, with a flattened [`LogArgs`] and
+/// [`ListenArgs`]
+pub trait PlugArgs: clap::Parser {
+    fn log_args(&self) -> &LogArgs;
+
+    fn listen_args(&self) -> &ListenArgs;
+
+    /// Options of the socket, e.g. a size limit for large messages
+    fn socket(&self) -> Builder {
+        Builder::new()
+    }
+}
+
+/// What a plug does with the data `kble` sends it, and what it sends back.
+/// An async closure over the sink and the stream is a plug too.
+pub trait Plug {
+    /// Run until done. Returning ends the plug, and an error is sent to
+    /// `kble` in the Close frame.
+    fn run(self, tx: SocketSink, rx: SocketStream) -> impl Future<Output = Result<()>>;
+}
+
+impl<F, Fut> Plug for F
+where
+    F: FnOnce(SocketSink, SocketStream) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    fn run(self, tx: SocketSink, rx: SocketStream) -> impl Future<Output = Result<()>> {
+        self(tx, rx)
+    }
+}
+
+/// A [`Plug`] that asks `kble` for [`Envelope`]s, and has the socket's
+/// control channel. See [`Builder::envelopes`].
+pub trait EnvelopedPlug {
+    fn run(
+        self,
+        tx: EnvelopeSink,
+        rx: EnvelopeStream,
+        control_tx: ControlSink,
+        control_rx: ControlStream,
+    ) -> impl Future<Output = Result<()>>;
+}
+
+impl<F, Fut> EnvelopedPlug for F
+where
+    F: FnOnce(EnvelopeSink, EnvelopeStream, ControlSink, ControlStream) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    fn run(
+        self,
+        tx: EnvelopeSink,
+        rx: EnvelopeStream,
+        control_tx: ControlSink,
+        control_rx: ControlStream,
+    ) -> impl Future<Output = Result<()>> {
+        self(tx, rx, control_tx, control_rx)
+    }
+}
+
+/// Run a plug binary: parse the command line, with `--license-notice`
+/// printing `notice`, set up logging, then run the plug that `plug` makes from
+/// the command line over stdio, in a Tokio runtime. The plug ends when it
+/// returns or on SIGINT or SIGTERM, and the WebSocket is closed with its error
+/// or normally (see [`Builder::run_stdio`]). Returns the plug's error, for
+/// `main` to return in turn.
+///
+/// With `--listen`, serve WebSocket connections one at a time instead, making
+/// a plug for each, until SIGINT or SIGTERM. A connection that arrives while
+/// another is served waits for it to end, and a plug's error ends only its
+/// connection.
+pub fn main<A, P>(notice: &str, mut plug: impl FnMut(&A) -> P) -> Result<()>
+where
+    A: PlugArgs,
+    P: Plug,
+{
+    let args = A::parse_with_license_notice(notice);
+    args.log_args().init();
+    let socket = args.socket();
+    tokio::runtime::Runtime::new()?.block_on(async {
+        let shutdown = Shutdown::new()?;
+        let Some(addr) = &args.listen_args().listen else {
+            let plug = plug(&args);
+            return socket
+                .run_stdio(|socket| {
+                    let (tx, rx) = socket.split();
+                    shutdown.until(plug.run(tx, rx))
+                })
+                .await;
+        };
+        let listener = socket.listen(addr).await?;
+        serve(listener, shutdown.clone(), |ws| {
+            let plug = plug(&args);
+            let shutdown = shutdown.clone();
+            socket.run_tungstenite(ws, |socket| {
+                let (tx, rx) = socket.split();
+                shutdown.until(plug.run(tx, rx))
+            })
+        })
+        .await
+    })
+}
+
+/// [`main`] for an [`EnvelopedPlug`]
+pub fn main_enveloped<A, P>(notice: &str, mut plug: impl FnMut(&A) -> P) -> Result<()>
+where
+    A: PlugArgs,
+    P: EnvelopedPlug,
+{
+    let args = A::parse_with_license_notice(notice);
+    args.log_args().init();
+    let socket = args.socket().envelopes();
+    tokio::runtime::Runtime::new()?.block_on(async {
+        let shutdown = Shutdown::new()?;
+        let Some(addr) = &args.listen_args().listen else {
+            let plug = plug(&args);
+            return socket
+                .run_stdio(|socket| {
+                    let (tx, rx, control_tx, control_rx) = socket.split_enveloped();
+                    shutdown.until(plug.run(tx, rx, control_tx, control_rx))
+                })
+                .await;
+        };
+        let listener = socket.listen(addr).await?;
+        serve(listener, shutdown.clone(), |ws| {
+            let plug = plug(&args);
+            let shutdown = shutdown.clone();
+            socket.run_tungstenite(ws, |socket| {
+                let (tx, rx, control_tx, control_rx) = socket.split_enveloped();
+                shutdown.until(plug.run(tx, rx, control_tx, control_rx))
+            })
+        })
+        .await
+    })
+}
+
+/// Accept connections on `listener` and `run` over each in turn, until
+/// shutdown
+async fn serve<F, Fut>(listener: Listener, mut shutdown: Shutdown, mut run: F) -> Result<()>
+where
+    F: FnMut(WebSocketStream<TcpStream>) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    info!("Listening at ws://{}", listener.local_addr()?);
+    loop {
+        let (ws, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("{e:#}");
+                    // Unlike a failed handshake, this would likely fail again
+                    // at once
+                    if e.downcast_ref::<io::Error>().is_some() {
+                        tokio::time::sleep(ACCEPT_BACKOFF).await;
+                    }
+                    continue;
+                }
+            },
+            _ = shutdown.received() => return Ok(()),
+        };
+        info!(%peer, "Connected");
+        match run(ws).await {
+            Ok(()) => info!(%peer, "Disconnected"),
+            Err(e) => warn!(%peer, "Plug failed: {e:#}"),
+        }
+        if shutdown.is_received() {
+            return Ok(());
+        }
+    }
+}
+
+/// Whether SIGINT or SIGTERM was received
+#[derive(Clone)]
+struct Shutdown(watch::Receiver<bool>);
+
+impl Shutdown {
+    /// Install the signal handlers. Must be called within a Tokio runtime.
+    fn new() -> Result<Self> {
+        let signal = shutdown_signal()?;
+        let (tx, rx) = watch::channel(false);
+        tokio::spawn(async move {
+            let signal = signal.await;
+            info!("Received {signal}, shutting down");
+            let _ = tx.send(true);
+        });
+        Ok(Shutdown(rx))
+    }
+
+    async fn received(&mut self) {
+        let _ = self.0.wait_for(|received| *received).await;
+    }
+
+    fn is_received(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Run `plug` until it returns, or until shutdown ends it cleanly
+    async fn until(mut self, plug: impl Future<Output = Result<()>>) -> Result<()> {
+        tokio::select! {
+            result = plug => result,
+            _ = self.received() => Ok(()),
+        }
+    }
+}
+
+/// Wait for SIGINT or SIGTERM, and name it
+#[cfg(unix)]
+fn shutdown_signal() -> Result<impl Future<Output = &'static str>> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    Ok(async move {
+        tokio::select! {
+            _ = interrupt.recv() => "SIGINT",
+            _ = terminate.recv() => "SIGTERM",
+        }
+    })
+}
+
+/// Wait for Ctrl-C, and name it
+#[cfg(not(unix))]
+fn shutdown_signal() -> Result<impl Future<Output = &'static str>> {
+    Ok(async {
+        let _ = tokio::signal::ctrl_c().await;
+        "Ctrl-C"
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::Message;
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    async fn echo(socket: kble_socket::Socket) -> Result<()> {
+        let (mut tx, mut rx) = socket.split();
+        while let Some(data) = rx.next().await {
+            tx.send(data?).await?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_serve_one_connection_at_a_time() {
+        let socket = Builder::new();
+        let listener = socket
+            .listen(&"ws://127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let url = format!("ws://{}/", listener.local_addr().unwrap());
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let serving = tokio::spawn(async move {
+            serve(listener, Shutdown(shutdown_rx), |ws| {
+                socket.run_tungstenite(ws, echo)
+            })
+            .await
+        });
+
+        let (mut first, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let second = tokio::spawn(tokio_tungstenite::connect_async(url));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!second.is_finished(), "served while the first is");
+        first
+            .send(Message::Binary(b"first".to_vec()))
+            .await
+            .unwrap();
+        let echoed = tokio::time::timeout(TIMEOUT, first.next()).await.unwrap();
+        assert_eq!(echoed.unwrap().unwrap(), Message::Binary(b"first".to_vec()));
+        first.close(None).await.unwrap();
+
+        let (mut second, _) = tokio::time::timeout(TIMEOUT, second)
+            .await
+            .expect("the second is served once the first ends")
+            .unwrap()
+            .unwrap();
+        second
+            .send(Message::Binary(b"second".to_vec()))
+            .await
+            .unwrap();
+        let echoed = tokio::time::timeout(TIMEOUT, second.next()).await.unwrap();
+        assert_eq!(
+            echoed.unwrap().unwrap(),
+            Message::Binary(b"second".to_vec())
+        );
+
+        shutdown_tx.send(true).unwrap();
+        // The connection being served still ends it first
+        second.close(None).await.unwrap();
+        tokio::time::timeout(TIMEOUT, serving)
+            .await
+            .expect("serving ends on shutdown")
+            .unwrap()
+            .unwrap();
+    }
+}
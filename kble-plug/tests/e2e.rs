@@ -0,0 +1,59 @@
+//! End-to-end tests that run the `echo` example as a plug process and talk to
+//! it over WebSocket-over-stdio, as the `kble` orchestrator does.
+
+use bytes::Bytes;
+use kble_test_support::Plug;
+use tokio::process::Command;
+
+/// The `echo` example, next to the directory of the test binary. `cargo test`
+/// builds it along with this test, but not when only a test is selected, as
+/// with `--test e2e`.
+fn echo() -> Command {
+    let mut dir = std::env::current_exe().expect("test binary path");
+    dir.pop();
+    if dir.ends_with("deps") {
+        dir.pop();
+    }
+    let path = dir.join("examples").join("echo");
+    assert!(
+        path.exists(),
+        "{} is not built: run `cargo test` without selecting a test target",
+        path.display()
+    );
+    Command::new(path)
+}
+
+/// SIGINT and SIGTERM both end a plug cleanly: it closes the WebSocket with
+/// Normal Closure rather than with an error, and exits successfully.
+#[cfg(unix)]
+#[tokio::test]
+async fn closes_normally_on_sigint_and_sigterm() {
+    for signal in ["-INT", "-TERM"] {
+        let mut plug = Plug::spawn(echo()).await.expect("spawn the echo plug");
+        // A frame first, so the plug is running with its signal handlers
+        plug.send(Bytes::from_static(b"payload"))
+            .await
+            .expect("send to the plug");
+        plug.recv().await.expect("the plug echoed the frame");
+        let pid = plug.id().expect("the plug is running").to_string();
+        let status = Command::new("kill")
+            .args([signal, &pid])
+            .status()
+            .await
+            .expect("run kill");
+        assert!(status.success());
+        // The plug exits right after its Close frame, so the end of the
+        // stream may be the pipe breaking under the client's reply
+        let err = plug
+            .recv()
+            .await
+            .expect_err("the stream ends after the signal");
+        assert!(
+            err.downcast_ref::<kble_socket::CloseError>().is_none(),
+            "the plug closed with an error on {signal}: {err}"
+        );
+        plug.shutdown()
+            .await
+            .unwrap_or_else(|e| panic!("the plug exits cleanly on {signal}: {e:#}"));
+    }
+}
@@ -1,23 +1,25 @@
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::{Duration, SystemTime},
+};
 
-use anyhow::Result;
+use anyhow::{ensure, Result};
 use axum::{
-    extract::{ws::WebSocket, Query, WebSocketUpgrade},
+    extract::{ws::WebSocket, Query, State, WebSocketUpgrade},
     http::StatusCode,
     response::Response,
     routing::get,
     Router,
 };
-use bytes::BytesMut;
 use clap::Parser;
 use futures::{SinkExt, StreamExt};
-use kble_socket::from_axum;
+use kble_socket::{batch::BatchArgs, logging::LogArgs, Envelope, Enveloped};
 use notalawyer_clap::*;
 use serde::Deserialize;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::AsyncWriteExt;
 use tokio_serial::{DataBits, FlowControl, Parity, SerialPortBuilderExt, SerialStream, StopBits};
-use tracing::error;
-use tracing_subscriber::{prelude::*, EnvFilter};
+use tokio_util::io::ReaderStream;
+use tracing::{error, warn};
 
 #[derive(Debug, Deserialize)]
 #[serde(remote = "DataBits")]
@@ -93,26 +95,61 @@ fn stopbits_default() -> StopBits {
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
+    #[clap(flatten)]
+    log: LogArgs,
+
     #[clap(action, long, env, default_value_t = Ipv4Addr::UNSPECIFIED.into())]
     addr: IpAddr,
     #[clap(action, long, env, default_value_t = 9600)]
     port: u16,
+    /// Ping each client this often, and drop it once it has been silent for
+    /// --keepalive-timeout-secs
+    #[clap(action, long, env, requires = "keepalive_timeout_secs", value_parser = clap::value_parser!(u64).range(1..))]
+    keepalive_interval_secs: Option<u64>,
+    #[clap(action, long, env, requires = "keepalive_interval_secs")]
+    keepalive_timeout_secs: Option<u64>,
+
+    #[clap(flatten)]
+    batch: BatchArgs,
+}
+
+/// What each connection is served with
+#[derive(Clone)]
+struct Config {
+    builder: kble_socket::Builder,
+    batch: BatchArgs,
+}
+
+impl Args {
+    fn socket_builder(&self) -> Result<kble_socket::Builder> {
+        let builder = kble_socket::Builder::new().envelopes();
+        let (Some(interval), Some(timeout)) =
+            (self.keepalive_interval_secs, self.keepalive_timeout_secs)
+        else {
+            return Ok(builder);
+        };
+        ensure!(
+            timeout > interval,
+            "--keepalive-timeout-secs must be longer than --keepalive-interval-secs"
+        );
+        Ok(builder.keepalive(kble_socket::Keepalive {
+            interval: Duration::from_secs(interval),
+            timeout: Duration::from_secs(timeout),
+        }))
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse_with_license_notice(include_notice!());
-
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::fmt::layer()
-                .with_ansi(false)
-                .with_writer(std::io::stderr),
-        )
-        .with(EnvFilter::from_default_env())
-        .init();
-
-    let app = Router::new().route("/open", get(handle_get));
+    args.log.init();
+
+    let app = Router::new()
+        .route("/open", get(handle_get))
+        .with_state(Config {
+            builder: args.socket_builder()?,
+            batch: args.batch.clone(),
+        });
     let addr = SocketAddr::new(args.addr, args.port);
     axum::Server::bind(&addr)
         .serve(app.into_make_service())
@@ -121,6 +158,7 @@ async fn main() -> Result<()> {
 }
 
 async fn handle_get(
+    State(config): State<Config>,
     upgrade: WebSocketUpgrade,
     opts: Query<SerialPortOptions>,
 ) -> Result<Response, StatusCode> {
@@ -134,34 +172,58 @@ async fn handle_get(
             error!("{:?}", err);
             StatusCode::INTERNAL_SERVER_ERROR
         })?;
-    Ok(upgrade.on_upgrade(|ws| handle_ws(ws, serialport)))
+    Ok(upgrade.on_upgrade(move |ws| handle_ws(ws, serialport, config)))
 }
 
-async fn handle_ws(ws: WebSocket, serialport: SerialStream) {
-    let (mut sink, mut stream) = from_axum(ws);
-    let (mut rx, mut tx) = tokio::io::split(serialport);
-    let rx_fut = async {
-        loop {
-            let mut buf = BytesMut::with_capacity(4096);
-            let len = rx.read_buf(&mut buf).await?;
-            if len == 0 {
-                break;
-            }
-            sink.send(buf.freeze()).await?;
-        }
-        anyhow::Ok(())
-    };
-    let tx_fut = async {
-        loop {
-            let Some(chunk) = stream.next().await else {
-                break;
+async fn handle_ws(ws: WebSocket, serialport: SerialStream, config: Config) {
+    // A serial port error is reported to the client in the Close frame
+    let result = config
+        .builder
+        .run_axum(ws, |socket| async move {
+            let (mut sink, mut stream, _, _) = socket.split_enveloped();
+            let (rx, mut tx) = tokio::io::split(serialport);
+            let rx_fut = async {
+                let chunks = ReaderStream::with_capacity(rx, 4096);
+                let mut messages = config
+                    .batch
+                    .apply(chunks.map(|chunk| chunk.map_err(Into::into)));
+                for sequence in 0.. {
+                    let Some(data) = messages.next().await else {
+                        break;
+                    };
+                    // Stamped once the message is complete, for peers that ask for envelopes
+                    let envelope = Envelope {
+                        received_at: SystemTime::now(),
+                        sequence,
+                        origin: None,
+                    };
+                    sink.send(Enveloped {
+                        envelope: Some(envelope),
+                        data: data?,
+                    })
+                    .await?;
+                }
+                anyhow::Ok(())
             };
-            let chunk = chunk?;
-            tx.write_all(&chunk).await?;
-        }
-        tx.flush().await?;
-        anyhow::Ok(())
-    };
-    tokio::pin!(rx_fut, tx_fut);
-    futures::future::try_select(rx_fut, tx_fut).await.ok();
+            let tx_fut = async {
+                loop {
+                    let Some(chunk) = stream.next().await else {
+                        break;
+                    };
+                    let chunk = chunk?;
+                    tx.write_all(&chunk.data).await?;
+                }
+                tx.flush().await?;
+                anyhow::Ok(())
+            };
+            tokio::pin!(rx_fut, tx_fut);
+            match futures::future::try_select(rx_fut, tx_fut).await {
+                Ok(_) => Ok(()),
+                Err(e) => Err(e.factor_first().0),
+            }
+        })
+        .await;
+    if let Err(e) = result {
+        warn!("{e:#}");
+    }
 }
@@ -68,9 +68,15 @@ static SETUP: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
 /// Spawn kble-serialport on a free port and wait until it is listening, with
 /// port selection serialized (see [`SETUP`]).
 async fn spawn_server() -> (Child, u16) {
+    spawn_server_with(&[]).await
+}
+
+/// [`spawn_server`] with extra command-line arguments.
+async fn spawn_server_with(args: &[&str]) -> (Child, u16) {
     let _setup = SETUP.lock().await;
     let port = free_port();
     let child = kble_serialport(port)
+        .args(args)
         .spawn()
         .expect("spawn kble-serialport");
     wait_until_listening(port).await;
@@ -82,6 +88,11 @@ async fn spawn_server() -> (Child, u16) {
 /// master (the "device" end the test reads from and writes to), and the pty
 /// slave handle, which the caller must keep alive — see below.
 async fn spawn_bridge() -> (Child, Ws, TTYPort, TTYPort) {
+    spawn_bridge_with(&[]).await
+}
+
+/// [`spawn_bridge`] with extra command-line arguments for the server.
+async fn spawn_bridge_with(args: &[&str]) -> (Child, Ws, TTYPort, TTYPort) {
     let (master, slave) = TTYPort::pair().expect("create pty pair");
     // /dev/pts/N on Linux, /dev/ttysNNN on macOS — kble-serialport just needs a path.
     let slave_name = slave.name().expect("slave pty has a device path");
@@ -92,7 +103,7 @@ async fn spawn_bridge() -> (Child, Ws, TTYPort, TTYPort) {
     // typewriter"). Holding it open keeps the pts alive; a tty has a single shared
     // input queue that kble-serialport drains, so our unread handle steals no bytes.
 
-    let (child, port) = spawn_server().await;
+    let (child, port) = spawn_server_with(args).await;
 
     let url = format!("ws://127.0.0.1:{port}/open?port={slave_name}&baudrate=9600");
     let (ws, _resp) = tokio_tungstenite::connect_async(url)
@@ -218,3 +229,78 @@ async fn rejects_an_unopenable_serial_port() {
 
     child.kill().await.ok();
 }
+
+/// With keepalive on, the server pings an idle client and drops one that stops
+/// answering: a client that never reads never sends a Pong, so once the
+/// timeout passes the connection is closed under it.
+#[tokio::test]
+async fn drops_a_client_that_stops_answering_pings() {
+    let (mut child, mut ws, _master, _slave) = spawn_bridge_with(&[
+        "--keepalive-interval-secs",
+        "1",
+        "--keepalive-timeout-secs",
+        "2",
+    ])
+    .await;
+
+    tokio::time::sleep(Duration::from_secs(4)).await;
+
+    // Reading now drains the Pings sent meanwhile, then hits the end of the
+    // connection. (Reading also answers the Pings, but too late.)
+    let mut pings = 0;
+    loop {
+        let msg = tokio::time::timeout(Duration::from_secs(5), ws.next())
+            .await
+            .expect("the server never dropped the connection");
+        match msg {
+            Some(Ok(Message::Ping(_))) => pings += 1,
+            Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+            Some(Ok(other)) => panic!("unexpected frame: {other:?}"),
+        }
+    }
+    assert!(pings >= 1, "the server never pinged the idle client");
+
+    child.kill().await.ok();
+}
+
+/// A client that keeps reading answers every Ping, so an idle but live
+/// connection outlasts the keepalive timeout and still carries data.
+#[tokio::test]
+async fn keeps_an_idle_client_that_answers_pings() {
+    let (mut child, mut ws, master, _slave) = spawn_bridge_with(&[
+        "--keepalive-interval-secs",
+        "1",
+        "--keepalive-timeout-secs",
+        "2",
+    ])
+    .await;
+
+    // Idle past the timeout, while reading (and so answering Pings)
+    let idle = tokio::time::timeout(Duration::from_secs(4), async {
+        loop {
+            match ws.next().await {
+                Some(Ok(Message::Ping(_))) => {}
+                other => panic!("unexpected frame on an idle connection: {other:?}"),
+            }
+        }
+    });
+    assert!(idle.await.is_err());
+
+    let payload = b"still alive";
+    let _master = device_write(master, payload).await;
+    let got = loop {
+        let msg = tokio::time::timeout(Duration::from_secs(5), ws.next())
+            .await
+            .expect("ws frame timed out")
+            .expect("ws stream ended early")
+            .expect("ws frame");
+        match msg {
+            Message::Ping(_) => {}
+            Message::Binary(b) => break b,
+            other => panic!("expected a binary frame, got: {other:?}"),
+        }
+    };
+    assert_eq!(got, payload);
+
+    child.kill().await.ok();
+}
@@ -0,0 +1,86 @@
+//! Throughput of the two transports a stdio plug can speak, WebSocket and the
+//! negotiated length-prefixed framing, over an in-memory pipe.
+//!
+//! Messages flow from the client (`kble`'s end, which masks WebSocket frames)
+//! to the server (the plug's end), as commands to a plug do. Run with
+//! `cargo bench -p kble-socket --features tungstenite --bench stdio_framing`.
+
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use futures_util::{SinkExt, StreamExt};
+use kble_socket::{from_length_prefixed, from_tungstenite, SocketSink, SocketStream};
+use tokio::io::DuplexStream;
+use tokio_tungstenite::{tungstenite::protocol::Role, WebSocketStream};
+
+const SIZES: [usize; 4] = [64, 1024, 16 * 1024, 256 * 1024];
+/// Bytes sent per measurement, so every size runs for a comparable time
+const VOLUME: usize = 256 * 1024 * 1024;
+/// The capacity of the in-memory pipe, about that of a Linux pipe
+const PIPE: usize = 64 * 1024;
+
+#[tokio::main]
+async fn main() {
+    println!(
+        "{:>10} {:>16} {:>16} {:>8}",
+        "size", "websocket", "length-prefixed", "speedup"
+    );
+    for size in SIZES {
+        let websocket = measure(size, websocket_pair).await;
+        let length_prefixed = measure(size, length_prefixed_pair).await;
+        println!(
+            "{:>10} {:>11.1} MiB/s {:>11.1} MiB/s {:>7.2}x",
+            size,
+            mib_per_sec(websocket),
+            mib_per_sec(length_prefixed),
+            websocket.as_secs_f64() / length_prefixed.as_secs_f64(),
+        );
+    }
+}
+
+async fn websocket_pair() -> (SocketSink, SocketStream) {
+    let (client, server) = tokio::io::duplex(PIPE);
+    let client = WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+    let server = WebSocketStream::from_raw_socket(server, Role::Server, None).await;
+    let (tx, _) = from_tungstenite(client);
+    let (_, rx) = from_tungstenite(server);
+    (tx, rx)
+}
+
+async fn length_prefixed_pair() -> (SocketSink, SocketStream) {
+    let (client, server): (DuplexStream, DuplexStream) = tokio::io::duplex(PIPE);
+    let (tx, _) = from_length_prefixed(client);
+    let (_, rx) = from_length_prefixed(server);
+    (tx, rx)
+}
+
+/// The time to send [`VOLUME`] bytes in `size`-byte messages and receive them
+async fn measure<F, Fut>(size: usize, pair: F) -> Duration
+where
+    F: FnOnce() -> Fut,
+    Fut: std::future::Future<Output = (SocketSink, SocketStream)>,
+{
+    let (mut tx, mut rx) = pair().await;
+    let count = VOLUME / size;
+    let message = Bytes::from(vec![0x5a; size]);
+
+    let start = Instant::now();
+    let send = tokio::spawn(async move {
+        for _ in 0..count {
+            tx.feed(message.clone()).await.expect("send");
+        }
+        tx.flush().await.expect("flush");
+        tx
+    });
+    for _ in 0..count {
+        let got = rx.next().await.expect("stream ended").expect("receive");
+        assert_eq!(got.len(), size);
+    }
+    let elapsed = start.elapsed();
+    drop(send.await.expect("sender task"));
+    elapsed
+}
+
+fn mib_per_sec(elapsed: Duration) -> f64 {
+    VOLUME as f64 / (1024.0 * 1024.0) / elapsed.as_secs_f64()
+}
@@ -1,19 +1,56 @@
-use axum::extract::ws::{Message, WebSocket};
+use axum::extract::ws::{CloseFrame, Message, WebSocket};
 use bytes::Bytes;
 use futures_util::{future, stream, SinkExt, StreamExt, TryStreamExt};
 
-use crate::{SocketSink, SocketStream};
+use crate::{
+    message::{self, CloseError, Frame},
+    SocketSink, SocketStream,
+};
 
 pub fn from_axum(ws: WebSocket) -> (SocketSink, SocketStream) {
+    crate::Builder::new().build_axum(ws).split()
+}
+
+pub(crate) fn split(ws: WebSocket) -> (SocketSink, SocketStream) {
     let (sink, stream) = ws.split();
     let sink = sink
         .with_flat_map(|b| stream::iter([Ok(Message::Binary(Bytes::into(b)))]))
         .sink_map_err(Into::into);
     let stream = stream
-        .try_filter_map(|msg| match msg {
-            Message::Binary(b) => future::ok(Some(b.into())),
-            _ => future::ok(None),
-        })
-        .map_err(Into::into);
+        .map_err(message::receive_error)
+        .try_filter_map(|msg| future::ready(message::stream_item(msg)));
     (Box::pin(sink), Box::pin(stream))
 }
+
+impl message::Message for Message {
+    fn binary(data: Bytes) -> Self {
+        Message::Binary(data.into())
+    }
+
+    fn text(text: String) -> Self {
+        Message::Text(text)
+    }
+
+    fn ping() -> Self {
+        Message::Ping(Vec::new())
+    }
+
+    fn close(code: u16, reason: String) -> Self {
+        Message::Close(Some(CloseFrame {
+            code,
+            reason: reason.into(),
+        }))
+    }
+
+    fn into_frame(self) -> Frame {
+        match self {
+            Message::Binary(b) => Frame::Binary(b.into()),
+            Message::Text(text) => Frame::Text(text),
+            Message::Close(frame) => Frame::Close(frame.map(|frame| CloseError {
+                code: frame.code,
+                reason: frame.reason.into_owned(),
+            })),
+            _ => Frame::Other,
+        }
+    }
+}
@@ -0,0 +1,276 @@
+//! Resizing the messages of a plug that bridges a byte stream, such as a
+//! serial port or a TCP connection, where message boundaries are only where
+//! the reads happened to end.
+//!
+//! [`coalesce`] joins small chunks, e.g. the handful of bytes a serial read
+//! returns, into larger messages, so WebSocket framing no longer dominates.
+//! [`split`] cuts oversized messages down to a size the peer accepts. A plug
+//! flattens [`BatchArgs`] into its command line and passes what it reads
+//! through [`BatchArgs::apply`].
+//!
+//! ```ignore
+//! let chunks = ReaderStream::new(serialport).map(|chunk| chunk.map_err(Into::into));
+//! let mut messages = args.batch.apply(chunks);
+//! while let Some(data) = messages.next().await {
+//!     tx.send(data?).await?;
+//! }
+//! ```
+
+use std::{
+    future::Future,
+    num::NonZeroUsize,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use anyhow::Result;
+use bytes::{Bytes, BytesMut};
+use futures_util::Stream;
+use pin_project_lite::pin_project;
+use tokio::time::{Instant, Sleep};
+
+use crate::SocketStream;
+
+/// Command line options to resize what a plug reads from its byte stream
+/// before sending it, to be flattened into a binary's arguments
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct BatchArgs {
+    /// Coalesce what is read into messages of at least this many bytes, sent
+    /// once that many have been read or --coalesce-latency-ms after the first
+    #[clap(long, value_name = "BYTES", global = true)]
+    pub coalesce_bytes: Option<NonZeroUsize>,
+    #[clap(
+        long,
+        value_name = "MS",
+        default_value_t = 10,
+        requires = "coalesce_bytes",
+        global = true
+    )]
+    pub coalesce_latency_ms: u64,
+    /// Split what is read into messages of at most this many bytes
+    #[clap(long, value_name = "BYTES", global = true)]
+    pub split_bytes: Option<NonZeroUsize>,
+}
+
+impl BatchArgs {
+    /// Coalesce and split `chunks` as the options say
+    pub fn apply<S>(&self, chunks: S) -> SocketStream
+    where
+        S: Stream<Item = Result<Bytes>> + Send + 'static,
+    {
+        // Split last, so a coalesced message overshooting its size is cut too
+        match (self.coalesce_bytes, self.split_bytes) {
+            (None, None) => Box::pin(chunks),
+            (Some(min), None) => Box::pin(coalesce(chunks, min.get(), self.coalesce_latency())),
+            (None, Some(max)) => Box::pin(split(chunks, max)),
+            (Some(min), Some(max)) => Box::pin(split(
+                coalesce(chunks, min.get(), self.coalesce_latency()),
+                max,
+            )),
+        }
+    }
+
+    fn coalesce_latency(&self) -> Duration {
+        Duration::from_millis(self.coalesce_latency_ms)
+    }
+}
+
+/// Join the chunks of `stream` into messages of at least `min_bytes`. What
+/// is buffered is sent as it is once `max_latency` has passed since its first
+/// chunk arrived, and when the stream ends or fails. A chunk of `min_bytes`
+/// or more arriving when nothing is buffered is passed on without a copy.
+pub fn coalesce<S>(stream: S, min_bytes: usize, max_latency: Duration) -> Coalesce<S> {
+    Coalesce {
+        stream,
+        min_bytes,
+        max_latency,
+        buf: BytesMut::new(),
+        deadline: None,
+        error: None,
+        done: false,
+    }
+}
+
+/// Cut the messages of `stream` longer than `max_bytes` into consecutive
+/// messages of `max_bytes` and the rest. The parts share the message's buffer.
+pub fn split<S>(stream: S, max_bytes: NonZeroUsize) -> Split<S> {
+    Split {
+        stream,
+        max_bytes: max_bytes.get(),
+        rest: Bytes::new(),
+    }
+}
+
+pin_project! {
+    /// A stream coalescing small chunks; see [`coalesce`]
+    pub struct Coalesce<S> {
+        #[pin]
+        stream: S,
+        min_bytes: usize,
+        max_latency: Duration,
+        buf: BytesMut,
+        // When `buf` is sent at the latest, armed by its first chunk
+        deadline: Option<Pin<Box<Sleep>>>,
+        // Yielded once `buf` is sent
+        error: Option<anyhow::Error>,
+        done: bool,
+    }
+}
+
+impl<S> Stream for Coalesce<S>
+where
+    S: Stream<Item = Result<Bytes>>,
+{
+    type Item = Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes>>> {
+        let mut this = self.project();
+        while !*this.done {
+            match this.stream.as_mut().poll_next(cx) {
+                Poll::Ready(Some(Ok(chunk))) => {
+                    if this.buf.is_empty() {
+                        if chunk.len() >= *this.min_bytes {
+                            return Poll::Ready(Some(Ok(chunk)));
+                        }
+                        let deadline = Instant::now() + *this.max_latency;
+                        match this.deadline {
+                            Some(sleep) => sleep.as_mut().reset(deadline),
+                            None => {
+                                *this.deadline = Some(Box::pin(tokio::time::sleep_until(deadline)))
+                            }
+                        }
+                    }
+                    this.buf.extend_from_slice(&chunk);
+                    if this.buf.len() >= *this.min_bytes {
+                        return Poll::Ready(Some(Ok(this.buf.split().freeze())));
+                    }
+                }
+                Poll::Ready(Some(Err(e))) => {
+                    *this.error = Some(e);
+                    *this.done = true;
+                }
+                Poll::Ready(None) => *this.done = true,
+                Poll::Pending => {
+                    let due = |sleep: &mut Pin<Box<Sleep>>| sleep.as_mut().poll(cx).is_ready();
+                    if !this.buf.is_empty() && this.deadline.as_mut().is_some_and(due) {
+                        return Poll::Ready(Some(Ok(this.buf.split().freeze())));
+                    }
+                    return Poll::Pending;
+                }
+            }
+        }
+        if !this.buf.is_empty() {
+            return Poll::Ready(Some(Ok(this.buf.split().freeze())));
+        }
+        Poll::Ready(this.error.take().map(Err))
+    }
+}
+
+pin_project! {
+    /// A stream splitting oversized messages; see [`split`]
+    pub struct Split<S> {
+        #[pin]
+        stream: S,
+        max_bytes: usize,
+        // What is left of the message being split
+        rest: Bytes,
+    }
+}
+
+impl<S> Stream for Split<S>
+where
+    S: Stream<Item = Result<Bytes>>,
+{
+    type Item = Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes>>> {
+        let this = self.project();
+        if this.rest.is_empty() {
+            match std::task::ready!(this.stream.poll_next(cx)) {
+                Some(Ok(data)) => *this.rest = data,
+                other => return Poll::Ready(other),
+            }
+            // An empty message is passed on as it is
+            if this.rest.is_empty() {
+                return Poll::Ready(Some(Ok(Bytes::new())));
+            }
+        }
+        let len = this.rest.len().min(*this.max_bytes);
+        Poll::Ready(Some(Ok(this.rest.split_to(len))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{stream, StreamExt};
+
+    use super::*;
+
+    fn chunks(chunks: &[&'static [u8]]) -> impl Stream<Item = Result<Bytes>> + Send + 'static {
+        let chunks = chunks.iter().map(|chunk| Ok(Bytes::from_static(chunk)));
+        stream::iter(chunks.collect::<Vec<_>>())
+    }
+
+    async fn collect(stream: impl Stream<Item = Result<Bytes>>) -> Vec<Bytes> {
+        stream.map(|data| data.unwrap()).collect().await
+    }
+
+    #[tokio::test]
+    async fn test_coalesce_by_size() {
+        let coalesced = coalesce(
+            chunks(&[b"ab", b"cd", b"efghij", b"k"]),
+            4,
+            Duration::from_secs(60),
+        );
+        // The rest is sent when the stream ends
+        assert_eq!(collect(coalesced).await, ["abcd", "efghij", "k"]);
+    }
+
+    #[tokio::test]
+    async fn test_coalesce_by_latency() {
+        let trickle = stream::iter([b"ab", b"cd"])
+            .then(|chunk| async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                Ok(Bytes::from_static(chunk))
+            })
+            .chain(stream::pending());
+        let mut coalesced = std::pin::pin!(coalesce(trickle, 1024, Duration::from_millis(10)));
+        assert_eq!(coalesced.next().await.unwrap().unwrap(), "ab");
+        assert_eq!(coalesced.next().await.unwrap().unwrap(), "cd");
+    }
+
+    #[tokio::test]
+    async fn test_coalesce_sends_the_buffer_before_an_error() {
+        let items = [Ok(Bytes::from_static(b"ab")), Err(anyhow::anyhow!("bad"))];
+        let mut coalesced =
+            std::pin::pin!(coalesce(stream::iter(items), 1024, Duration::from_secs(60)));
+        assert_eq!(coalesced.next().await.unwrap().unwrap(), "ab");
+        assert!(coalesced.next().await.unwrap().is_err());
+        assert!(coalesced.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_split() {
+        let data = Bytes::from_static(b"abcdefghij");
+        let max = NonZeroUsize::new(4).unwrap();
+        let parts = collect(split(
+            stream::iter([Ok(data.clone()), Ok(Bytes::new())]),
+            max,
+        ))
+        .await;
+        assert_eq!(parts, ["abcd", "efgh", "ij", ""]);
+        assert_eq!(parts[1].as_ptr(), data[4..].as_ptr());
+    }
+
+    #[tokio::test]
+    async fn test_args_split_what_is_coalesced() {
+        let args = BatchArgs {
+            coalesce_bytes: NonZeroUsize::new(4),
+            coalesce_latency_ms: 60_000,
+            split_bytes: NonZeroUsize::new(3),
+        };
+        let messages = args.apply(chunks(&[b"ab", b"cde", b"f"]));
+        assert_eq!(collect(messages).await, ["abc", "de", "f"]);
+    }
+}
@@ -0,0 +1,361 @@
+//! Plugs without an async runtime: a [`Socket`] over this process's
+//! stdin/stdout, read and written with plain `std::io`.
+//!
+//! It speaks what [`from_stdio`](crate::from_stdio) does: the WebSocket
+//! server role, or the length-prefixed framing when `kble` offers it. Pings
+//! are answered while the plug is in [`Socket::recv`], so a plug that only
+//! sends should not be given a `keepalive` in the spaghetti file.
+//!
+//! ```no_run
+//! fn main() -> anyhow::Result<()> {
+//!     kble_socket::blocking::run_stdio(|socket| {
+//!         while let Some(data) = socket.recv()? {
+//!             socket.send(data)?;
+//!         }
+//!         Ok(())
+//!     })
+//! }
+//! ```
+
+use std::io::{self, Read, StdinLock, StdoutLock, Write};
+
+use anyhow::{anyhow, Result};
+use bytes::{Bytes, BytesMut};
+use tokio_tungstenite::tungstenite::{
+    self,
+    protocol::{CloseFrame, Role, WebSocketConfig},
+    WebSocket,
+};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::{
+    framing::{self, Codec},
+    message::{self, CloseError, Frame, Message as _},
+};
+
+/// A binary socket over this process's stdin/stdout, with the default size
+/// limits. See [`Builder::build_blocking_stdio`](crate::Builder::build_blocking_stdio)
+/// to change them.
+pub fn from_stdio() -> Socket {
+    crate::Builder::new().build_blocking_stdio()
+}
+
+/// Run a plug over [`from_stdio`]'s socket, and close it as
+/// [`run_stdio`](crate::run_stdio) does: normally when `plug` returns `Ok`,
+/// else with Internal Error (1011) and the error as the reason. Returns what
+/// `plug` returned.
+pub fn run_stdio<F>(plug: F) -> Result<()>
+where
+    F: FnOnce(&mut Socket) -> Result<()>,
+{
+    let mut socket = from_stdio();
+    let result = plug(&mut socket);
+    // The peer may be gone already; what `plug` returned matters more
+    let _ = match &result {
+        Ok(()) => socket.close(),
+        Err(e) => socket.close_with_error(e),
+    };
+    result
+}
+
+/// A binary socket read and written synchronously. Text (control) messages
+/// from the peer are skipped.
+pub struct Socket<S = Stdio> {
+    transport: Transport<S>,
+    /// A Close was received or sent
+    closed: bool,
+}
+
+enum Transport<S> {
+    WebSocket(Box<WebSocket<S>>),
+    LengthPrefixed(LengthPrefixed<S>),
+}
+
+impl<S: Read + Write> Socket<S> {
+    /// The plug's side over `io`: take `kble`'s offer of length-prefixed
+    /// framing when `offered`, falling back to WebSocket as
+    /// `framing::accept` does
+    pub(crate) fn accept(mut io: S, offered: bool, config: WebSocketConfig) -> Self {
+        let read = if offered {
+            accept_offer(&mut io)
+        } else {
+            Ok(Some(Vec::new()))
+        };
+        let transport = match read {
+            Ok(None) => Transport::LengthPrefixed(LengthPrefixed::new(io, &config)),
+            Ok(Some(read)) => Transport::WebSocket(Box::new(WebSocket::from_partially_read(
+                io,
+                read,
+                Role::Server,
+                Some(config),
+            ))),
+            Err(_) => Transport::WebSocket(Box::new(WebSocket::from_raw_socket(
+                io,
+                Role::Server,
+                Some(config),
+            ))),
+        };
+        Socket {
+            transport,
+            closed: false,
+        }
+    }
+
+    /// Wait for the next binary message, or `None` once the peer closed the
+    /// socket. An abnormal Close is a [`CloseError`].
+    pub fn recv(&mut self) -> Result<Option<Bytes>> {
+        while !self.closed {
+            let frame = match &mut self.transport {
+                Transport::WebSocket(ws) => match ws.read() {
+                    Ok(msg) => msg.into_frame(),
+                    Err(tungstenite::Error::ConnectionClosed) => Frame::Close(None),
+                    Err(e) => return Err(message::receive_error(e)),
+                },
+                Transport::LengthPrefixed(lp) => match lp.read() {
+                    Ok(Some(msg)) => msg.into_frame(),
+                    Ok(None) => Frame::Close(None),
+                    Err(e) => return Err(message::receive_error(e)),
+                },
+            };
+            if let Frame::Close(_) = frame {
+                self.closed = true;
+                // Send the reply tungstenite queued; the peer may not wait
+                if let Transport::WebSocket(ws) = &mut self.transport {
+                    let _ = ws.flush();
+                }
+            }
+            if let Some(data) = message::frame_item(frame)? {
+                return Ok(Some(data));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Send `data` as a binary message, and flush it
+    pub fn send(&mut self, data: Bytes) -> Result<()> {
+        if self.closed {
+            return Err(anyhow!("WebSocket connection is closed"));
+        }
+        match &mut self.transport {
+            Transport::WebSocket(ws) => ws.send(tungstenite::Message::binary(data))?,
+            Transport::LengthPrefixed(lp) => lp.write(framing::Message::Binary(data))?,
+        }
+        Ok(())
+    }
+
+    /// Close the socket normally
+    pub fn close(&mut self) -> Result<()> {
+        self.close_with(message::NORMAL_CLOSURE, String::new())
+    }
+
+    /// Close the socket with Internal Error (1011) and `err` as the reason, as
+    /// for a plug that failed
+    pub fn close_with_error(&mut self, err: &anyhow::Error) -> Result<()> {
+        let close = CloseError::from_error(err);
+        self.close_with(close.code, close.reason)
+    }
+
+    fn close_with(&mut self, code: u16, reason: String) -> Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        self.closed = true;
+        match &mut self.transport {
+            Transport::WebSocket(ws) => {
+                ws.close(Some(CloseFrame {
+                    code: code.into(),
+                    reason: reason.into(),
+                }))?;
+                ws.flush()?;
+            }
+            Transport::LengthPrefixed(lp) => lp.write(framing::Message::close(code, reason))?,
+        }
+        Ok(())
+    }
+}
+
+/// Take the offer, and read whether `kble` confirmed it. Returns `None` when
+/// it did, else the bytes of WebSocket read meanwhile.
+fn accept_offer<S: Read + Write>(io: &mut S) -> io::Result<Option<Vec<u8>>> {
+    io.write_all(framing::PREAMBLE)?;
+    io.flush()?;
+    let mut first = [0; 1];
+    let n = io.read(&mut first)?;
+    if n == 0 || first[0] != framing::PREAMBLE[0] {
+        return Ok(Some(first[..n].to_vec()));
+    }
+    let mut rest = [0; framing::PREAMBLE.len() - 1];
+    io.read_exact(&mut rest)?;
+    if rest != framing::PREAMBLE[1..] {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Invalid length-prefixed framing preamble",
+        ));
+    }
+    Ok(None)
+}
+
+/// Length-prefixed [`framing::Message`]s over `io`, answering Pings as they
+/// are read
+struct LengthPrefixed<S> {
+    io: S,
+    codec: Codec,
+    buf: BytesMut,
+}
+
+impl<S: Read + Write> LengthPrefixed<S> {
+    fn new(io: S, config: &WebSocketConfig) -> Self {
+        LengthPrefixed {
+            io,
+            codec: Codec::new(config),
+            buf: BytesMut::new(),
+        }
+    }
+
+    /// The next message, or `None` at the end of `io`
+    fn read(&mut self) -> io::Result<Option<framing::Message>> {
+        loop {
+            match self.codec.decode(&mut self.buf)? {
+                Some(framing::Message::Ping(data)) => {
+                    self.write(framing::Message::Pong(data.clone()))?;
+                    return Ok(Some(framing::Message::Ping(data)));
+                }
+                Some(msg) => return Ok(Some(msg)),
+                None => {}
+            }
+            let mut chunk = [0; 8 * 1024];
+            let n = self.io.read(&mut chunk)?;
+            if n == 0 {
+                return match self.buf.is_empty() {
+                    true => Ok(None),
+                    false => Err(io::ErrorKind::UnexpectedEof.into()),
+                };
+            }
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    fn write(&mut self, msg: framing::Message) -> io::Result<()> {
+        let mut frame = BytesMut::new();
+        self.codec.encode(msg, &mut frame)?;
+        self.io.write_all(&frame)?;
+        self.io.flush()
+    }
+}
+
+/// This process's stdin and stdout, locked for the [`Socket`]
+pub struct Stdio {
+    stdin: StdinLock<'static>,
+    stdout: StdoutLock<'static>,
+}
+
+impl Stdio {
+    pub(crate) fn new() -> Self {
+        Stdio {
+            stdin: io::stdin().lock(),
+            stdout: io::stdout().lock(),
+        }
+    }
+}
+
+impl Read for Stdio {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stdin.read(buf)
+    }
+}
+
+impl Write for Stdio {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stdout.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stdout.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{TcpListener, TcpStream},
+        thread,
+    };
+
+    use futures_util::{SinkExt, StreamExt};
+
+    use super::*;
+
+    fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn test_websocket_without_an_offer() {
+        let (client, server) = tcp_pair();
+        let plug = thread::spawn(move || {
+            let mut socket = Socket::accept(server, false, WebSocketConfig::default());
+            while let Some(data) = socket.recv().unwrap() {
+                socket.send(data).unwrap();
+            }
+        });
+
+        let mut ws = WebSocket::from_raw_socket(client, Role::Client, None);
+        ws.send(tungstenite::Message::Ping(b"ping".to_vec()))
+            .unwrap();
+        ws.send(tungstenite::Message::binary(b"echo".to_vec()))
+            .unwrap();
+        // The Pong comes first, answered while the plug waited in `recv`
+        assert_eq!(
+            ws.read().unwrap(),
+            tungstenite::Message::Pong(b"ping".to_vec())
+        );
+        assert_eq!(
+            ws.read().unwrap(),
+            tungstenite::Message::binary(b"echo".to_vec())
+        );
+        ws.close(None).unwrap();
+        plug.join().unwrap();
+    }
+
+    #[test]
+    fn test_length_prefixed_when_kble_confirms() {
+        let (client, server) = tcp_pair();
+        let plug = thread::spawn(move || {
+            let mut socket = Socket::accept(server, true, WebSocketConfig::default());
+            let data = socket.recv().unwrap().unwrap();
+            socket.send(data).unwrap();
+            socket.close_with_error(&anyhow!("done")).unwrap();
+        });
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async move {
+            client.set_nonblocking(true).unwrap();
+            let client = tokio::net::TcpStream::from_std(client).unwrap();
+            let negotiated =
+                framing::connect(client, framing::OFFER_TIMEOUT, WebSocketConfig::default())
+                    .await
+                    .unwrap();
+            let framing::Negotiated::LengthPrefixed(mut lp) = negotiated else {
+                panic!("kble fell back to WebSocket");
+            };
+            lp.send(framing::Message::Binary(Bytes::from_static(b"echo")))
+                .await
+                .unwrap();
+            assert!(matches!(
+                lp.next().await,
+                Some(Ok(framing::Message::Binary(data))) if data == "echo"
+            ));
+            assert!(matches!(
+                lp.next().await,
+                Some(Ok(framing::Message::Close(Some(close)))) if close.reason == "done"
+            ));
+        });
+        plug.join().unwrap();
+    }
+}
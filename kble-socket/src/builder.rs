@@ -0,0 +1,408 @@
+use std::future::Future;
+
+#[cfg(feature = "tungstenite")]
+use anyhow::Context;
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use futures_util::{future, sink, stream, SinkExt, TryStreamExt};
+#[cfg(feature = "tungstenite")]
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+
+#[cfg(feature = "tungstenite")]
+use crate::framing::{self, Negotiated};
+
+use crate::{
+    driver, message, ControlMessage, ControlSink, ControlStream, EnvelopeSink, EnvelopeStream,
+    Enveloped, Keepalive, SocketSink, SocketStream,
+};
+
+/// Options for turning a WebSocket into a [`Socket`]. The `from_*` functions
+/// are shorthands for `Builder::new().build_*`, split into a
+/// [`SocketSink`]/[`SocketStream`] pair.
+#[derive(Debug, Clone, Default)]
+pub struct Builder {
+    keepalive: Option<Keepalive>,
+    max_message_size: Option<usize>,
+    max_frame_size: Option<usize>,
+    control: bool,
+    envelopes: driver::Envelopes,
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ping the peer and fail the socket when it goes silent. See
+    /// [`Keepalive`]. The socket is then driven by a background task, so the
+    /// `build_*` methods must be called within a Tokio runtime.
+    pub fn keepalive(mut self, keepalive: Keepalive) -> Self {
+        self.keepalive = Some(keepalive);
+        self
+    }
+
+    /// Fail the socket when the peer sends a message of more than `max` bytes,
+    /// instead of at tungstenite's default of 64 MiB. The stream then ends
+    /// with a [`MessageTooLong`](crate::MessageTooLong) error. Also raises the
+    /// frame limit to `max` unless [`max_frame_size`](Self::max_frame_size)
+    /// is set, as a message is usually sent in a single frame.
+    ///
+    /// A WebSocket's limits are fixed when it is opened, so this applies to
+    /// the sockets the builder opens itself (`build_stdio`, `run_stdio`,
+    /// `build_blocking_stdio`, `build_tcp`, `handshake` and the like). Open
+    /// others with [`websocket_config`](Self::websocket_config), or with
+    /// axum's `WebSocketUpgrade::max_message_size`.
+    pub fn max_message_size(mut self, max: usize) -> Self {
+        self.max_message_size = Some(max);
+        self
+    }
+
+    /// Fail the socket when the peer sends a frame of more than `max` bytes,
+    /// instead of at tungstenite's default of 16 MiB, as
+    /// [`max_message_size`](Self::max_message_size) does for messages.
+    pub fn max_frame_size(mut self, max: usize) -> Self {
+        self.max_frame_size = Some(max);
+        self
+    }
+
+    /// Keep the socket's control channel: [`ControlMessage`]s in Text frames,
+    /// apart from the data (see [`Socket::split_with_control`]). Read the
+    /// control stream, or drop it to ignore them; while it is full, more are
+    /// dropped with a warning. The socket is then driven by a background task,
+    /// so the `build_*` methods must be called within a Tokio runtime.
+    pub fn control(mut self) -> Self {
+        self.control = true;
+        self
+    }
+
+    /// Carry [`Envelope`](crate::Envelope)s when the peer asks for them,
+    /// over the control channel, which the socket then has (see
+    /// [`control`](Self::control)). The socket asks back only then, so a peer
+    /// that never asks sees no handshake: use this on the accepting side, and
+    /// [`offer_envelopes`](Self::offer_envelopes) on the connecting one. The
+    /// data carries them both ways once both asked; see
+    /// [`Socket::split_enveloped`].
+    pub fn envelopes(mut self) -> Self {
+        self.envelopes = driver::Envelopes::Answer;
+        self
+    }
+
+    /// Ask the peer for [`Envelope`](crate::Envelope)s as soon as the socket
+    /// is open, as [`envelopes`](Self::envelopes) does in answer to the peer
+    pub fn offer_envelopes(mut self) -> Self {
+        self.envelopes = driver::Envelopes::Offer;
+        self
+    }
+
+    /// The configuration of a WebSocket with this builder's size limits, for
+    /// opening one to pass to [`build_tungstenite`](Self::build_tungstenite)
+    #[cfg(feature = "tungstenite")]
+    pub fn websocket_config(&self) -> WebSocketConfig {
+        let mut config = WebSocketConfig::default();
+        if let Some(max) = self.max_message_size {
+            config.max_message_size = Some(max);
+        }
+        if let Some(max) = self.max_frame_size.or(self.max_message_size) {
+            config.max_frame_size = Some(max);
+        }
+        config
+    }
+
+    /// Listen for WebSocket connections at `addr`, with this builder's size
+    /// limits, e.g. to serve a plug remotely instead of over stdio. Run over
+    /// each connection with [`run_tungstenite`](Self::run_tungstenite) and the
+    /// like.
+    #[cfg(feature = "listen")]
+    pub async fn listen(&self, addr: &crate::ListenAddr) -> Result<crate::Listener> {
+        crate::Listener::bind(addr, self.websocket_config()).await
+    }
+
+    /// Open a WebSocket over `io` as `handshake` says, with this builder's
+    /// size limits, to build or run a socket over with
+    /// [`build_tungstenite`](Self::build_tungstenite) and the like.
+    #[cfg(feature = "net")]
+    pub async fn handshake<S>(
+        &self,
+        io: S,
+        handshake: crate::Handshake,
+    ) -> Result<tokio_tungstenite::WebSocketStream<S>>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        crate::net::open(io, handshake, self.websocket_config()).await
+    }
+
+    /// See [`crate::from_tcp`].
+    #[cfg(feature = "net")]
+    pub async fn build_tcp(
+        &self,
+        stream: tokio::net::TcpStream,
+        handshake: crate::Handshake,
+    ) -> Result<Socket> {
+        let wss = self.handshake(stream, handshake).await?;
+        Ok(self.build_tungstenite(wss))
+    }
+
+    /// See [`crate::from_unix`].
+    #[cfg(all(feature = "net", unix))]
+    pub async fn build_unix(
+        &self,
+        stream: tokio::net::UnixStream,
+        handshake: crate::Handshake,
+    ) -> Result<Socket> {
+        let wss = self.handshake(stream, handshake).await?;
+        Ok(self.build_tungstenite(wss))
+    }
+
+    #[cfg(feature = "tungstenite")]
+    pub fn build_tungstenite<S>(&self, wss: tokio_tungstenite::WebSocketStream<S>) -> Socket
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+    {
+        if self.driven() {
+            self.socket(driver::spawn(wss, self.keepalive, self.envelopes))
+                .0
+        } else {
+            Socket::bytes(crate::tungstenite::split(wss))
+        }
+    }
+
+    #[cfg(feature = "axum")]
+    pub fn build_axum(&self, ws: axum::extract::ws::WebSocket) -> Socket {
+        if self.driven() {
+            self.socket(driver::spawn(ws, self.keepalive, self.envelopes))
+                .0
+        } else {
+            Socket::bytes(crate::axum::split(ws))
+        }
+    }
+
+    /// See [`crate::from_stdio`].
+    #[cfg(all(feature = "stdio", feature = "tungstenite"))]
+    pub async fn build_stdio(&self) -> Socket {
+        let socket = crate::stdio::negotiate(self.websocket_config()).await;
+        self.build_negotiated(socket)
+    }
+
+    /// See [`crate::blocking::from_stdio`]. The size limits apply, but not a
+    /// keepalive, which needs a background task, nor the control channel.
+    #[cfg(feature = "blocking")]
+    pub fn build_blocking_stdio(&self) -> crate::blocking::Socket {
+        let stdio = crate::blocking::Stdio::new();
+        crate::blocking::Socket::accept(stdio, framing::offered(), self.websocket_config())
+    }
+
+    /// The other end of [`build_stdio`](Self::build_stdio): a plug process's
+    /// stdin and stdout as `io`, the way `kble` talks to its `exec:` plugs.
+    /// Start the process with [`FRAMING_ENV`](crate::FRAMING_ENV) set to
+    /// [`LENGTH_PREFIXED`](crate::LENGTH_PREFIXED) to offer it length-prefixed
+    /// framing. A plug that does not take the offer within a second gets a
+    /// WebSocket, as one that predates it does.
+    #[cfg(feature = "tungstenite")]
+    pub async fn build_child<S>(&self, io: S) -> Result<Socket>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+    {
+        let socket = framing::connect(io, framing::OFFER_TIMEOUT, self.websocket_config())
+            .await
+            .context("Failed to negotiate the framing")?;
+        Ok(self.build_negotiated(socket))
+    }
+
+    /// See [`crate::from_length_prefixed`]. The message size limit applies;
+    /// frames are not split, so the frame size limit does not.
+    #[cfg(feature = "tungstenite")]
+    pub fn build_length_prefixed<S>(&self, io: S) -> Socket
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+    {
+        let socket = framing::LengthPrefixed::new(io, self.websocket_config());
+        if self.driven() {
+            self.socket(driver::spawn(socket, self.keepalive, self.envelopes))
+                .0
+        } else {
+            Socket::bytes(framing::split(socket))
+        }
+    }
+
+    #[cfg(feature = "tungstenite")]
+    fn build_negotiated<S>(&self, socket: Negotiated<S>) -> Socket
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+    {
+        match socket {
+            _ if self.driven() => self.socket(self.drive(socket)).0,
+            Negotiated::WebSocket(wss) => Socket::bytes(crate::tungstenite::split(wss)),
+            Negotiated::LengthPrefixed(socket) => Socket::bytes(framing::split(socket)),
+        }
+    }
+
+    /// Hand a negotiated socket to a driver task, as [`driver::spawn`]
+    #[cfg(feature = "tungstenite")]
+    fn drive<S>(&self, socket: Negotiated<S>) -> driver::Driven
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+    {
+        match socket {
+            Negotiated::WebSocket(wss) => driver::spawn(wss, self.keepalive, self.envelopes),
+            Negotiated::LengthPrefixed(socket) => {
+                driver::spawn(socket, self.keepalive, self.envelopes)
+            }
+        }
+    }
+
+    /// Whether sockets need a driver task
+    fn driven(&self) -> bool {
+        self.keepalive.is_some() || self.control || self.envelopes != driver::Envelopes::Off
+    }
+
+    /// The socket of a driven one, with its control channel when asked for,
+    /// and the closer for [`run`]
+    fn socket(&self, driven: driver::Driven) -> (Socket, driver::Closer) {
+        let control = (self.control || self.envelopes != driver::Envelopes::Off)
+            .then_some((driven.control_sink, driven.control_stream));
+        let socket = Socket {
+            data: Data::Enveloped(driven.sink, driven.stream),
+            control,
+        };
+        (socket, driven.closer)
+    }
+
+    /// Run `plug` over the socket, then close the WebSocket with a code that
+    /// tells the peer how it ended: Normal Closure (1000) when `plug` returns
+    /// `Ok`, or Internal Error (1011) with the error as the reason. The peer's
+    /// socket reports the latter as a [`crate::CloseError`]. Returns what
+    /// `plug` returned.
+    #[cfg(feature = "tungstenite")]
+    pub async fn run_tungstenite<S, F, Fut>(
+        &self,
+        wss: tokio_tungstenite::WebSocketStream<S>,
+        plug: F,
+    ) -> Result<()>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+        F: FnOnce(Socket) -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        let driven = driver::spawn(wss, self.keepalive, self.envelopes);
+        run(self.socket(driven), plug).await
+    }
+
+    /// [`run_tungstenite`](Self::run_tungstenite) for an axum WebSocket.
+    #[cfg(feature = "axum")]
+    pub async fn run_axum<F, Fut>(&self, ws: axum::extract::ws::WebSocket, plug: F) -> Result<()>
+    where
+        F: FnOnce(Socket) -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        let driven = driver::spawn(ws, self.keepalive, self.envelopes);
+        run(self.socket(driven), plug).await
+    }
+
+    /// See [`crate::run_stdio`].
+    #[cfg(all(feature = "stdio", feature = "tungstenite"))]
+    pub async fn run_stdio<F, Fut>(&self, plug: F) -> Result<()>
+    where
+        F: FnOnce(Socket) -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        let socket = crate::stdio::negotiate(self.websocket_config()).await;
+        run(self.socket(self.drive(socket)), plug).await
+    }
+}
+
+/// A socket built by a [`Builder`]: its data, and its control channel when
+/// the builder asked for one. Split it into the halves the plug needs.
+pub struct Socket {
+    data: Data,
+    control: Option<(ControlSink, ControlStream)>,
+}
+
+enum Data {
+    Bytes(SocketSink, SocketStream),
+    Enveloped(EnvelopeSink, EnvelopeStream),
+}
+
+impl Socket {
+    fn bytes((sink, stream): (SocketSink, SocketStream)) -> Self {
+        Socket {
+            data: Data::Bytes(sink, stream),
+            control: None,
+        }
+    }
+
+    /// The data as plain bytes. Envelopes are dropped, and so is the control
+    /// channel, with the control messages received.
+    pub fn split(self) -> (SocketSink, SocketStream) {
+        match self.data {
+            Data::Bytes(sink, stream) => (sink, stream),
+            Data::Enveloped(sink, stream) => bytes(sink, stream),
+        }
+    }
+
+    /// The data as plain bytes, and the control channel (see
+    /// [`Builder::control`]). Without one, the control sink fails and the
+    /// control stream ends at once.
+    pub fn split_with_control(self) -> (SocketSink, SocketStream, ControlSink, ControlStream) {
+        let (control_sink, control_stream) = self.control.unwrap_or_else(no_control);
+        let (sink, stream) = match self.data {
+            Data::Bytes(sink, stream) => (sink, stream),
+            Data::Enveloped(sink, stream) => bytes(sink, stream),
+        };
+        (sink, stream, control_sink, control_stream)
+    }
+
+    /// The data with its envelopes (see [`Builder::envelopes`]), and the
+    /// control channel as [`split_with_control`](Self::split_with_control)
+    /// hands it out. Until the peer agrees to envelopes, or with a builder
+    /// that did not ask for them, messages arrive without one, and the sink
+    /// drops theirs.
+    pub fn split_enveloped(self) -> (EnvelopeSink, EnvelopeStream, ControlSink, ControlStream) {
+        let (control_sink, control_stream) = self.control.unwrap_or_else(no_control);
+        let (sink, stream) = match self.data {
+            Data::Bytes(sink, stream) => enveloped(sink, stream),
+            Data::Enveloped(sink, stream) => (sink, stream),
+        };
+        (sink, stream, control_sink, control_stream)
+    }
+}
+
+/// The data of a socket that does not ask for envelopes, as plain bytes
+fn bytes(sink: EnvelopeSink, stream: EnvelopeStream) -> (SocketSink, SocketStream) {
+    let sink = sink.with(|data: Bytes| future::ok::<_, anyhow::Error>(Enveloped::from(data)));
+    let stream = stream.map_ok(|msg| msg.data);
+    (Box::pin(sink), Box::pin(stream))
+}
+
+/// Bare messages, as envelopes that are never sent
+fn enveloped(sink: SocketSink, stream: SocketStream) -> (EnvelopeSink, EnvelopeStream) {
+    let sink = sink.with(|msg: Enveloped| future::ok::<_, anyhow::Error>(msg.data));
+    let stream = stream.map_ok(Enveloped::from);
+    (Box::pin(sink), Box::pin(stream))
+}
+
+/// The control channel of a socket without one
+fn no_control() -> (ControlSink, ControlStream) {
+    let sink = sink::unfold((), |(), _: ControlMessage| {
+        future::err(anyhow!(
+            "The socket has no control channel: see Builder::control"
+        ))
+    });
+    (Box::pin(sink), Box::pin(stream::empty()))
+}
+
+async fn run<F, Fut>((socket, closer): (Socket, driver::Closer), plug: F) -> Result<()>
+where
+    F: FnOnce(Socket) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let result = plug(socket).await;
+    match &result {
+        Ok(()) => closer.close(message::NORMAL_CLOSURE, String::new()).await,
+        Err(e) => {
+            let close = message::CloseError::from_error(e);
+            closer.close(close.code, close.reason).await;
+        }
+    }
+    result
+}
@@ -0,0 +1,288 @@
+use std::{
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use anyhow::Result;
+use bytes::BytesMut;
+use futures_util::{future, Sink, Stream, StreamExt};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::{SocketSink, SocketStream};
+
+type ErrorHook = Box<dyn FnMut(anyhow::Error) + Send>;
+
+/// Decode the messages of `rx`, taken as one byte stream, with `decoder`.
+/// E.g. for a plug that splits a serial byte stream into frames.
+pub fn decode<D>(rx: SocketStream, decoder: D) -> DecodeStream<D>
+where
+    D: Decoder,
+    D::Error: Into<anyhow::Error>,
+{
+    DecodeStream {
+        rx,
+        decoder,
+        buf: BytesMut::new(),
+        on_error: None,
+    }
+}
+
+/// Encode each item with `encoder` and send it to `tx` as one message
+pub fn encode<E>(tx: SocketSink, encoder: E) -> EncodeSink<E> {
+    EncodeSink {
+        tx,
+        encoder,
+        on_error: None,
+    }
+}
+
+/// A [`Stream`] of the items decoded from a [`SocketStream`]. See [`decode`].
+///
+/// A decoder error ends the stream with it, unless [`on_error`](Self::on_error)
+/// reports it instead. An error of the socket always ends the stream, and
+/// bytes left undecoded when the socket ends are dropped, as the start of a
+/// frame that never completed.
+pub struct DecodeStream<D> {
+    rx: SocketStream,
+    decoder: D,
+    buf: BytesMut,
+    on_error: Option<ErrorHook>,
+}
+
+impl<D> DecodeStream<D>
+where
+    D: Decoder,
+    D::Error: Into<anyhow::Error>,
+{
+    /// Pass decoder errors to `report` and keep decoding, for a decoder that
+    /// consumes the offending bytes before failing
+    pub fn on_error(mut self, report: impl FnMut(anyhow::Error) + Send + 'static) -> Self {
+        self.on_error = Some(Box::new(report));
+        self
+    }
+
+    /// Split the decoded items into data and junk, e.g. the EB90 decoder's
+    /// frames and skipped bytes. Junk is passed to `report` and skipped.
+    pub fn on_junk<T, J>(
+        self,
+        mut split: impl FnMut(D::Item) -> Result<T, J> + Send + 'static,
+        mut report: impl FnMut(J) + Send + 'static,
+    ) -> impl Stream<Item = Result<T>> + Send + Unpin + 'static
+    where
+        D: Send + Unpin + 'static,
+        D::Item: Send,
+        T: Send + 'static,
+    {
+        self.filter_map(move |item| {
+            let item = match item.map(&mut split) {
+                Ok(Ok(data)) => Some(Ok(data)),
+                Ok(Err(junk)) => {
+                    report(junk);
+                    None
+                }
+                Err(e) => Some(Err(e)),
+            };
+            future::ready(item)
+        })
+    }
+}
+
+impl<D> Stream for DecodeStream<D>
+where
+    D: Decoder + Unpin,
+    D::Error: Into<anyhow::Error>,
+{
+    type Item = Result<D::Item>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            match this.decoder.decode(&mut this.buf) {
+                Ok(Some(item)) => return Poll::Ready(Some(Ok(item))),
+                Ok(None) => {}
+                Err(e) => match &mut this.on_error {
+                    Some(report) => {
+                        report(e.into());
+                        continue;
+                    }
+                    None => return Poll::Ready(Some(Err(e.into()))),
+                },
+            }
+            match ready!(this.rx.as_mut().poll_next(cx)) {
+                Some(Ok(chunk)) => this.buf.extend_from_slice(&chunk),
+                Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+/// A [`Sink`] encoding items into messages of a [`SocketSink`]. See [`encode`].
+///
+/// An encoder error fails the send, unless [`on_error`](Self::on_error)
+/// reports it and drops the item instead.
+pub struct EncodeSink<E> {
+    tx: SocketSink,
+    encoder: E,
+    on_error: Option<ErrorHook>,
+}
+
+impl<E> EncodeSink<E> {
+    /// Pass encoder errors to `report` and drop the item, e.g. one too large
+    /// for the framing, instead of failing
+    pub fn on_error(mut self, report: impl FnMut(anyhow::Error) + Send + 'static) -> Self {
+        self.on_error = Some(Box::new(report));
+        self
+    }
+}
+
+impl<E, I> Sink<I> for EncodeSink<E>
+where
+    E: Encoder<I> + Unpin,
+    E::Error: Into<anyhow::Error>,
+{
+    type Error = anyhow::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.get_mut().tx.as_mut().poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: I) -> Result<()> {
+        let this = self.get_mut();
+        let mut buf = BytesMut::new();
+        match this.encoder.encode(item, &mut buf) {
+            Ok(()) => this.tx.as_mut().start_send(buf.freeze()),
+            Err(e) => match &mut this.on_error {
+                Some(report) => {
+                    report(e.into());
+                    Ok(())
+                }
+                None => Err(e.into()),
+            },
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.get_mut().tx.as_mut().poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.get_mut().tx.as_mut().poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use bytes::Bytes;
+    use futures_util::{sink, stream, SinkExt};
+    use tokio_util::codec::LinesCodec;
+
+    use super::*;
+
+    fn socket_stream(chunks: &[&'static str]) -> SocketStream {
+        let chunks: Vec<Result<Bytes>> = chunks
+            .iter()
+            .map(|chunk| Ok(Bytes::from_static(chunk.as_bytes())))
+            .collect();
+        Box::pin(stream::iter(chunks))
+    }
+
+    #[tokio::test]
+    async fn test_decode_across_messages() {
+        let rx = socket_stream(&["ab", "c\nde\nf", "\ng"]);
+        let lines: Vec<String> = decode(rx, LinesCodec::new())
+            .map(|line| line.unwrap())
+            .collect()
+            .await;
+        assert_eq!(lines, ["abc", "de", "f"]);
+    }
+
+    #[tokio::test]
+    async fn test_decode_error() {
+        let rx = socket_stream(&["toolong\n", "ok\n"]);
+        let mut lines = decode(rx, LinesCodec::new_with_max_length(4));
+        assert!(lines.next().await.unwrap().is_err());
+
+        let rx = socket_stream(&["toolong\n", "ok\n"]);
+        let errors = Arc::new(Mutex::new(0));
+        let lines: Vec<String> = decode(rx, LinesCodec::new_with_max_length(4))
+            .on_error({
+                let errors = errors.clone();
+                move |_| *errors.lock().unwrap() += 1
+            })
+            .map(|line| line.unwrap())
+            .collect()
+            .await;
+        assert_eq!(lines, ["ok"]);
+        assert_eq!(*errors.lock().unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_decode_junk() {
+        let rx = socket_stream(&["a\n#junk\nb\n"]);
+        let (junk_tx, junk_rx) = std::sync::mpsc::channel();
+        let lines: Vec<String> = decode(rx, LinesCodec::new())
+            .on_junk(
+                |line| match line.strip_prefix('#') {
+                    Some(junk) => Err(junk.to_string()),
+                    None => Ok(line),
+                },
+                move |junk| junk_tx.send(junk).unwrap(),
+            )
+            .map(|line| line.unwrap())
+            .collect()
+            .await;
+        assert_eq!(lines, ["a", "b"]);
+        assert_eq!(junk_rx.try_iter().collect::<Vec<_>>(), ["junk"]);
+    }
+
+    /// Encodes strings of up to 2 bytes as is
+    struct Short;
+
+    impl Encoder<&str> for Short {
+        type Error = std::io::Error;
+
+        fn encode(&mut self, item: &str, dst: &mut BytesMut) -> std::io::Result<()> {
+            if item.len() > 2 {
+                return Err(std::io::Error::other("too long"));
+            }
+            dst.extend_from_slice(item.as_bytes());
+            Ok(())
+        }
+    }
+
+    fn socket_sink() -> (SocketSink, Arc<Mutex<Vec<Bytes>>>) {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let tx = sink::unfold((), {
+            let sent = sent.clone();
+            move |(), message| {
+                sent.lock().unwrap().push(message);
+                future::ok(())
+            }
+        });
+        (Box::pin(tx), sent)
+    }
+
+    #[tokio::test]
+    async fn test_encode() {
+        let (tx, sent) = socket_sink();
+        let mut tx = encode(tx, Short);
+        tx.send("a").await.unwrap();
+        tx.send("bc").await.unwrap();
+        assert!(tx.send("def").await.is_err());
+        assert_eq!(*sent.lock().unwrap(), ["a", "bc"]);
+
+        let (tx, sent) = socket_sink();
+        let errors = Arc::new(Mutex::new(0));
+        let mut tx = encode(tx, Short).on_error({
+            let errors = errors.clone();
+            move |_| *errors.lock().unwrap() += 1
+        });
+        tx.send("def").await.unwrap();
+        tx.send("a").await.unwrap();
+        assert_eq!(*sent.lock().unwrap(), ["a"]);
+        assert_eq!(*errors.lock().unwrap(), 1);
+    }
+}
@@ -0,0 +1,107 @@
+use std::{fmt, pin::Pin, str::FromStr};
+
+use anyhow::{anyhow, Result};
+use futures_util::{Sink, Stream};
+use serde_json::{Map, Value};
+
+pub type ControlSink = Pin<Box<dyn Sink<ControlMessage, Error = anyhow::Error> + Send + 'static>>;
+/// Yields an error for a Text frame that is not a control message, and goes on
+/// after it.
+pub type ControlStream = Pin<Box<dyn Stream<Item = Result<ControlMessage>> + Send + 'static>>;
+
+/// A message on a socket's control channel, sent as a Text frame beside the
+/// Binary data frames: a JSON object whose `type` says what it is, e.g. a plug
+/// reporting `{"type":"status","connected":true}`, or the orchestrator asking
+/// for `{"type":"set_baud_rate","baud_rate":115200}`. A peer that does not know
+/// a type ignores the message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControlMessage {
+    pub kind: String,
+    /// The other members of the object. A `type` here is left out when the
+    /// message is sent, as `kind` is the message's type.
+    pub fields: Map<String, Value>,
+}
+
+impl ControlMessage {
+    pub fn new(kind: impl Into<String>) -> Self {
+        ControlMessage {
+            kind: kind.into(),
+            fields: Map::new(),
+        }
+    }
+
+    /// Set the member `key` to `value`. `type` is ignored: it is
+    /// [`kind`](Self::kind).
+    pub fn with(mut self, key: impl Into<String>, value: impl Into<Value>) -> Self {
+        let key = key.into();
+        if key != "type" {
+            self.fields.insert(key, value.into());
+        }
+        self
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.fields.get(key)
+    }
+}
+
+impl FromStr for ControlMessage {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = |why: &str| anyhow!("Invalid control message {s:?}: {why}");
+        let Value::Object(mut fields) =
+            serde_json::from_str(s).map_err(|e| invalid(&e.to_string()))?
+        else {
+            return Err(invalid("not an object"));
+        };
+        let Some(Value::String(kind)) = fields.remove("type") else {
+            return Err(invalid("no string \"type\""));
+        };
+        Ok(ControlMessage { kind, fields })
+    }
+}
+
+impl fmt::Display for ControlMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // `type` first, for people reading the frames
+        let kind = Value::String(self.kind.clone());
+        let mut fields = self.fields.clone();
+        fields.remove("type");
+        let fields = Value::Object(fields).to_string();
+        match fields.strip_prefix('{').filter(|rest| *rest != "}") {
+            Some(rest) => write!(f, "{{\"type\":{kind},{rest}"),
+            None => write!(f, "{{\"type\":{kind}}}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let msg = ControlMessage::new("set_baud_rate").with("baud_rate", 115200);
+        let text = msg.to_string();
+        assert_eq!(text, r#"{"type":"set_baud_rate","baud_rate":115200}"#);
+        assert_eq!(text.parse::<ControlMessage>().unwrap(), msg);
+    }
+
+    #[test]
+    fn test_type_is_the_kind() {
+        let msg = ControlMessage::new("status").with("type", "other");
+        assert_eq!(msg, ControlMessage::new("status"));
+        let mut msg = msg.with("connected", true);
+        msg.fields.insert("type".to_string(), "other".into());
+        assert_eq!(msg.to_string(), r#"{"type":"status","connected":true}"#);
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!("hello".parse::<ControlMessage>().is_err());
+        assert!("[1]".parse::<ControlMessage>().is_err());
+        assert!(r#"{"baud_rate":9600}"#.parse::<ControlMessage>().is_err());
+        assert!(r#"{"type":1}"#.parse::<ControlMessage>().is_err());
+    }
+}
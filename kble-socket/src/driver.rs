@@ -0,0 +1,519 @@
+use std::{
+    fmt,
+    future::Future,
+    pin::{pin, Pin},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
+    task::{self, ready},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use futures_util::{
+    future::{self, Either},
+    stream,
+    task::AtomicWaker,
+    Sink, SinkExt, Stream, StreamExt,
+};
+use tokio::{
+    sync::{
+        mpsc::{self, error::TrySendError},
+        oneshot,
+    },
+    time::{self, Instant, Interval, MissedTickBehavior},
+};
+use tokio_util::sync::PollSender;
+use tracing::warn;
+
+use crate::{
+    envelope::{self, Handshake},
+    message::{self, Frame, Message},
+    ControlMessage, ControlSink, ControlStream, EnvelopeSink, EnvelopeStream, Enveloped,
+};
+
+/// WebSocket keepalive: send a Ping every `interval`, and fail the socket
+/// when nothing (data, Ping or Pong) arrives from the peer for `timeout`.
+///
+/// The timeout only runs while the socket's stream has room for another
+/// frame, so a consumer that stops reading is never mistaken for a dead peer.
+/// `timeout` should be comfortably longer than `interval`, or an idle link
+/// times out before the Pong for the last Ping can arrive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keepalive {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+/// The error a keepalive socket's stream ends with when the peer went silent
+/// for [`Keepalive::timeout`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeepaliveTimeout {
+    pub timeout: Duration,
+}
+
+impl fmt::Display for KeepaliveTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "No frame from the peer within {:?}", self.timeout)
+    }
+}
+
+impl std::error::Error for KeepaliveTimeout {}
+
+/// How many received control messages a driven socket's control stream
+/// holds before dropping more
+const CONTROL_BACKLOG: usize = 16;
+
+/// Whether a driven socket carries [`Envelope`](crate::Envelope)s
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum Envelopes {
+    #[default]
+    Off,
+    /// Ask for them in answer to the peer's offer only, so a peer that never
+    /// offers never sees the handshake
+    Answer,
+    /// Offer them on connecting
+    Offer,
+}
+
+/// A socket driven by [`spawn`]
+pub(crate) struct Driven {
+    /// Sends envelopes once the peer asked for them, and drops them before
+    pub(crate) sink: EnvelopeSink,
+    pub(crate) stream: EnvelopeStream,
+    /// Closing or dropping it leaves the data and the WebSocket alone
+    pub(crate) control_sink: ControlSink,
+    /// Dropping it discards the control messages received. While it is alive,
+    /// it holds up to [`CONTROL_BACKLOG`] of them, and those arriving while it
+    /// is full are dropped with a warning, so the data never waits for it.
+    pub(crate) control_stream: ControlStream,
+    pub(crate) closer: Closer,
+}
+
+/// Hand `ws` to a driver task that reads and writes it concurrently, so Pings
+/// go out and the peer is watched even while the returned halves sit idle.
+/// Must be called within a Tokio runtime. Envelopes are sent once the peer
+/// asks for them, as `envelopes` says.
+pub(crate) fn spawn<M, S, E>(ws: S, keepalive: Option<Keepalive>, envelopes: Envelopes) -> Driven
+where
+    M: Message,
+    S: Stream<Item = Result<M, E>> + Sink<M, Error = E> + Send + Unpin + 'static,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    if let Some(keepalive) = keepalive {
+        assert!(!keepalive.interval.is_zero(), "keepalive interval is zero");
+    }
+    let (outbound_tx, outbound_rx) = mpsc::channel(1);
+    let (inbound_tx, inbound_rx) = mpsc::channel(1);
+    let (control_out_tx, control_out_rx) = mpsc::channel(1);
+    let (control_in_tx, control_in_rx) = mpsc::channel(CONTROL_BACKLOG);
+    let (closed_tx, closed_rx) = oneshot::channel();
+    let (request_tx, request_rx) = oneshot::channel();
+    let (flushed_tx, flushed_rx) = oneshot::channel();
+    let failure = Arc::new(OnceLock::new());
+    let written = Arc::new(Written::default());
+    tokio::spawn(drive(
+        ws,
+        keepalive,
+        envelopes,
+        Channels {
+            outbound: outbound_rx,
+            inbound: inbound_tx,
+            control_out: control_out_rx,
+            control_in: control_in_tx,
+            closed: closed_tx,
+            close_request: request_rx,
+            flushed: flushed_tx,
+            written: written.clone(),
+        },
+        failure.clone(),
+    ));
+    let closer = Closer {
+        _outbound: outbound_tx.clone(),
+        request: request_tx,
+        flushed: flushed_rx,
+    };
+    let sink = DriverSink {
+        outbound: PollSender::new(outbound_tx),
+        closing: false,
+        closed: Some(closed_rx),
+        failure,
+        sent: 0,
+        written,
+    };
+    let stream = stream::unfold(inbound_rx, |mut rx| async move {
+        rx.recv().await.map(|item| (item, rx))
+    });
+    let control_sink = PollSender::new(control_out_tx)
+        .sink_map_err(|_| anyhow!("WebSocket connection is closed"))
+        .with(|msg: ControlMessage| future::ok::<_, anyhow::Error>(msg.to_string()));
+    let control_stream = stream::unfold(control_in_rx, |mut rx| async move {
+        rx.recv().await.map(|item| (item, rx))
+    });
+    Driven {
+        sink: Box::pin(sink),
+        stream: Box::pin(stream),
+        control_sink: Box::pin(control_sink),
+        control_stream: Box::pin(control_stream),
+        closer,
+    }
+}
+
+/// Closes a driven socket with a chosen code and reason. While it is alive,
+/// dropping the sink does not close the WebSocket (closing it still does);
+/// dropping it unused leaves that to the sink as usual.
+pub(crate) struct Closer {
+    /// Keeps the outbound channel open until [`Closer::close`]
+    _outbound: mpsc::Sender<Outbound>,
+    request: oneshot::Sender<(u16, String)>,
+    flushed: oneshot::Receiver<()>,
+}
+
+impl Closer {
+    /// Send a Close frame once everything sent through the sink is out, and
+    /// wait until it has been flushed too.
+    pub(crate) async fn close(self, code: u16, reason: String) {
+        if self.request.send((code, reason)).is_ok() {
+            let _ = self.flushed.await;
+        }
+    }
+}
+
+enum Outbound {
+    Data(Enveloped),
+    /// The sink was closed
+    Close,
+}
+
+/// How many data messages the driver has written and flushed, so the sink
+/// can tell when its flush is through
+#[derive(Default)]
+struct Written {
+    count: AtomicU64,
+    /// Set once the driver is gone, and the rest never will be
+    done: AtomicBool,
+    waker: AtomicWaker,
+}
+
+impl Written {
+    fn add(&self) {
+        self.count.fetch_add(1, Ordering::Release);
+        self.waker.wake();
+    }
+}
+
+/// Marks [`Written::done`] however the driver ends, even when its task is
+/// dropped
+struct WrittenGuard(Arc<Written>);
+
+impl Drop for WrittenGuard {
+    fn drop(&mut self) {
+        self.0.done.store(true, Ordering::Release);
+        self.0.waker.wake();
+    }
+}
+
+struct Channels {
+    outbound: mpsc::Receiver<Outbound>,
+    inbound: mpsc::Sender<Result<Enveloped>>,
+    control_out: mpsc::Receiver<String>,
+    control_in: mpsc::Sender<Result<ControlMessage>>,
+    closed: oneshot::Sender<Result<()>>,
+    close_request: oneshot::Receiver<(u16, String)>,
+    flushed: oneshot::Sender<()>,
+    written: Arc<Written>,
+}
+
+async fn drive<M, S, E>(
+    ws: S,
+    keepalive: Option<Keepalive>,
+    envelopes: Envelopes,
+    channels: Channels,
+    failure: Arc<OnceLock<String>>,
+) where
+    M: Message,
+    S: Stream<Item = Result<M, E>> + Sink<M, Error = E> + Send + Unpin + 'static,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let Channels {
+        mut outbound,
+        inbound,
+        mut control_out,
+        control_in,
+        closed,
+        close_request,
+        flushed,
+        written,
+    } = channels;
+    let written = WrittenGuard(written);
+    let (mut ws_sink, mut ws_stream) = ws.split();
+    // Set once reading ends: the peer closed or reset the connection, so
+    // failing to close it too is no failure
+    let peer_gone = AtomicBool::new(false);
+    let enveloped = envelopes != Envelopes::Off;
+    // Fired when the peer asks for envelopes
+    let (accepted_tx, accepted_rx) = oneshot::channel();
+
+    // Ends with `Err` only when the peer timed out, i.e. is presumed dead
+    let read = async {
+        // Owned here, so the streams end as soon as reading does
+        let (inbound, control_in) = (inbound, control_in);
+        let mut accepted = enveloped.then_some(accepted_tx);
+        // Whether the peer's Binary frames have headers
+        let mut decoding = false;
+        loop {
+            // No permit means the stream was dropped: keep reading anyway, so
+            // the peer is still watched, and discard the data.
+            let permit = inbound.reserve().await.ok();
+            let next = match keepalive {
+                Some(keepalive) => match time::timeout(keepalive.timeout, ws_stream.next()).await {
+                    Ok(next) => next,
+                    Err(_) => {
+                        let e = anyhow::Error::new(KeepaliveTimeout {
+                            timeout: keepalive.timeout,
+                        });
+                        let _ = failure.set(e.to_string());
+                        if let Some(permit) = permit {
+                            permit.send(Err(e));
+                        }
+                        return Err(());
+                    }
+                },
+                None => ws_stream.next().await,
+            };
+            let item = match next {
+                Some(Ok(msg)) => match msg.into_frame() {
+                    Frame::Text(text) => {
+                        let msg = text.parse::<ControlMessage>();
+                        match msg.as_ref().map(Handshake::of) {
+                            Ok(Handshake::Offer) if enveloped => {
+                                if let Some(accepted) = accepted.take() {
+                                    let _ = accepted.send(());
+                                }
+                            }
+                            Ok(Handshake::Start) if enveloped => decoding = true,
+                            _ if msg.as_ref().is_ok_and(Handshake::is_handshake) => {}
+                            // Waiting for the control stream would hold up the
+                            // data, and Pongs with it. Fails quietly when it was
+                            // dropped.
+                            _ => {
+                                if let Err(TrySendError::Full(msg)) = control_in.try_send(msg) {
+                                    match msg {
+                                        Ok(msg) => {
+                                            warn!(control = %msg, "Control stream full: dropped")
+                                        }
+                                        Err(e) => warn!("Control stream full: dropped {e:#}"),
+                                    }
+                                }
+                            }
+                        }
+                        continue;
+                    }
+                    frame => match message::frame_item(frame) {
+                        Ok(Some(data)) if decoding => envelope::decode(data),
+                        Ok(Some(data)) => Ok(data.into()),
+                        Ok(None) => continue,
+                        Err(e) => Err(e),
+                    },
+                },
+                Some(Err(e)) => Err(message::receive_error(e)),
+                None => {
+                    peer_gone.store(true, Ordering::Relaxed);
+                    return Ok(());
+                }
+            };
+            let is_err = item.is_err();
+            if let Some(permit) = permit {
+                permit.send(item);
+            }
+            if is_err {
+                peer_gone.store(true, Ordering::Relaxed);
+                return Ok(());
+            }
+        }
+    };
+
+    let write = async {
+        let mut ping = keepalive.map(|keepalive| {
+            let mut ping =
+                time::interval_at(Instant::now() + keepalive.interval, keepalive.interval);
+            ping.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            ping
+        });
+        let mut close_request = Some(close_request);
+        let mut control_open = true;
+        let mut accepted = enveloped.then_some(accepted_rx);
+        // Whether our Binary frames have headers
+        let mut encoding = false;
+        // Whether to ask the peer for envelopes before anything else
+        let mut offer = envelopes == Envelopes::Offer;
+        loop {
+            if offer {
+                offer = false;
+                if let Err(e) = ws_sink.send(M::text(envelope::offer().to_string())).await {
+                    let e = anyhow::Error::from(e);
+                    let _ = failure.set(e.to_string());
+                    return Err(e);
+                }
+            }
+            let (msg, data) = tokio::select! {
+                // Data and control first, so a Close frame never overtakes them,
+                // after the start of envelopes as soon as the peer asks
+                biased;
+                result = async { accepted.as_mut().unwrap().await }, if accepted.is_some() => {
+                    accepted = None;
+                    if result.is_err() {
+                        // Reading ended before the peer asked
+                        continue;
+                    }
+                    encoding = true;
+                    // Asking back only now
+                    offer = envelopes == Envelopes::Answer;
+                    (M::text(envelope::start().to_string()), false)
+                },
+                data = outbound.recv() => match data {
+                    Some(Outbound::Data(msg)) if encoding => (M::binary(envelope::encode(msg)), true),
+                    Some(Outbound::Data(msg)) => (M::binary(msg.data), true),
+                    // The sink was closed, or dropped along with any closer
+                    Some(Outbound::Close) | None => {
+                        return match ws_sink.close().await {
+                            Err(_) if peer_gone.load(Ordering::Relaxed) => Ok(()),
+                            result => result.map_err(Into::into),
+                        }
+                    }
+                },
+                text = control_out.recv(), if control_open => match text {
+                    Some(text) => (M::text(text), false),
+                    None => {
+                        control_open = false;
+                        continue;
+                    }
+                },
+                request = async { close_request.as_mut().unwrap().await }, if close_request.is_some() => {
+                    let Ok((code, reason)) = request else {
+                        // The closer was dropped unused
+                        close_request = None;
+                        continue;
+                    };
+                    let result = async {
+                        ws_sink.send(M::close(code, reason)).await?;
+                        ws_sink.close().await
+                    };
+                    let result = result.await.map_err(Into::into);
+                    let _ = flushed.send(());
+                    return result;
+                },
+                _ = tick(&mut ping) => (M::ping(), false),
+            };
+            // Sending flushes the transport too
+            if let Err(e) = ws_sink.send(msg).await {
+                let e = anyhow::Error::from(e);
+                let _ = failure.set(e.to_string());
+                return Err(e);
+            }
+            if data {
+                written.0.add();
+            }
+        }
+    };
+
+    let (read, write) = (pin!(read), pin!(write));
+    match future::select(read, write).await {
+        // The peer went away: abandon the writer along with the connection
+        Either::Left((Err(()), _)) => {
+            let _ = closed.send(Err(anyhow!(failure.get().cloned().unwrap_or_default())));
+        }
+        // The peer closed the connection (or reading it failed); writes keep
+        // going until they fail or the sink is closed, as without keepalive
+        Either::Left((Ok(()), write)) => {
+            let _ = closed.send(write.await);
+        }
+        Either::Right((result, read)) => {
+            let _ = closed.send(result);
+            let _ = read.await;
+        }
+    };
+}
+
+/// The next keepalive tick, or never without keepalive.
+async fn tick(ping: &mut Option<Interval>) {
+    match ping {
+        Some(ping) => {
+            ping.tick().await;
+        }
+        None => future::pending().await,
+    }
+}
+
+/// The sink half of [`spawn`]'s socket. Closing it closes the WebSocket and
+/// waits for the driver to report how that went.
+struct DriverSink {
+    outbound: PollSender<Outbound>,
+    /// Whether [`Outbound::Close`] has been sent
+    closing: bool,
+    closed: Option<oneshot::Receiver<Result<()>>>,
+    failure: Arc<OnceLock<String>>,
+    /// How many data messages have been handed to the driver
+    sent: u64,
+    written: Arc<Written>,
+}
+
+impl DriverSink {
+    fn closed_error(&self) -> anyhow::Error {
+        match self.failure.get() {
+            Some(failure) => anyhow!("WebSocket connection failed: {failure}"),
+            None => anyhow!("WebSocket connection is closed"),
+        }
+    }
+}
+
+impl Sink<Enveloped> for DriverSink {
+    type Error = anyhow::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> task::Poll<Result<()>> {
+        let ready = ready!(self.outbound.poll_reserve(cx));
+        task::Poll::Ready(ready.map_err(|_| self.closed_error()))
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Enveloped) -> Result<()> {
+        self.outbound
+            .send_item(Outbound::Data(item))
+            .map_err(|_| self.closed_error())?;
+        self.sent += 1;
+        Ok(())
+    }
+
+    /// Ready once the driver has written and flushed everything sent so far
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> task::Poll<Result<()>> {
+        let flushed = || self.written.count.load(Ordering::Acquire) >= self.sent;
+        if flushed() {
+            return task::Poll::Ready(Ok(()));
+        }
+        self.written.waker.register(cx.waker());
+        if flushed() {
+            return task::Poll::Ready(Ok(()));
+        }
+        if self.written.done.load(Ordering::Acquire) {
+            return task::Poll::Ready(Err(self.closed_error()));
+        }
+        task::Poll::Pending
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> task::Poll<Result<()>> {
+        if !self.closing {
+            // If the driver is gone, so is the connection: nothing to close
+            if ready!(self.outbound.poll_reserve(cx)).is_ok() {
+                let _ = self.outbound.send_item(Outbound::Close);
+            }
+            self.closing = true;
+        }
+        let Some(closed) = self.closed.as_mut() else {
+            return task::Poll::Ready(Ok(()));
+        };
+        let result = ready!(Pin::new(closed).poll(cx));
+        self.closed = None;
+        // An error here means the driver is gone, and the connection with it
+        task::Poll::Ready(result.unwrap_or(Ok(())))
+    }
+}
@@ -0,0 +1,239 @@
+use std::{
+    pin::Pin,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures_util::{Sink, Stream};
+
+use crate::ControlMessage;
+
+pub type EnvelopeSink = Pin<Box<dyn Sink<Enveloped, Error = anyhow::Error> + Send + 'static>>;
+pub type EnvelopeStream = Pin<Box<dyn Stream<Item = Result<Enveloped>> + Send + 'static>>;
+
+/// Metadata carried with a message, between peers that both asked for
+/// envelopes.
+///
+/// The connecting side that wants them sends the control message
+/// `{"type":"envelope","version":1}`. A side that receives it answers
+/// `{"type":"envelope_start"}`, and every Binary frame it sends after that
+/// starts with a header. A server that wants them too sends the offer back
+/// then, and only then, so a client that never asks sees no handshake. The
+/// header is a byte 0 for a message without an envelope, or a byte
+/// 1 followed by the receive time in nanoseconds since the Unix epoch (u64),
+/// the sequence number (u64), and the origin's length (u16) and UTF-8 bytes,
+/// all big-endian. A peer that never asks gets bare messages.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Envelope {
+    /// When the data was first received, e.g. read from a serial port
+    pub received_at: SystemTime,
+    /// Increases by one with each message from the same origin
+    pub sequence: u64,
+    /// The plug the data came from. `kble` fills in the source plug's name
+    /// when it is missing.
+    pub origin: Option<String>,
+}
+
+/// A message, with its envelope if it has one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Enveloped {
+    pub envelope: Option<Envelope>,
+    pub data: Bytes,
+}
+
+impl From<Bytes> for Enveloped {
+    fn from(data: Bytes) -> Self {
+        Enveloped {
+            envelope: None,
+            data,
+        }
+    }
+}
+
+const VERSION: u64 = 1;
+const OFFER: &str = "envelope";
+const START: &str = "envelope_start";
+
+const BARE: u8 = 0;
+const WITH_ENVELOPE: u8 = 1;
+
+/// What a control message means to the envelope handshake
+pub(crate) enum Handshake {
+    /// The peer asks for envelopes
+    Offer,
+    /// The peer's Binary frames have headers from now on
+    Start,
+    /// An offer of another version, or not part of the handshake
+    None,
+}
+
+impl Handshake {
+    pub(crate) fn of(msg: &ControlMessage) -> Self {
+        match msg.kind.as_str() {
+            OFFER if msg.get("version").and_then(|v| v.as_u64()) == Some(VERSION) => {
+                Handshake::Offer
+            }
+            START => Handshake::Start,
+            _ => Handshake::None,
+        }
+    }
+
+    /// Whether `msg` belongs to the handshake, for the control stream to skip
+    pub(crate) fn is_handshake(msg: &ControlMessage) -> bool {
+        msg.kind == OFFER || msg.kind == START
+    }
+}
+
+pub(crate) fn offer() -> ControlMessage {
+    ControlMessage::new(OFFER).with("version", VERSION)
+}
+
+pub(crate) fn start() -> ControlMessage {
+    ControlMessage::new(START)
+}
+
+/// The payload of a Binary frame with a header
+pub(crate) fn encode(msg: Enveloped) -> Bytes {
+    let Some(envelope) = msg.envelope else {
+        let mut buf = BytesMut::with_capacity(1 + msg.data.len());
+        buf.put_u8(BARE);
+        buf.put_slice(&msg.data);
+        return buf.freeze();
+    };
+    let nanos = envelope
+        .received_at
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_nanos().try_into().unwrap_or(u64::MAX));
+    let origin = truncate_origin(envelope.origin.as_deref().unwrap_or_default()).as_bytes();
+    let mut buf = BytesMut::with_capacity(19 + origin.len() + msg.data.len());
+    buf.put_u8(WITH_ENVELOPE);
+    buf.put_u64(nanos);
+    buf.put_u64(envelope.sequence);
+    buf.put_u16(origin.len() as u16);
+    buf.put_slice(origin);
+    buf.put_slice(&msg.data);
+    buf.freeze()
+}
+
+/// `origin`, cut on a char boundary to fit its u16 length
+fn truncate_origin(origin: &str) -> &str {
+    let mut end = origin.len().min(u16::MAX as usize);
+    while !origin.is_char_boundary(end) {
+        end -= 1;
+    }
+    &origin[..end]
+}
+
+/// The message in a Binary frame with a header
+pub(crate) fn decode(mut frame: Bytes) -> Result<Enveloped> {
+    let too_short = || anyhow!("Invalid envelope: the frame is too short");
+    if frame.is_empty() {
+        return Err(too_short());
+    }
+    match frame.get_u8() {
+        BARE => Ok(frame.into()),
+        WITH_ENVELOPE => {
+            if frame.len() < 18 {
+                return Err(too_short());
+            }
+            let received_at = UNIX_EPOCH + Duration::from_nanos(frame.get_u64());
+            let sequence = frame.get_u64();
+            let origin_len = frame.get_u16() as usize;
+            if frame.len() < origin_len {
+                return Err(too_short());
+            }
+            let origin = frame.split_to(origin_len);
+            let origin = String::from_utf8(origin.to_vec())
+                .map_err(|_| anyhow!("Invalid envelope: the origin is not UTF-8"))?;
+            Ok(Enveloped {
+                envelope: Some(Envelope {
+                    received_at,
+                    sequence,
+                    origin: (!origin.is_empty()).then_some(origin),
+                }),
+                data: frame,
+            })
+        }
+        tag => Err(anyhow!("Invalid envelope: unknown header {tag}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let msg = Enveloped {
+            envelope: Some(Envelope {
+                received_at: UNIX_EPOCH + Duration::new(1_700_000_000, 123),
+                sequence: 42,
+                origin: Some("uart".to_string()),
+            }),
+            data: Bytes::from_static(b"data"),
+        };
+        assert_eq!(decode(encode(msg.clone())).unwrap(), msg);
+        let bare = Enveloped::from(Bytes::from_static(b"bare"));
+        assert_eq!(encode(bare.clone())[..], *b"\0bare");
+        assert_eq!(decode(encode(bare.clone())).unwrap(), bare);
+    }
+
+    #[test]
+    fn test_without_origin() {
+        let msg = Enveloped {
+            envelope: Some(Envelope {
+                received_at: UNIX_EPOCH,
+                sequence: 0,
+                origin: None,
+            }),
+            data: Bytes::new(),
+        };
+        assert_eq!(encode(msg.clone()).len(), 19);
+        assert_eq!(decode(encode(msg.clone())).unwrap(), msg);
+    }
+
+    #[test]
+    fn test_long_origin_is_truncated_on_a_char_boundary() {
+        let msg = Enveloped {
+            envelope: Some(Envelope {
+                received_at: UNIX_EPOCH,
+                sequence: 0,
+                origin: Some("é".repeat(40_000)),
+            }),
+            data: Bytes::from_static(b"data"),
+        };
+        let decoded = decode(encode(msg)).unwrap();
+        let origin = decoded.envelope.unwrap().origin.unwrap();
+        assert_eq!(origin, "é".repeat(32_767));
+        assert_eq!(decoded.data, "data");
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(decode(Bytes::new()).is_err());
+        assert!(decode(Bytes::from_static(&[2])).is_err());
+        assert!(decode(Bytes::from_static(&[1, 0, 0])).is_err());
+        let mut frame = encode(Enveloped {
+            envelope: Some(Envelope {
+                received_at: UNIX_EPOCH,
+                sequence: 0,
+                origin: Some("uart".to_string()),
+            }),
+            data: Bytes::new(),
+        })
+        .to_vec();
+        frame.truncate(frame.len() - 1);
+        assert!(decode(frame.into()).is_err());
+    }
+
+    #[test]
+    fn test_handshake() {
+        assert!(matches!(Handshake::of(&offer()), Handshake::Offer));
+        assert!(matches!(Handshake::of(&start()), Handshake::Start));
+        let other = ControlMessage::new(OFFER).with("version", 2);
+        assert!(matches!(Handshake::of(&other), Handshake::None));
+        assert!(Handshake::is_handshake(&other));
+        assert!(!Handshake::is_handshake(&ControlMessage::new("status")));
+    }
+}
@@ -0,0 +1,645 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures_util::{future, stream, Sink, SinkExt, Stream, StreamExt, TryStreamExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio_tungstenite::{
+    tungstenite::{
+        protocol::{Role, WebSocketConfig},
+        Message as WsMessage,
+    },
+    WebSocketStream,
+};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+use crate::{
+    message::{self, CloseError, Frame, MessageTooLong},
+    SocketSink, SocketStream,
+};
+
+/// Environment variable through which `kble` offers an `exec:` plug
+/// length-prefixed framing over its stdin/stdout instead of WebSocket, when
+/// set to [`LENGTH_PREFIXED`]. It is only read, never unset: a plug that
+/// starts processes speaking over their own stdio must not pass it on to them
+/// (e.g. with [`Command::env_remove`](std::process::Command::env_remove)).
+pub const FRAMING_ENV: &str = "KBLE_STDIO_FRAMING";
+
+/// The value of [`FRAMING_ENV`] offering length-prefixed framing
+pub const LENGTH_PREFIXED: &str = "length-prefixed";
+
+/// How long `kble` waits for a plug to take the offer before it speaks
+/// WebSocket, as a plug that predates the offer does. `kble` only makes the
+/// offer to plugs configured for it, so other plugs do not wait.
+pub(crate) const OFFER_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Written by a plug taking the offer, and back by `kble` to confirm. Its first
+/// byte never starts a WebSocket frame, as it sets a reserved bit.
+pub(crate) const PREAMBLE: &[u8] = b"KBLE-LP1\n";
+
+/// Each frame is an opcode (as WebSocket's), the payload length as a big
+/// endian `u32`, and the payload
+const HEADER_LEN: usize = 5;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+/// A binary socket speaking length-prefixed framing over `io`: lighter than a
+/// WebSocket, without masking, for a peer that speaks it too. A plug and `kble`
+/// use it over stdio when they negotiate it (see [`FRAMING_ENV`]).
+pub fn from_length_prefixed<S>(io: S) -> (SocketSink, SocketStream)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    crate::Builder::new().build_length_prefixed(io).split()
+}
+
+/// A socket over stdin/stdout, as negotiated
+pub(crate) enum Negotiated<S> {
+    WebSocket(WebSocketStream<Rewound<S>>),
+    LengthPrefixed(LengthPrefixed<Rewound<S>>),
+}
+
+/// Whether `kble` offered this process length-prefixed framing
+#[cfg(any(feature = "stdio", feature = "blocking"))]
+pub(crate) fn offered() -> bool {
+    std::env::var(FRAMING_ENV).is_ok_and(|v| v == LENGTH_PREFIXED)
+}
+
+/// The plug's side: take the offer when `offered`, and go with what `kble`
+/// confirms. Falls back to WebSocket on any I/O error, which then fails the
+/// socket as usual.
+#[cfg(feature = "stdio")]
+pub(crate) async fn accept<S>(mut io: S, offered: bool, config: WebSocketConfig) -> Negotiated<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let rewound = if offered {
+        accept_offer(&mut io).await
+    } else {
+        Ok(Some(Bytes::new()))
+    };
+    match rewound {
+        Ok(None) => Negotiated::LengthPrefixed(LengthPrefixed::new(Rewound::new(io), config)),
+        Ok(Some(read)) => {
+            let io = Rewound::new(io).with_prefix(read);
+            let ws = WebSocketStream::from_raw_socket(io, Role::Server, Some(config)).await;
+            Negotiated::WebSocket(ws)
+        }
+        Err(_) => {
+            let io = Rewound::new(io);
+            let ws = WebSocketStream::from_raw_socket(io, Role::Server, Some(config)).await;
+            Negotiated::WebSocket(ws)
+        }
+    }
+}
+
+/// Take the offer, and read whether `kble` confirmed it. Returns `None` when
+/// it did, else the bytes of WebSocket read meanwhile.
+#[cfg(feature = "stdio")]
+async fn accept_offer<S>(io: &mut S) -> io::Result<Option<Bytes>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    io.write_all(PREAMBLE).await?;
+    io.flush().await?;
+    let mut first = [0; 1];
+    let n = io.read(&mut first).await?;
+    if n == 0 || first[0] != PREAMBLE[0] {
+        return Ok(Some(Bytes::copy_from_slice(&first[..n])));
+    }
+    read_preamble(io).await?;
+    Ok(None)
+}
+
+/// `kble`'s side, for a plug started with [`FRAMING_ENV`] set: length-prefixed
+/// framing when the plug takes the offer within `timeout`, else WebSocket. A
+/// plug that starts the preamble but does not finish it within `timeout` is an
+/// error.
+pub(crate) async fn connect<S>(
+    mut io: S,
+    timeout: Duration,
+    config: WebSocketConfig,
+) -> io::Result<Negotiated<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut first = [0; 1];
+    match tokio::time::timeout_at(deadline, io.read(&mut first)).await {
+        Ok(Ok(1)) if first[0] == PREAMBLE[0] => {
+            tokio::time::timeout_at(deadline, read_preamble(&mut io))
+                .await
+                .map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "Length-prefixed framing preamble not finished in time",
+                    )
+                })??;
+            io.write_all(PREAMBLE).await?;
+            io.flush().await?;
+            Ok(Negotiated::LengthPrefixed(LengthPrefixed::new(
+                Rewound::new(io),
+                config,
+            )))
+        }
+        // A plug that writes before taking the offer speaks WebSocket
+        Ok(Ok(n)) => {
+            let io = Rewound::new(io).with_prefix(Bytes::copy_from_slice(&first[..n]));
+            let ws = WebSocketStream::from_raw_socket(io, Role::Client, Some(config)).await;
+            Ok(Negotiated::WebSocket(ws))
+        }
+        Ok(Err(e)) => Err(e),
+        // Either the plug predates the offer, or it was slow to take it. A
+        // Ping tells the latter that it was declined, and the former ignores
+        // it; the preamble the latter writes meanwhile is skipped.
+        Err(_) => {
+            let io = Rewound::new(io).skipping_preamble();
+            let mut ws = WebSocketStream::from_raw_socket(io, Role::Client, Some(config)).await;
+            ws.send(WsMessage::Ping(Vec::new()))
+                .await
+                .map_err(io::Error::other)?;
+            Ok(Negotiated::WebSocket(ws))
+        }
+    }
+}
+
+/// Read the rest of the preamble, the first byte of which was read
+async fn read_preamble<S>(io: &mut S) -> io::Result<()>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut rest = [0; PREAMBLE.len() - 1];
+    io.read_exact(&mut rest).await?;
+    if rest != PREAMBLE[1..] {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Invalid length-prefixed framing preamble",
+        ));
+    }
+    Ok(())
+}
+
+/// An I/O stream with bytes already read from it put back in front, and
+/// optionally a preamble skipped when it comes first
+pub(crate) struct Rewound<S> {
+    io: S,
+    prefix: Bytes,
+    skip: Skip,
+}
+
+enum Skip {
+    No,
+    /// The preamble, if the next byte read starts it
+    Preamble,
+    /// The rest of the preamble
+    Rest(usize),
+}
+
+impl<S> Rewound<S> {
+    fn new(io: S) -> Self {
+        Rewound {
+            io,
+            prefix: Bytes::new(),
+            skip: Skip::No,
+        }
+    }
+
+    fn with_prefix(mut self, prefix: Bytes) -> Self {
+        self.prefix = prefix;
+        self
+    }
+
+    fn skipping_preamble(mut self) -> Self {
+        self.skip = Skip::Preamble;
+        self
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewound<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if !this.prefix.is_empty() {
+                let n = this.prefix.len().min(buf.remaining());
+                buf.put_slice(&this.prefix.split_to(n));
+                return Poll::Ready(Ok(()));
+            }
+            if let Skip::No = this.skip {
+                return Pin::new(&mut this.io).poll_read(cx, buf);
+            }
+            let mut chunk = [0; PREAMBLE.len()];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.io).poll_read(cx, &mut chunk))?;
+            let read = chunk.filled();
+            let skip = match this.skip {
+                Skip::Preamble if read.first() == Some(&PREAMBLE[0]) => PREAMBLE.len(),
+                Skip::Rest(n) => n,
+                _ => 0,
+            };
+            let n = skip.min(read.len());
+            this.skip = match skip - n {
+                0 => Skip::No,
+                rest => Skip::Rest(rest),
+            };
+            if read.is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+            this.prefix = Bytes::copy_from_slice(&read[n..]);
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewound<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_shutdown(cx)
+    }
+}
+
+/// A frame of the length-prefixed framing, standing in for a WebSocket message
+#[derive(Debug)]
+pub(crate) enum Message {
+    Text(String),
+    Binary(Bytes),
+    Close(Option<CloseError>),
+    Ping(Bytes),
+    Pong(Bytes),
+}
+
+impl message::Message for Message {
+    fn binary(data: Bytes) -> Self {
+        Message::Binary(data)
+    }
+
+    fn text(text: String) -> Self {
+        Message::Text(text)
+    }
+
+    fn ping() -> Self {
+        Message::Ping(Bytes::new())
+    }
+
+    fn close(code: u16, reason: String) -> Self {
+        Message::Close(Some(CloseError { code, reason }))
+    }
+
+    fn into_frame(self) -> Frame {
+        match self {
+            Message::Binary(data) => Frame::Binary(data),
+            Message::Text(text) => Frame::Text(text),
+            Message::Close(close) => Frame::Close(close),
+            Message::Ping(_) | Message::Pong(_) => Frame::Other,
+        }
+    }
+}
+
+/// Frames [`Message`]s with their length, failing on one over the limit
+pub(crate) struct Codec {
+    max_message_size: usize,
+}
+
+impl Codec {
+    pub(crate) fn new(config: &WebSocketConfig) -> Self {
+        Codec {
+            max_message_size: config.max_message_size.unwrap_or(usize::MAX),
+        }
+    }
+}
+
+impl Decoder for Codec {
+    type Item = Message;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Message>> {
+        if src.len() < HEADER_LEN {
+            return Ok(None);
+        }
+        let opcode = src[0];
+        let len = u32::from_be_bytes([src[1], src[2], src[3], src[4]]) as usize;
+        if len > self.max_message_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                MessageTooLong {
+                    size: len,
+                    max_size: self.max_message_size,
+                },
+            ));
+        }
+        if src.len() < HEADER_LEN + len {
+            src.reserve(HEADER_LEN + len - src.len());
+            return Ok(None);
+        }
+        src.advance(HEADER_LEN);
+        let mut payload = src.split_to(len).freeze();
+        let msg = match opcode {
+            OPCODE_TEXT => Message::Text(utf8(payload)?),
+            OPCODE_BINARY => Message::Binary(payload),
+            OPCODE_CLOSE if payload.is_empty() => Message::Close(None),
+            OPCODE_CLOSE if payload.len() >= 2 => {
+                let code = payload.get_u16();
+                let reason = utf8(payload)?;
+                Message::Close(Some(CloseError { code, reason }))
+            }
+            OPCODE_PING => Message::Ping(payload),
+            OPCODE_PONG => Message::Pong(payload),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid length-prefixed frame with opcode {opcode:#x}"),
+                ))
+            }
+        };
+        Ok(Some(msg))
+    }
+}
+
+impl Encoder<Message> for Codec {
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: Message, dst: &mut BytesMut) -> io::Result<()> {
+        let (opcode, payload) = match msg {
+            Message::Text(text) => (OPCODE_TEXT, Bytes::from(text)),
+            Message::Binary(data) => (OPCODE_BINARY, data),
+            Message::Close(None) => (OPCODE_CLOSE, Bytes::new()),
+            Message::Close(Some(close)) => {
+                let mut payload = BytesMut::with_capacity(2 + close.reason.len());
+                payload.put_u16(close.code);
+                payload.put_slice(close.reason.as_bytes());
+                (OPCODE_CLOSE, payload.freeze())
+            }
+            Message::Ping(data) => (OPCODE_PING, data),
+            Message::Pong(data) => (OPCODE_PONG, data),
+        };
+        let len = u32::try_from(payload.len()).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Message of {} bytes is too long to frame", payload.len()),
+            )
+        })?;
+        dst.reserve(HEADER_LEN + payload.len());
+        dst.put_u8(opcode);
+        dst.put_u32(len);
+        dst.put_slice(&payload);
+        Ok(())
+    }
+}
+
+fn utf8(payload: Bytes) -> io::Result<String> {
+    String::from_utf8(payload.into()).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// [`Message`]s over `io`, answering Pings, ending after a Close, and sending
+/// one when closed, as a WebSocket does
+pub(crate) struct LengthPrefixed<S> {
+    framed: Framed<S, Codec>,
+    /// A Close was received
+    closed: bool,
+    /// A Close was sent
+    close_sent: bool,
+}
+
+impl<S: AsyncRead + AsyncWrite> LengthPrefixed<S> {
+    pub(crate) fn new(io: S, config: WebSocketConfig) -> Self {
+        LengthPrefixed {
+            framed: Framed::new(io, Codec::new(&config)),
+            closed: false,
+            close_sent: false,
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Stream for LengthPrefixed<S> {
+    type Item = io::Result<Message>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.closed {
+            return Poll::Ready(None);
+        }
+        let msg = ready!(this.framed.poll_next_unpin(cx));
+        match &msg {
+            // Best effort, as tungstenite: a Pong that does not fit the write
+            // buffer now is dropped
+            Some(Ok(Message::Ping(data))) => {
+                if let Poll::Ready(Ok(())) = this.framed.poll_ready_unpin(cx) {
+                    let _ = this.framed.start_send_unpin(Message::Pong(data.clone()));
+                    let _ = this.framed.poll_flush_unpin(cx);
+                }
+            }
+            Some(Ok(Message::Close(_))) => this.closed = true,
+            _ => {}
+        }
+        Poll::Ready(msg)
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Sink<Message> for LengthPrefixed<S> {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().framed.poll_ready_unpin(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, msg: Message) -> io::Result<()> {
+        let this = self.get_mut();
+        this.close_sent |= matches!(msg, Message::Close(_));
+        this.framed.start_send_unpin(msg)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().framed.poll_flush_unpin(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        // Shutting down a pipe does not close it, so the Close is the end
+        if !this.close_sent {
+            ready!(this.framed.poll_ready_unpin(cx))?;
+            this.framed.start_send_unpin(Message::Close(None))?;
+            this.close_sent = true;
+        }
+        this.framed.poll_close_unpin(cx)
+    }
+}
+
+pub(crate) fn split<S>(socket: LengthPrefixed<S>) -> (SocketSink, SocketStream)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (sink, stream) = socket.split();
+    let sink = sink
+        .with_flat_map(|b| stream::iter([Ok(Message::Binary(b))]))
+        .sink_map_err(Into::into);
+    let stream = stream
+        .map_err(message::receive_error)
+        .try_filter_map(|msg| future::ready(message::stream_item(msg)));
+    (Box::pin(sink), Box::pin(stream))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codec_roundtrip() {
+        let mut codec = Codec {
+            max_message_size: 16,
+        };
+        let mut buf = BytesMut::new();
+        let close = CloseError {
+            code: 1011,
+            reason: "failed".to_string(),
+        };
+        codec
+            .encode(Message::Binary(Bytes::from_static(b"data")), &mut buf)
+            .unwrap();
+        codec
+            .encode(Message::Close(Some(close.clone())), &mut buf)
+            .unwrap();
+        let mut partial = buf.split_to(HEADER_LEN + 2);
+        assert!(codec.decode(&mut partial).unwrap().is_none());
+        partial.unsplit(buf);
+        let mut buf = partial;
+        assert!(matches!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Message::Binary(data)) if data == "data"
+        ));
+        assert!(matches!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Message::Close(Some(decoded))) if decoded == close
+        ));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_codec_message_too_long() {
+        let mut codec = Codec {
+            max_message_size: 2,
+        };
+        let mut buf = BytesMut::new();
+        codec
+            .encode(Message::Binary(Bytes::from_static(b"long")), &mut buf)
+            .unwrap();
+        let err = codec.decode(&mut buf).unwrap_err();
+        let err = message::receive_error(err);
+        assert_eq!(
+            err.downcast_ref::<MessageTooLong>(),
+            Some(&MessageTooLong {
+                size: 4,
+                max_size: 2
+            })
+        );
+    }
+
+    #[cfg(feature = "stdio")]
+    mod negotiation {
+        use tokio::io::duplex;
+
+        use super::*;
+
+        fn config() -> WebSocketConfig {
+            WebSocketConfig::default()
+        }
+
+        async fn roundtrip<A, B>(a: Negotiated<A>, b: Negotiated<B>)
+        where
+            A: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+            B: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+        {
+            let (mut a_tx, mut a_rx) = pair(a);
+            let (mut b_tx, mut b_rx) = pair(b);
+            a_tx.send(Bytes::from_static(b"ping")).await.unwrap();
+            assert_eq!(b_rx.next().await.unwrap().unwrap(), "ping");
+            b_tx.send(Bytes::from_static(b"pong")).await.unwrap();
+            assert_eq!(a_rx.next().await.unwrap().unwrap(), "pong");
+        }
+
+        fn pair<S>(negotiated: Negotiated<S>) -> (SocketSink, SocketStream)
+        where
+            S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+        {
+            match negotiated {
+                Negotiated::WebSocket(ws) => crate::tungstenite::split(ws),
+                Negotiated::LengthPrefixed(socket) => split(socket),
+            }
+        }
+
+        #[tokio::test]
+        async fn test_negotiate_length_prefixed() {
+            let (kble, plug) = duplex(1024);
+            let (kble, plug) = tokio::join!(
+                connect(kble, OFFER_TIMEOUT, config()),
+                accept(plug, true, config())
+            );
+            let kble = kble.unwrap();
+            assert!(matches!(kble, Negotiated::LengthPrefixed(_)));
+            assert!(matches!(plug, Negotiated::LengthPrefixed(_)));
+            roundtrip(kble, plug).await;
+        }
+
+        #[tokio::test]
+        async fn test_negotiate_without_offer() {
+            let (kble, plug) = duplex(1024);
+            let (kble, plug) = tokio::join!(
+                connect(kble, Duration::from_millis(50), config()),
+                accept(plug, false, config())
+            );
+            let kble = kble.unwrap();
+            assert!(matches!(kble, Negotiated::WebSocket(_)));
+            assert!(matches!(plug, Negotiated::WebSocket(_)));
+            roundtrip(kble, plug).await;
+        }
+
+        #[tokio::test]
+        async fn test_negotiate_stalled_preamble() {
+            let (kble, mut plug) = duplex(1024);
+            plug.write_all(&PREAMBLE[..1]).await.unwrap();
+            let kble = tokio::time::timeout(
+                OFFER_TIMEOUT * 5,
+                connect(kble, Duration::from_millis(50), config()),
+            )
+            .await
+            .expect("the preamble read is bounded");
+            let e = kble.err().expect("a stalled preamble is an error");
+            assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+            drop(plug);
+        }
+
+        #[tokio::test]
+        async fn test_negotiate_late_acceptance() {
+            let (kble, plug) = duplex(1024);
+            let plug = async {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                accept(plug, true, config()).await
+            };
+            let (kble, plug) =
+                tokio::join!(connect(kble, Duration::from_millis(50), config()), plug);
+            let kble = kble.unwrap();
+            assert!(matches!(kble, Negotiated::WebSocket(_)));
+            assert!(matches!(plug, Negotiated::WebSocket(_)));
+            roundtrip(kble, plug).await;
+        }
+    }
+}
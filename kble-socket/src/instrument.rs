@@ -0,0 +1,305 @@
+//! Counters for a socket pair, to tell which hop of a pipeline is slow.
+//!
+//! [`Stats::sink`] and [`Stats::stream`] wrap a socket's halves, counting what
+//! goes through them into the [`Stats`], which is cheap to clone and can be
+//! read with [`Stats::snapshot`] from anywhere while the socket is in use.
+//! With [`Stats::with_spans`], each message also gets a `TRACE` span: `send`
+//! from when the sink takes it until it is flushed, and `recv` when the
+//! stream yields it.
+//!
+//! ```ignore
+//! let stats = Stats::new().with_spans("eb90");
+//! let (sink, stream) = (stats.sink(sink), stats.stream(stream));
+//! // ...
+//! let snapshot = stats.snapshot();
+//! ```
+
+use std::{
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use futures_util::{Sink, Stream};
+use pin_project_lite::pin_project;
+use tracing::Span;
+
+/// A message whose payload size is counted
+pub trait Payload {
+    fn payload_len(&self) -> usize;
+}
+
+impl Payload for Bytes {
+    fn payload_len(&self) -> usize {
+        self.len()
+    }
+}
+
+impl Payload for Vec<u8> {
+    fn payload_len(&self) -> usize {
+        self.len()
+    }
+}
+
+#[cfg(any(feature = "tungstenite", feature = "axum"))]
+impl Payload for crate::Enveloped {
+    fn payload_len(&self) -> usize {
+        self.data.len()
+    }
+}
+
+/// What went through a socket pair, shared by its wrapped halves
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    counters: Arc<Mutex<Snapshot>>,
+    /// The `socket` field of the per-message spans, if they are emitted
+    span_name: Option<Arc<str>>,
+}
+
+/// The counters of a [`Stats`] at one point
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    pub sent_messages: u64,
+    pub sent_bytes: u64,
+    pub received_messages: u64,
+    pub received_bytes: u64,
+    /// Total time messages were in flight: from the sink taking one until the
+    /// next flush completed, i.e. until the wrapped sink wrote it out. Grows
+    /// when the peer is slow to read them.
+    pub send_time: Duration,
+    /// Total time the sink had no room for the next message (backpressure)
+    pub wait_time: Duration,
+    /// Since when the messages the sink took are waiting for a flush, if any
+    pub in_flight_since: Option<Instant>,
+    pub last_sent: Option<Instant>,
+    pub last_received: Option<Instant>,
+}
+
+impl Snapshot {
+    /// When a message was last sent or received
+    pub fn last_activity(&self) -> Option<Instant> {
+        self.last_sent.max(self.last_received)
+    }
+
+    /// How long the messages waiting for a flush have been in flight, if any
+    pub fn in_flight(&self) -> Option<Duration> {
+        self.in_flight_since.map(|since| since.elapsed())
+    }
+
+    /// The time spent sending: waiting for room and for flushes
+    pub fn busy_time(&self) -> Duration {
+        self.send_time + self.wait_time
+    }
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Emit a `TRACE` span per message, with `name` as its `socket` field
+    pub fn with_spans(mut self, name: impl Into<Arc<str>>) -> Self {
+        self.span_name = Some(name.into());
+        self
+    }
+
+    /// The counters now
+    pub fn snapshot(&self) -> Snapshot {
+        self.lock().clone()
+    }
+
+    /// Count what is sent through `sink`
+    pub fn sink<S>(&self, sink: S) -> InstrumentedSink<S> {
+        InstrumentedSink {
+            sink,
+            stats: self.clone(),
+            spans: Vec::new(),
+            waiting_since: None,
+        }
+    }
+
+    /// Count what is received from `stream`
+    pub fn stream<S>(&self, stream: S) -> InstrumentedStream<S> {
+        InstrumentedStream {
+            stream,
+            stats: self.clone(),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Snapshot> {
+        // The counters stay consistent even if a holder panicked
+        self.counters.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn send_span(&self, bytes: usize) -> Option<Span> {
+        let socket = self.span_name.as_deref()?;
+        Some(tracing::trace_span!("send", socket, bytes))
+    }
+
+    fn recv_span(&self, bytes: usize) -> Option<Span> {
+        let socket = self.span_name.as_deref()?;
+        Some(tracing::trace_span!("recv", socket, bytes))
+    }
+}
+
+pin_project! {
+    /// A sink counting into a [`Stats`]; see [`Stats::sink`]
+    pub struct InstrumentedSink<S> {
+        #[pin]
+        sink: S,
+        stats: Stats,
+        // Of the messages in flight, closed once they are flushed
+        spans: Vec<Span>,
+        // Since when `poll_ready` is pending, if it is
+        waiting_since: Option<Instant>,
+    }
+}
+
+impl<S, T> Sink<T> for InstrumentedSink<S>
+where
+    S: Sink<T>,
+    T: Payload,
+{
+    type Error = S::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        let this = self.project();
+        let Poll::Ready(result) = this.sink.poll_ready(cx) else {
+            this.waiting_since.get_or_insert_with(Instant::now);
+            return Poll::Pending;
+        };
+        if let Some(since) = this.waiting_since.take() {
+            this.stats.lock().wait_time += since.elapsed();
+        }
+        Poll::Ready(result)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: T) -> Result<(), S::Error> {
+        let this = self.project();
+        let len = item.payload_len();
+        this.sink.start_send(item)?;
+        let now = Instant::now();
+        let mut counters = this.stats.lock();
+        counters.sent_messages += 1;
+        counters.sent_bytes += len as u64;
+        counters.last_sent = Some(now);
+        counters.in_flight_since.get_or_insert(now);
+        drop(counters);
+        this.spans.extend(this.stats.send_span(len));
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        let this = self.project();
+        let result = ready!(this.sink.poll_flush(cx));
+        flushed(this.stats, this.spans);
+        Poll::Ready(result)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        let this = self.project();
+        let result = ready!(this.sink.poll_close(cx));
+        flushed(this.stats, this.spans);
+        Poll::Ready(result)
+    }
+}
+
+impl<S> InstrumentedSink<S> {
+    /// The wrapped sink
+    pub fn into_inner(self) -> S {
+        self.sink
+    }
+}
+
+/// Count the time the messages in flight took, and close their spans
+fn flushed(stats: &Stats, spans: &mut Vec<Span>) {
+    let mut counters = stats.lock();
+    if let Some(since) = counters.in_flight_since.take() {
+        counters.send_time += since.elapsed();
+    }
+    drop(counters);
+    spans.clear();
+}
+
+pin_project! {
+    /// A stream counting into a [`Stats`]; see [`Stats::stream`]
+    pub struct InstrumentedStream<S> {
+        #[pin]
+        stream: S,
+        stats: Stats,
+    }
+}
+
+impl<S, T, E> Stream for InstrumentedStream<S>
+where
+    S: Stream<Item = Result<T, E>>,
+    T: Payload,
+{
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+        let this = self.project();
+        let item = ready!(this.stream.poll_next(cx));
+        if let Some(Ok(msg)) = &item {
+            let len = msg.payload_len();
+            let mut counters = this.stats.lock();
+            counters.received_messages += 1;
+            counters.received_bytes += len as u64;
+            counters.last_received = Some(Instant::now());
+            drop(counters);
+            // Closed right away: it marks when the message arrived
+            drop(this.stats.recv_span(len));
+        }
+        Poll::Ready(item)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{sink, stream, SinkExt, StreamExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_counts_both_halves() {
+        let stats = Stats::new().with_spans("test");
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let collect = sink::unfold(sent.clone(), |sent, msg: Bytes| async move {
+            sent.lock().unwrap().push(msg);
+            anyhow::Ok(sent)
+        });
+        let mut sink = std::pin::pin!(stats.sink(collect));
+
+        assert_eq!(stats.snapshot(), Snapshot::default());
+        sink.send(Bytes::from_static(b"abc")).await.unwrap();
+        sink.feed(Bytes::from_static(b"de")).await.unwrap();
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.sent_messages, 2);
+        assert_eq!(snapshot.sent_bytes, 5);
+        assert!(snapshot.in_flight_since.is_some(), "fed without a flush");
+        sink.flush().await.unwrap();
+        assert!(stats.snapshot().in_flight().is_none());
+
+        let sent = std::mem::take(&mut *sent.lock().unwrap());
+        let sent = sent.into_iter().map(anyhow::Ok);
+        let mut stream = stats.stream(stream::iter(sent));
+        assert_eq!(stream.next().await.unwrap().unwrap(), "abc");
+        assert_eq!(stream.next().await.unwrap().unwrap(), "de");
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.received_messages, 2);
+        assert_eq!(snapshot.received_bytes, 5);
+        assert_eq!(snapshot.last_activity(), snapshot.last_received);
+    }
+
+    #[tokio::test]
+    async fn test_errors_are_not_counted() {
+        let stats = Stats::new();
+        let items = [Ok(Bytes::from_static(b"ok")), Err(anyhow::anyhow!("bad"))];
+        let mut stream = stats.stream(stream::iter(items));
+        assert!(stream.next().await.unwrap().is_ok());
+        assert!(stream.next().await.unwrap().is_err());
+        assert_eq!(stats.snapshot().received_messages, 1);
+    }
+}
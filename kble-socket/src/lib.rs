@@ -7,6 +7,54 @@ use futures_util::{Sink, Stream};
 pub type SocketSink = Pin<Box<dyn Sink<Bytes, Error = anyhow::Error> + Send + 'static>>;
 pub type SocketStream = Pin<Box<dyn Stream<Item = Result<Bytes>> + Send + 'static>>;
 
+#[cfg(any(feature = "tungstenite", feature = "axum"))]
+mod builder;
+#[cfg(any(feature = "tungstenite", feature = "axum"))]
+pub use builder::{Builder, Socket};
+#[cfg(any(feature = "tungstenite", feature = "axum"))]
+mod control;
+#[cfg(any(feature = "tungstenite", feature = "axum"))]
+pub use control::{ControlMessage, ControlSink, ControlStream};
+#[cfg(any(feature = "tungstenite", feature = "axum"))]
+mod driver;
+#[cfg(any(feature = "tungstenite", feature = "axum"))]
+mod envelope;
+#[cfg(any(feature = "tungstenite", feature = "axum"))]
+pub use driver::{Keepalive, KeepaliveTimeout};
+#[cfg(any(feature = "tungstenite", feature = "axum"))]
+pub use envelope::{Envelope, EnvelopeSink, EnvelopeStream, Enveloped};
+#[cfg(any(feature = "tungstenite", feature = "axum"))]
+mod message;
+#[cfg(any(feature = "tungstenite", feature = "axum"))]
+pub use message::{CloseError, MessageTooLong};
+
+#[cfg(feature = "batch")]
+pub mod batch;
+
+#[cfg(feature = "blocking")]
+pub mod blocking;
+
+#[cfg(feature = "codec")]
+pub mod codec;
+
+#[cfg(feature = "instrument")]
+pub mod instrument;
+
+#[cfg(feature = "listen")]
+mod listen;
+#[cfg(feature = "listen")]
+pub use listen::{ListenAddr, ListenArgs, Listener};
+
+#[cfg(feature = "logging")]
+pub mod logging;
+
+#[cfg(feature = "net")]
+mod net;
+#[cfg(all(feature = "net", unix))]
+pub use net::from_unix;
+#[cfg(feature = "net")]
+pub use net::{from_tcp, Handshake};
+
 #[cfg(feature = "stdio")]
 mod stdio;
 #[cfg(feature = "stdio")]
@@ -15,7 +63,12 @@ pub use stdio::Stdio;
 // re-export on both features so `features = ["stdio"]` alone still compiles
 // (giving the `Stdio` byte-stream adapter without `from_stdio`).
 #[cfg(all(feature = "stdio", feature = "tungstenite"))]
-pub use stdio::from_stdio;
+pub use stdio::{from_stdio, run_stdio};
+
+#[cfg(feature = "tungstenite")]
+mod framing;
+#[cfg(feature = "tungstenite")]
+pub use framing::{from_length_prefixed, FRAMING_ENV, LENGTH_PREFIXED};
 
 #[cfg(feature = "tungstenite")]
 mod tungstenite;
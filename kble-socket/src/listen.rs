@@ -0,0 +1,104 @@
+use std::{fmt, net::SocketAddr, str::FromStr};
+
+use anyhow::{anyhow, Context, Result};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::{tungstenite::protocol::WebSocketConfig, WebSocketStream};
+
+/// Command line option to serve a plug over WebSocket instead of stdio, to be
+/// flattened into a binary's arguments
+#[derive(clap::Args, Debug, Clone)]
+pub struct ListenArgs {
+    /// Serve over WebSocket at this address, e.g. `ws://0.0.0.0:9600`, for
+    /// `kble` to connect to as a `ws://` plug, instead of over stdin/stdout
+    #[clap(long, value_name = "URL", global = true)]
+    pub listen: Option<ListenAddr>,
+}
+
+/// A `ws://host:port` address to listen at. A path is allowed and ignored, so
+/// the URL a `kble` spaghetti file uses to connect can be given as is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListenAddr {
+    /// `host:port`
+    authority: String,
+}
+
+impl FromStr for ListenAddr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let rest = s
+            .strip_prefix("ws://")
+            .ok_or_else(|| anyhow!("Invalid listen address {s:?}: only ws:// is supported"))?;
+        let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+        let port = authority
+            .rsplit_once(':')
+            .map(|(_, port)| port)
+            .ok_or_else(|| anyhow!("Invalid listen address {s:?}: no port"))?;
+        port.parse::<u16>()
+            .map_err(|_| anyhow!("Invalid listen address {s:?}: invalid port {port:?}"))?;
+        Ok(ListenAddr {
+            authority: authority.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ws://{}", self.authority)
+    }
+}
+
+/// Accepts WebSocket connections, to be run over with
+/// [`Builder::run_tungstenite`](crate::Builder::run_tungstenite) and the like.
+/// See [`Builder::listen`](crate::Builder::listen).
+pub struct Listener {
+    tcp: TcpListener,
+    config: WebSocketConfig,
+}
+
+impl Listener {
+    pub(crate) async fn bind(addr: &ListenAddr, config: WebSocketConfig) -> Result<Self> {
+        let tcp = TcpListener::bind(addr.authority.as_str())
+            .await
+            .with_context(|| format!("Failed to listen at {addr}"))?;
+        Ok(Listener { tcp, config })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.tcp.local_addr()?)
+    }
+
+    /// Wait for the next connection, and complete its WebSocket handshake. A
+    /// failed handshake fails only that connection, so accept again after it.
+    /// Failing to accept the connection at all is an [`io::Error`](std::io::Error),
+    /// e.g. when out of file descriptors: back off before accepting again.
+    pub async fn accept(&self) -> Result<(WebSocketStream<TcpStream>, SocketAddr)> {
+        let (tcp, peer) = self.tcp.accept().await.context("Failed to accept")?;
+        let ws = tokio_tungstenite::accept_async_with_config(tcp, Some(self.config))
+            .await
+            .with_context(|| format!("WebSocket handshake with {peer} failed"))?;
+        Ok((ws, peer))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let addr: ListenAddr = "ws://0.0.0.0:9600".parse().unwrap();
+        assert_eq!(addr.authority, "0.0.0.0:9600");
+        let addr: ListenAddr = "ws://[::1]:9600/eb90?x=1".parse().unwrap();
+        assert_eq!(addr.authority, "[::1]:9600");
+        assert_eq!(addr.to_string(), "ws://[::1]:9600");
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!("wss://0.0.0.0:9600".parse::<ListenAddr>().is_err());
+        assert!("0.0.0.0:9600".parse::<ListenAddr>().is_err());
+        assert!("ws://localhost".parse::<ListenAddr>().is_err());
+        assert!("ws://localhost:http".parse::<ListenAddr>().is_err());
+    }
+}
@@ -0,0 +1,168 @@
+use std::{fmt, io};
+
+use tracing::{Event, Subscriber};
+use tracing_subscriber::{
+    fmt::{
+        format::{Format, Json, JsonFields, Writer},
+        layer, FmtContext, FormatEvent, FormatFields, Layer,
+    },
+    prelude::*,
+    registry::LookupSpan,
+    EnvFilter,
+};
+
+/// Environment variable naming the plug a process runs as. `kble` sets it for
+/// `exec:` plugs, and JSON logs include it as `plug`.
+pub const PLUG_NAME_ENV: &str = "KBLE_PLUG_NAME";
+
+/// Environment variable choosing the log format, as `--log-format` does
+pub const LOG_FORMAT_ENV: &str = "KBLE_LOG_FORMAT";
+
+/// Command line options for logging, to be flattened into a binary's arguments
+#[derive(clap::Args, Debug, Clone)]
+pub struct LogArgs {
+    /// Format of the logs written to stderr
+    #[clap(long, env = LOG_FORMAT_ENV, value_enum, default_value_t, global = true)]
+    pub log_format: LogFormat,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human-readable lines
+    #[default]
+    Text,
+    /// JSON lines with the span context, and the plug name when run by `kble`
+    Json,
+}
+
+impl LogFormat {
+    /// The value of `--log-format` and `KBLE_LOG_FORMAT` for this format
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LogFormat::Text => "text",
+            LogFormat::Json => "json",
+        }
+    }
+}
+
+impl LogArgs {
+    /// Install the global subscriber, filtered by `RUST_LOG`
+    pub fn init(&self) {
+        let registry = tracing_subscriber::registry();
+        let filter = EnvFilter::from_default_env();
+        match self.log_format {
+            LogFormat::Text => registry
+                .with(layer().with_ansi(false).with_writer(io::stderr))
+                .with(filter)
+                .init(),
+            LogFormat::Json => {
+                let plug = std::env::var(PLUG_NAME_ENV).ok();
+                registry
+                    .with(json_layer(plug).with_writer(io::stderr))
+                    .with(filter)
+                    .init()
+            }
+        }
+    }
+}
+
+fn json_layer<S>(plug: Option<String>) -> Layer<S, JsonFields, WithPlug<Format<Json>>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let format = tracing_subscriber::fmt::format()
+        .json()
+        .with_current_span(true)
+        .with_span_list(true);
+    layer().json().event_format(WithPlug::new(format, plug))
+}
+
+/// Adds `"plug": <name>` to each JSON line of `inner`
+struct WithPlug<F> {
+    inner: F,
+    /// The plug name as a JSON string
+    plug: Option<String>,
+}
+
+impl<F> WithPlug<F> {
+    fn new(inner: F, plug: Option<String>) -> Self {
+        let plug = plug.map(|plug| serde_json::to_string(&plug).expect("a string is JSON"));
+        WithPlug { inner, plug }
+    }
+}
+
+impl<S, N, F> FormatEvent<S, N> for WithPlug<F>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+    F: FormatEvent<S, N>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let Some(plug) = &self.plug else {
+            return self.inner.format_event(ctx, writer, event);
+        };
+        let mut line = String::new();
+        self.inner
+            .format_event(ctx, Writer::new(&mut line), event)?;
+        match line.strip_prefix('{') {
+            Some(rest) => write!(writer, "{{\"plug\":{plug},{rest}"),
+            None => writer.write_str(&line),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn log_json(plug: Option<&str>) -> serde_json::Value {
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let subscriber = tracing_subscriber::registry()
+            .with(json_layer(plug.map(str::to_string)).with_writer(move || writer.clone()));
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("link", link = "a -> b");
+            let _enter = span.enter();
+            tracing::info!(bytes = 3, "forwarded");
+        });
+        let line = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    #[test]
+    fn test_json_with_plug_and_span() {
+        let line = log_json(Some("sat \"1\""));
+        assert_eq!(line["plug"], "sat \"1\"");
+        assert_eq!(line["fields"]["message"], "forwarded");
+        assert_eq!(line["fields"]["bytes"], 3);
+        assert_eq!(line["span"]["name"], "link");
+        assert_eq!(line["span"]["link"], "a -> b");
+        assert_eq!(line["spans"][0]["link"], "a -> b");
+    }
+
+    #[test]
+    fn test_json_without_plug() {
+        let line = log_json(None);
+        assert!(line.get("plug").is_none());
+        assert_eq!(line["fields"]["message"], "forwarded");
+    }
+}
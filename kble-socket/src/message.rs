@@ -0,0 +1,166 @@
+use std::fmt;
+
+use anyhow::Result;
+use bytes::Bytes;
+
+/// Close code for a normal closure (RFC 6455, 7.4.1)
+pub(crate) const NORMAL_CLOSURE: u16 = 1000;
+/// Close code for "an unexpected condition prevented the request from being
+/// fulfilled" (RFC 6455, 7.4.1), sent when a plug fails
+pub(crate) const INTERNAL_ERROR: u16 = 1011;
+/// Close reasons must fit a control frame's payload with the code (RFC 6455, 5.5)
+const MAX_REASON_LEN: usize = 123;
+
+/// The peer closed the WebSocket with a code other than Normal Closure (1000),
+/// e.g. a plug that failed. A socket's stream yields this as its last error,
+/// inside the `anyhow::Error` (see [`anyhow::Error::downcast_ref`]).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseError {
+    pub code: u16,
+    pub reason: String,
+}
+
+impl CloseError {
+    /// The Close frame sent for a plug that failed with `err`: Internal Error,
+    /// with the error chain as the reason, truncated to fit the frame.
+    pub(crate) fn from_error(err: &anyhow::Error) -> Self {
+        let mut reason = format!("{err:#}");
+        if reason.len() > MAX_REASON_LEN {
+            let mut end = MAX_REASON_LEN;
+            while !reason.is_char_boundary(end) {
+                end -= 1;
+            }
+            reason.truncate(end);
+        }
+        CloseError {
+            code: INTERNAL_ERROR,
+            reason,
+        }
+    }
+}
+
+impl fmt::Display for CloseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.reason.is_empty() {
+            write!(f, "closed with code {}", self.code)
+        } else {
+            write!(f, "closed with code {}: {}", self.code, self.reason)
+        }
+    }
+}
+
+impl std::error::Error for CloseError {}
+
+/// The peer sent a message, or a frame, over the socket's size limit (see
+/// [`Builder::max_message_size`](crate::Builder::max_message_size)). A
+/// socket's stream yields this as its last error, inside the `anyhow::Error`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageTooLong {
+    pub size: usize,
+    pub max_size: usize,
+}
+
+impl fmt::Display for MessageTooLong {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Received a message of {} bytes, over the limit of {} bytes",
+            self.size, self.max_size
+        )
+    }
+}
+
+impl std::error::Error for MessageTooLong {}
+
+/// The error of a failed read from the WebSocket: a [`MessageTooLong`] when
+/// tungstenite (or the length-prefixed framing) refused a message over the
+/// limit, else `err` itself.
+pub(crate) fn receive_error<E>(err: E) -> anyhow::Error
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(&err);
+    while let Some(e) = source {
+        if let Some(too_long) = too_long(e) {
+            return too_long.into();
+        }
+        source = e.source();
+    }
+    err.into()
+}
+
+fn too_long(err: &(dyn std::error::Error + 'static)) -> Option<MessageTooLong> {
+    // An `io::Error`'s source is its inner error's, so look inside
+    if let Some(err) = err.downcast_ref::<std::io::Error>() {
+        return err.get_ref()?.downcast_ref::<MessageTooLong>().copied();
+    }
+    // axum wraps the same tungstenite error
+    #[cfg(feature = "tungstenite")]
+    {
+        use tokio_tungstenite::tungstenite::error::{CapacityError, Error};
+        if let Some(Error::Capacity(CapacityError::MessageTooLong { size, max_size })) =
+            err.downcast_ref::<Error>()
+        {
+            return Some(MessageTooLong {
+                size: *size,
+                max_size: *max_size,
+            });
+        }
+    }
+    None
+}
+
+/// What a received WebSocket message means to a binary socket.
+pub(crate) enum Frame {
+    Binary(Bytes),
+    /// A control message, for sockets with a control channel
+    Text(String),
+    /// A Close frame; `None` without a code
+    Close(Option<CloseError>),
+    /// Ping and Pong
+    Other,
+}
+
+/// A WebSocket message type the adapters can work with.
+pub(crate) trait Message: Send + 'static {
+    fn binary(data: Bytes) -> Self;
+    fn text(text: String) -> Self;
+    fn ping() -> Self;
+    fn close(code: u16, reason: String) -> Self;
+    fn into_frame(self) -> Frame;
+}
+
+/// The stream item for `msg`: the payload of a Binary frame, an error for an
+/// abnormal Close, or `None` to skip it.
+pub(crate) fn stream_item<M: Message>(msg: M) -> Result<Option<Bytes>> {
+    frame_item(msg.into_frame())
+}
+
+/// [`stream_item`] for a frame already taken apart
+pub(crate) fn frame_item(frame: Frame) -> Result<Option<Bytes>> {
+    match frame {
+        Frame::Binary(data) => Ok(Some(data)),
+        Frame::Close(Some(close)) if close.code != NORMAL_CLOSURE => Err(close.into()),
+        Frame::Close(_) | Frame::Text(_) | Frame::Other => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_close_reason_is_the_error_chain() {
+        let err = anyhow::anyhow!("too short").context("Invalid frame");
+        let close = CloseError::from_error(&err);
+        assert_eq!(close.code, INTERNAL_ERROR);
+        assert_eq!(close.reason, "Invalid frame: too short");
+    }
+
+    #[test]
+    fn test_close_reason_is_truncated_on_a_char_boundary() {
+        let err = anyhow::anyhow!("{}", "é".repeat(100));
+        let close = CloseError::from_error(&err);
+        assert_eq!(close.reason, "é".repeat(61));
+    }
+}
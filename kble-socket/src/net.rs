@@ -0,0 +1,75 @@
+use anyhow::{Context, Result};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio_tungstenite::{
+    tungstenite::protocol::{Role, WebSocketConfig},
+    WebSocketStream,
+};
+
+use crate::{SocketSink, SocketStream};
+
+/// How [`from_tcp`] and [`from_unix`] open a WebSocket over their stream
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Handshake {
+    /// Request the upgrade as the client, to this URL (`ws://host:port/path`;
+    /// only the host and path are sent)
+    Client(String),
+    /// Accept the upgrade request as the server
+    Server,
+    /// No upgrade: frame from the first byte, in this role, as `kble` and its
+    /// `exec:` plugs do over stdio. Both ends must skip it.
+    Skip(Role),
+}
+
+/// A binary socket over a TCP stream, opened as `handshake` says. See
+/// [`Builder::build_tcp`](crate::Builder::build_tcp) for keepalive and size
+/// limits.
+pub async fn from_tcp(
+    stream: TcpStream,
+    handshake: Handshake,
+) -> Result<(SocketSink, SocketStream)> {
+    Ok(crate::Builder::new()
+        .build_tcp(stream, handshake)
+        .await?
+        .split())
+}
+
+/// A binary socket over a Unix domain socket stream, opened as `handshake`
+/// says. See [`Builder::build_unix`](crate::Builder::build_unix) for keepalive
+/// and size limits.
+#[cfg(unix)]
+pub async fn from_unix(
+    stream: UnixStream,
+    handshake: Handshake,
+) -> Result<(SocketSink, SocketStream)> {
+    Ok(crate::Builder::new()
+        .build_unix(stream, handshake)
+        .await?
+        .split())
+}
+
+/// Open a WebSocket over `io` as `handshake` says
+pub(crate) async fn open<S>(
+    io: S,
+    handshake: Handshake,
+    config: WebSocketConfig,
+) -> Result<WebSocketStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let ws = match handshake {
+        Handshake::Client(url) => {
+            let (ws, _) = tokio_tungstenite::client_async_with_config(&url, io, Some(config))
+                .await
+                .with_context(|| format!("WebSocket handshake with {url} failed"))?;
+            ws
+        }
+        Handshake::Server => tokio_tungstenite::accept_async_with_config(io, Some(config))
+            .await
+            .context("WebSocket handshake failed")?,
+        Handshake::Skip(role) => WebSocketStream::from_raw_socket(io, role, Some(config)).await,
+    };
+    Ok(ws)
+}
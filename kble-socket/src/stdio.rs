@@ -7,7 +7,9 @@ use tokio::io::{AsyncRead, AsyncWrite, Stdin, Stdout};
 use crate::{SocketSink, SocketStream};
 
 /// Build a binary socket over this process's stdin/stdout (the WebSocket
-/// *server* side; the orchestrator is the client).
+/// *server* side; the orchestrator is the client). When the orchestrator
+/// offers length-prefixed framing (see [`crate::FRAMING_ENV`]), it is used
+/// instead of WebSocket.
 ///
 /// Pipe stdin/stdout (how a plug is launched) are read/written cancellably so
 /// the plug shuts down cleanly even when it ends for a reason other than stdin
@@ -15,10 +17,34 @@ use crate::{SocketSink, SocketStream};
 /// [`StdinReader`] for the why.
 #[cfg(feature = "tungstenite")]
 pub async fn from_stdio() -> (SocketSink, SocketStream) {
-    use tokio_tungstenite::{tungstenite::protocol::Role, WebSocketStream};
-    let stdio = AutoStdio::new();
-    let wss = WebSocketStream::from_raw_socket(stdio, Role::Server, None).await;
-    crate::from_tungstenite(wss)
+    crate::Builder::new().build_stdio().await.split()
+}
+
+/// Run a plug over this process's stdin/stdout (see [`from_stdio`]), and tell
+/// the orchestrator how it ended: when `plug` fails, the WebSocket is closed
+/// with Internal Error (1011) and the error as the reason, which `kble` logs
+/// and reports. Returns what `plug` returned, for `main` to return in turn.
+#[cfg(feature = "tungstenite")]
+pub async fn run_stdio<F, Fut>(plug: F) -> Result<()>
+where
+    F: FnOnce(SocketSink, SocketStream) -> Fut,
+    Fut: std::future::Future<Output = Result<()>>,
+{
+    crate::Builder::new()
+        .run_stdio(|socket| {
+            let (sink, stream) = socket.split();
+            plug(sink, stream)
+        })
+        .await
+}
+
+/// The socket over stdin/stdout: length-prefixed framing when `kble` offered
+/// it through [`crate::FRAMING_ENV`], else a WebSocket
+#[cfg(feature = "tungstenite")]
+pub(crate) async fn negotiate(
+    config: tokio_tungstenite::tungstenite::protocol::WebSocketConfig,
+) -> crate::framing::Negotiated<AutoStdio> {
+    crate::framing::accept(AutoStdio::new(), crate::framing::offered(), config).await
 }
 
 /// The stdin half of [`from_stdio`]'s socket.
@@ -95,7 +121,7 @@ fn unix_pipe_stdout() -> Option<tokio::net::unix::pipe::Sender> {
 /// stdin + stdout (each cancellable when a pipe) as one duplex stream. Every
 /// half is `Unpin`, so the trait impls project via `get_mut`, not `pin_project`.
 #[cfg(feature = "tungstenite")]
-struct AutoStdio {
+pub(crate) struct AutoStdio {
     stdin: StdinReader,
     stdout: StdoutWriter,
 }
@@ -1,11 +1,24 @@
 use bytes::Bytes;
 use futures_util::{future, stream, SinkExt, StreamExt, TryStreamExt};
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+use tokio_tungstenite::{
+    tungstenite::{protocol::CloseFrame, Message},
+    WebSocketStream,
+};
 
-use crate::{SocketSink, SocketStream};
+use crate::{
+    message::{self, CloseError, Frame},
+    SocketSink, SocketStream,
+};
 
 pub fn from_tungstenite<S>(wss: WebSocketStream<S>) -> (SocketSink, SocketStream)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    crate::Builder::new().build_tungstenite(wss).split()
+}
+
+pub(crate) fn split<S>(wss: WebSocketStream<S>) -> (SocketSink, SocketStream)
 where
     S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
 {
@@ -14,10 +27,40 @@ where
         .with_flat_map(|b| stream::iter([Ok(Message::Binary(Bytes::into(b)))]))
         .sink_map_err(Into::into);
     let stream = stream
-        .try_filter_map(|msg| match msg {
-            Message::Binary(b) => future::ok(Some(b.into())),
-            _ => future::ok(None),
-        })
-        .map_err(Into::into);
+        .map_err(message::receive_error)
+        .try_filter_map(|msg| future::ready(message::stream_item(msg)));
     (Box::pin(sink), Box::pin(stream))
 }
+
+impl message::Message for Message {
+    fn binary(data: Bytes) -> Self {
+        Message::Binary(data.into())
+    }
+
+    fn text(text: String) -> Self {
+        Message::Text(text)
+    }
+
+    fn ping() -> Self {
+        Message::Ping(Vec::new())
+    }
+
+    fn close(code: u16, reason: String) -> Self {
+        Message::Close(Some(CloseFrame {
+            code: code.into(),
+            reason: reason.into(),
+        }))
+    }
+
+    fn into_frame(self) -> Frame {
+        match self {
+            Message::Binary(b) => Frame::Binary(b.into()),
+            Message::Text(text) => Frame::Text(text),
+            Message::Close(frame) => Frame::Close(frame.map(|frame| CloseError {
+                code: frame.code.into(),
+                reason: frame.reason.into_owned(),
+            })),
+            _ => Frame::Other,
+        }
+    }
+}
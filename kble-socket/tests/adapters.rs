@@ -125,3 +125,747 @@ async fn from_axum_round_trips_binary_frames() {
     // Bounded like the rest: a hung close shouldn't stall the suite.
     let _ = tokio::time::timeout(TIMEOUT, client.close(None)).await;
 }
+
+/// Keepalive settings short enough for a test to outwait the timeout.
+#[cfg(feature = "tungstenite")]
+const KEEPALIVE: kble_socket::Keepalive = kble_socket::Keepalive {
+    interval: std::time::Duration::from_millis(100),
+    timeout: std::time::Duration::from_millis(400),
+};
+
+/// Keepalive settings that get a socket driven by a background task without
+/// Pings or timeouts getting in the way of a test.
+#[cfg(feature = "tungstenite")]
+const DRIVEN: kble_socket::Keepalive = kble_socket::Keepalive {
+    interval: std::time::Duration::from_secs(60),
+    timeout: std::time::Duration::from_secs(120),
+};
+
+/// `Builder::keepalive`: a peer that never reads never answers the Pings, so
+/// the adapter's stream fails once the timeout passes, and its sink with it.
+#[cfg(feature = "tungstenite")]
+#[tokio::test]
+async fn keepalive_fails_the_socket_when_the_peer_goes_silent() {
+    use bytes::Bytes;
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::protocol::Role;
+    use tokio_tungstenite::WebSocketStream;
+
+    let (server_io, client_io) = tokio::io::duplex(64 * 1024);
+    let server = WebSocketStream::from_raw_socket(server_io, Role::Server, None).await;
+    // Held but never polled: the silent peer
+    let _client = WebSocketStream::from_raw_socket(client_io, Role::Client, None).await;
+    let (mut sink, mut stream) = kble_socket::Builder::new()
+        .keepalive(KEEPALIVE)
+        .build_tungstenite(server)
+        .split();
+
+    let err = tokio::time::timeout(TIMEOUT, stream.next())
+        .await
+        .expect("keepalive never timed out")
+        .expect("stream reports the timeout before ending")
+        .expect_err("a silent peer is an error");
+    assert_eq!(
+        err.downcast_ref::<kble_socket::KeepaliveTimeout>(),
+        Some(&kble_socket::KeepaliveTimeout {
+            timeout: KEEPALIVE.timeout
+        }),
+        "{err}"
+    );
+    assert!(tokio::time::timeout(TIMEOUT, stream.next())
+        .await
+        .expect("stream ends after the error")
+        .is_none());
+    tokio::time::timeout(TIMEOUT, sink.send(Bytes::from_static(b"too late")))
+        .await
+        .expect("adapter sink send timed out")
+        .expect_err("the sink fails along with the connection");
+}
+
+/// `Builder::keepalive`: the adapter pings an idle peer, a peer that keeps
+/// reading answers, and the connection outlives the timeout with frames still
+/// round-tripping.
+#[cfg(feature = "tungstenite")]
+#[tokio::test]
+async fn keepalive_keeps_an_answering_peer() {
+    use bytes::Bytes;
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::protocol::Role;
+    use tokio_tungstenite::tungstenite::Message;
+    use tokio_tungstenite::WebSocketStream;
+
+    let (server_io, client_io) = tokio::io::duplex(64 * 1024);
+    let server = WebSocketStream::from_raw_socket(server_io, Role::Server, None).await;
+    let mut client = WebSocketStream::from_raw_socket(client_io, Role::Client, None).await;
+    let (mut sink, mut stream) = kble_socket::Builder::new()
+        .keepalive(KEEPALIVE)
+        .build_tungstenite(server)
+        .split();
+
+    // Idle for several timeouts; reading makes the peer answer every Ping
+    let mut pings = 0;
+    let idle = tokio::time::timeout(KEEPALIVE.timeout * 3, async {
+        loop {
+            match client.next().await {
+                Some(Ok(Message::Ping(_))) => pings += 1,
+                other => panic!("unexpected frame on an idle connection: {other:?}"),
+            }
+        }
+    });
+    assert!(idle.await.is_err());
+    assert!(pings >= 2, "only {pings} Pings while idle");
+
+    tokio::time::timeout(TIMEOUT, client.send(Message::Binary(b"ping-free".to_vec())))
+        .await
+        .expect("peer send timed out")
+        .expect("peer sends a binary frame");
+    let got = tokio::time::timeout(TIMEOUT, stream.next())
+        .await
+        .expect("adapter stream timed out")
+        .expect("adapter stream yields a frame")
+        .expect("frame is not an error");
+    assert_eq!(&got[..], b"ping-free");
+
+    tokio::time::timeout(TIMEOUT, sink.send(Bytes::from_static(b"and back")))
+        .await
+        .expect("adapter sink send timed out")
+        .expect("adapter sink accepts a frame");
+    let echoed = loop {
+        match tokio::time::timeout(TIMEOUT, client.next())
+            .await
+            .expect("peer receive timed out")
+            .expect("peer receives a frame")
+            .expect("frame is not an error")
+        {
+            Message::Ping(_) => continue,
+            Message::Binary(b) => break b,
+            other => panic!("expected a binary frame, got: {other:?}"),
+        }
+    };
+    assert_eq!(&echoed[..], b"and back");
+
+    // Closing the sink closes the WebSocket and reports how that went
+    tokio::time::timeout(TIMEOUT, sink.close())
+        .await
+        .expect("adapter sink close timed out")
+        .expect("adapter sink closes cleanly");
+}
+
+/// `Builder::run_tungstenite`: a plug that fails closes the WebSocket with
+/// Internal Error and the error as the reason, after everything it sent; the
+/// peer's adapter stream reports that as a `CloseError`.
+#[cfg(feature = "tungstenite")]
+#[tokio::test]
+async fn run_reports_a_failure_in_the_close_frame() {
+    use bytes::Bytes;
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::protocol::Role;
+    use tokio_tungstenite::WebSocketStream;
+
+    let (server_io, client_io) = tokio::io::duplex(64 * 1024);
+    let server = WebSocketStream::from_raw_socket(server_io, Role::Server, None).await;
+    let client = WebSocketStream::from_raw_socket(client_io, Role::Client, None).await;
+    let (_client_sink, mut client_stream) = kble_socket::from_tungstenite(client);
+
+    let result = tokio::time::timeout(
+        TIMEOUT,
+        kble_socket::Builder::new().run_tungstenite(server, |socket| async move {
+            let (mut tx, _rx) = socket.split();
+            tx.send(Bytes::from_static(b"last words")).await?;
+            anyhow::bail!("frame is too short")
+        }),
+    )
+    .await
+    .expect("run timed out");
+    assert_eq!(result.unwrap_err().to_string(), "frame is too short");
+
+    let got = tokio::time::timeout(TIMEOUT, client_stream.next())
+        .await
+        .expect("peer stream timed out")
+        .expect("peer stream yields a frame")
+        .expect("frame is not an error");
+    assert_eq!(&got[..], b"last words");
+    let err = tokio::time::timeout(TIMEOUT, client_stream.next())
+        .await
+        .expect("peer stream timed out")
+        .expect("peer stream reports the close")
+        .expect_err("an Internal Error close is an error");
+    let close = err
+        .downcast_ref::<kble_socket::CloseError>()
+        .expect("the error is the close frame");
+    assert_eq!(close.code, 1011);
+    assert_eq!(close.reason, "frame is too short");
+}
+
+/// `Builder::run_tungstenite`: a plug that succeeds closes normally, which
+/// the peer's adapter stream reports as a plain end of stream.
+#[cfg(feature = "tungstenite")]
+#[tokio::test]
+async fn run_closes_normally_on_success() {
+    use futures_util::StreamExt;
+    use tokio_tungstenite::tungstenite::protocol::Role;
+    use tokio_tungstenite::WebSocketStream;
+
+    let (server_io, client_io) = tokio::io::duplex(64 * 1024);
+    let server = WebSocketStream::from_raw_socket(server_io, Role::Server, None).await;
+    let client = WebSocketStream::from_raw_socket(client_io, Role::Client, None).await;
+    let (_client_sink, mut client_stream) = kble_socket::from_tungstenite(client);
+
+    tokio::time::timeout(
+        TIMEOUT,
+        kble_socket::Builder::new().run_tungstenite(server, |_socket| async { Ok(()) }),
+    )
+    .await
+    .expect("run timed out")
+    .expect("run returns what the plug returned");
+
+    assert!(tokio::time::timeout(TIMEOUT, client_stream.next())
+        .await
+        .expect("peer stream timed out")
+        .is_none());
+}
+
+/// `Builder::control`: Text frames are control messages,
+/// kept apart from the data both ways. A Text frame that is not one is an error
+/// on the control stream only.
+#[cfg(feature = "tungstenite")]
+#[tokio::test]
+async fn control_messages_travel_apart_from_the_data() {
+    use futures_util::{SinkExt, StreamExt};
+    use kble_socket::ControlMessage;
+    use tokio_tungstenite::tungstenite::protocol::Role;
+    use tokio_tungstenite::tungstenite::Message;
+    use tokio_tungstenite::WebSocketStream;
+
+    let (server_io, client_io) = tokio::io::duplex(64 * 1024);
+    let server = WebSocketStream::from_raw_socket(server_io, Role::Server, None).await;
+    let mut client = WebSocketStream::from_raw_socket(client_io, Role::Client, None).await;
+    let (_sink, mut stream, mut control_sink, mut control_stream) = kble_socket::Builder::new()
+        .control()
+        .build_tungstenite(server)
+        .split_with_control();
+
+    for msg in [
+        Message::Text(r#"{"type":"set_baud_rate","baud_rate":9600}"#.to_string()),
+        Message::Text("not json".to_string()),
+        Message::Binary(b"data".to_vec()),
+    ] {
+        tokio::time::timeout(TIMEOUT, client.send(msg))
+            .await
+            .expect("peer send timed out")
+            .expect("peer sends a frame");
+    }
+    let control = tokio::time::timeout(TIMEOUT, control_stream.next())
+        .await
+        .expect("control stream timed out")
+        .expect("control stream yields a message")
+        .expect("message is not an error");
+    assert_eq!(
+        control,
+        ControlMessage::new("set_baud_rate").with("baud_rate", 9600)
+    );
+    tokio::time::timeout(TIMEOUT, control_stream.next())
+        .await
+        .expect("control stream timed out")
+        .expect("control stream goes on")
+        .expect_err("a Text frame that is not JSON is an error");
+    let got = tokio::time::timeout(TIMEOUT, stream.next())
+        .await
+        .expect("adapter stream timed out")
+        .expect("adapter stream yields a frame")
+        .expect("frame is not an error");
+    assert_eq!(&got[..], b"data");
+
+    tokio::time::timeout(
+        TIMEOUT,
+        control_sink.send(ControlMessage::new("status").with("connected", true)),
+    )
+    .await
+    .expect("control sink send timed out")
+    .expect("control sink accepts a message");
+    match tokio::time::timeout(TIMEOUT, client.next())
+        .await
+        .expect("peer receive timed out")
+        .expect("peer receives a frame")
+        .expect("frame is not an error")
+    {
+        Message::Text(text) => assert_eq!(text, r#"{"type":"status","connected":true}"#),
+        other => panic!("expected a text frame, got: {other:?}"),
+    }
+}
+
+/// A control stream that is kept but not read drops the control messages it
+/// has no room for, rather than holding up the data behind them.
+#[cfg(feature = "tungstenite")]
+#[tokio::test]
+async fn unread_control_messages_do_not_hold_up_the_data() {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::protocol::Role;
+    use tokio_tungstenite::tungstenite::Message;
+    use tokio_tungstenite::WebSocketStream;
+
+    let (server_io, client_io) = tokio::io::duplex(64 * 1024);
+    let server = WebSocketStream::from_raw_socket(server_io, Role::Server, None).await;
+    let mut client = WebSocketStream::from_raw_socket(client_io, Role::Client, None).await;
+    let (_sink, mut stream, _control_sink, mut control_stream) = kble_socket::Builder::new()
+        .control()
+        .build_tungstenite(server)
+        .split_with_control();
+
+    for _ in 0..100 {
+        let status = Message::Text(r#"{"type":"status"}"#.to_string());
+        tokio::time::timeout(TIMEOUT, client.send(status))
+            .await
+            .expect("peer send timed out")
+            .expect("peer sends a frame");
+    }
+    tokio::time::timeout(TIMEOUT, client.send(Message::Binary(b"data".to_vec())))
+        .await
+        .expect("peer send timed out")
+        .expect("peer sends a frame");
+    let got = tokio::time::timeout(TIMEOUT, stream.next())
+        .await
+        .expect("the data waited for the control stream")
+        .expect("adapter stream yields a frame")
+        .expect("frame is not an error");
+    assert_eq!(&got[..], b"data");
+
+    // The first ones are kept
+    let control = tokio::time::timeout(TIMEOUT, control_stream.next())
+        .await
+        .expect("control stream timed out")
+        .expect("control stream yields a message")
+        .expect("message is not an error");
+    assert_eq!(control.kind, "status");
+}
+
+/// `Builder::control`: a peer without a control channel
+/// skips the control messages and still gets the data.
+#[cfg(feature = "tungstenite")]
+#[tokio::test]
+async fn control_messages_are_skipped_without_a_control_channel() {
+    use bytes::Bytes;
+    use futures_util::{SinkExt, StreamExt};
+    use kble_socket::ControlMessage;
+    use tokio_tungstenite::tungstenite::protocol::Role;
+    use tokio_tungstenite::WebSocketStream;
+
+    let (server_io, client_io) = tokio::io::duplex(64 * 1024);
+    let server = WebSocketStream::from_raw_socket(server_io, Role::Server, None).await;
+    let client = WebSocketStream::from_raw_socket(client_io, Role::Client, None).await;
+    let (_client_sink, mut client_stream) = kble_socket::from_tungstenite(client);
+
+    tokio::time::timeout(
+        TIMEOUT,
+        kble_socket::Builder::new()
+            .control()
+            .run_tungstenite(server, |socket| async move {
+                let (mut tx, _rx, mut control_tx, _control_rx) = socket.split_with_control();
+                control_tx.send(ControlMessage::new("status")).await?;
+                tx.send(Bytes::from_static(b"data")).await?;
+                Ok(())
+            }),
+    )
+    .await
+    .expect("run timed out")
+    .expect("run returns what the plug returned");
+
+    let got = tokio::time::timeout(TIMEOUT, client_stream.next())
+        .await
+        .expect("peer stream timed out")
+        .expect("peer stream yields a frame")
+        .expect("frame is not an error");
+    assert_eq!(&got[..], b"data");
+}
+
+/// `Builder::offer_envelopes` and `Builder::envelopes`: a client that offers
+/// and a server that answers carry envelopes both ways, while a server facing
+/// a plain client sends it bare data and no handshake.
+#[cfg(feature = "tungstenite")]
+#[tokio::test]
+async fn envelopes_are_carried_between_peers_that_ask() {
+    use bytes::Bytes;
+    use futures_util::{SinkExt, StreamExt};
+    use kble_socket::{Envelope, Enveloped};
+    use tokio_tungstenite::tungstenite::protocol::Role;
+    use tokio_tungstenite::tungstenite::Message;
+    use tokio_tungstenite::WebSocketStream;
+
+    let msg = Enveloped {
+        envelope: Some(Envelope {
+            received_at: std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000),
+            sequence: 3,
+            origin: Some("uart".to_string()),
+        }),
+        data: Bytes::from_static(b"data"),
+    };
+
+    let (server_io, client_io) = tokio::io::duplex(64 * 1024);
+    let server = WebSocketStream::from_raw_socket(server_io, Role::Server, None).await;
+    let client = WebSocketStream::from_raw_socket(client_io, Role::Client, None).await;
+    let builder = kble_socket::Builder::new().envelopes();
+    let (mut server_sink, mut server_stream, _, _) =
+        builder.build_tungstenite(server).split_enveloped();
+    let (mut client_sink, mut client_stream, _, _) = kble_socket::Builder::new()
+        .offer_envelopes()
+        .build_tungstenite(client)
+        .split_enveloped();
+    for (sink, stream) in [
+        (&mut server_sink, &mut client_stream),
+        (&mut client_sink, &mut server_stream),
+    ] {
+        // Messages sent before the handshake is through arrive bare
+        let sent = async {
+            loop {
+                if let Err(e) = sink.send(msg.clone()).await {
+                    return e;
+                }
+            }
+        };
+        let received = async {
+            loop {
+                let got = stream
+                    .next()
+                    .await
+                    .expect("stream yields a message")
+                    .expect("message is not an error");
+                assert_eq!(got.data, msg.data);
+                if got.envelope.is_some() {
+                    assert_eq!(got.envelope, msg.envelope);
+                    break;
+                }
+            }
+        };
+        tokio::time::timeout(TIMEOUT, async {
+            tokio::select! {
+                e = sent => panic!("sending failed: {e:#}"),
+                () = received => {}
+            }
+        })
+        .await
+        .expect("the envelope never arrived");
+    }
+
+    let (server_io, client_io) = tokio::io::duplex(64 * 1024);
+    let server = WebSocketStream::from_raw_socket(server_io, Role::Server, None).await;
+    let mut client = WebSocketStream::from_raw_socket(client_io, Role::Client, None).await;
+    let (mut sink, _stream, _, _) = builder.build_tungstenite(server).split_enveloped();
+    tokio::time::timeout(TIMEOUT, sink.send(msg.clone()))
+        .await
+        .expect("adapter sink send timed out")
+        .expect("adapter sink accepts a message");
+    let got = tokio::time::timeout(TIMEOUT, client.next())
+        .await
+        .expect("peer stream timed out")
+        .expect("peer stream yields a frame")
+        .expect("frame is not an error");
+    assert_eq!(got, Message::Binary(msg.data.to_vec()));
+}
+
+/// `Builder::max_message_size`: a message within the limit arrives, and one
+/// over it ends the stream with `MessageTooLong`, both with and without the
+/// driver task.
+#[cfg(feature = "tungstenite")]
+#[tokio::test]
+async fn max_message_size_fails_the_socket_with_a_clear_error() {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::protocol::Role;
+    use tokio_tungstenite::tungstenite::Message;
+    use tokio_tungstenite::WebSocketStream;
+
+    for keepalive in [None, Some(KEEPALIVE)] {
+        let mut builder = kble_socket::Builder::new().max_message_size(16);
+        if let Some(keepalive) = keepalive {
+            builder = builder.keepalive(keepalive);
+        }
+        let (server_io, client_io) = tokio::io::duplex(64 * 1024);
+        let config = Some(builder.websocket_config());
+        let server = WebSocketStream::from_raw_socket(server_io, Role::Server, config).await;
+        let mut client = WebSocketStream::from_raw_socket(client_io, Role::Client, None).await;
+        let (_sink, mut stream) = builder.build_tungstenite(server).split();
+
+        for size in [16, 32] {
+            tokio::time::timeout(TIMEOUT, client.send(Message::Binary(vec![0; size])))
+                .await
+                .expect("peer send timed out")
+                .expect("peer sends a binary frame");
+        }
+        let got = tokio::time::timeout(TIMEOUT, stream.next())
+            .await
+            .expect("adapter stream timed out")
+            .expect("adapter stream yields a frame")
+            .expect("a message within the limit is not an error");
+        assert_eq!(got.len(), 16);
+        let err = tokio::time::timeout(TIMEOUT, stream.next())
+            .await
+            .expect("adapter stream timed out")
+            .expect("stream reports the oversized message")
+            .expect_err("a message over the limit is an error");
+        assert_eq!(
+            err.downcast_ref::<kble_socket::MessageTooLong>(),
+            Some(&kble_socket::MessageTooLong {
+                size: 32,
+                max_size: 16
+            }),
+            "{err}"
+        );
+    }
+}
+
+/// `from_length_prefixed`: bytes round-trip both ways between two adapters
+/// over a duplex, and a peer that keeps reading answers the keepalive Pings
+/// as a WebSocket peer does.
+#[cfg(feature = "tungstenite")]
+#[tokio::test]
+async fn from_length_prefixed_round_trips_and_answers_pings() {
+    use bytes::Bytes;
+    use futures_util::{SinkExt, StreamExt};
+
+    let (a_io, b_io) = tokio::io::duplex(64 * 1024);
+    let (mut a_sink, mut a_stream) = kble_socket::Builder::new()
+        .keepalive(KEEPALIVE)
+        .build_length_prefixed(a_io)
+        .split();
+    let (mut b_sink, mut b_stream) = kble_socket::from_length_prefixed(b_io);
+
+    // Idle for several timeouts while the peer reads
+    let idle = tokio::time::timeout(KEEPALIVE.timeout * 3, b_stream.next());
+    assert!(
+        idle.await.is_err(),
+        "nothing but Pings on an idle connection"
+    );
+
+    tokio::time::timeout(TIMEOUT, b_sink.send(Bytes::from_static(b"to a")))
+        .await
+        .expect("peer send timed out")
+        .expect("peer sends a frame");
+    let got = tokio::time::timeout(TIMEOUT, a_stream.next())
+        .await
+        .expect("adapter stream timed out")
+        .expect("adapter stream yields a frame")
+        .expect("the peer answered the Pings");
+    assert_eq!(&got[..], b"to a");
+
+    tokio::time::timeout(TIMEOUT, a_sink.send(Bytes::from_static(b"to b")))
+        .await
+        .expect("adapter sink send timed out")
+        .expect("adapter sink accepts a frame");
+    let got = tokio::time::timeout(TIMEOUT, b_stream.next())
+        .await
+        .expect("peer stream timed out")
+        .expect("peer stream yields a frame")
+        .expect("frame is not an error");
+    assert_eq!(&got[..], b"to b");
+
+    // Closing one end ends the other's stream
+    tokio::time::timeout(TIMEOUT, a_sink.close())
+        .await
+        .expect("adapter sink close timed out")
+        .expect("adapter sink closes cleanly");
+    let end = tokio::time::timeout(TIMEOUT, b_stream.next())
+        .await
+        .expect("peer stream timed out");
+    assert!(end.is_none(), "the stream ends after the Close frame");
+}
+
+/// Send `data` on `sink` and receive it from `stream`, within [`TIMEOUT`]
+#[cfg(feature = "net")]
+async fn round_trip(
+    sink: &mut kble_socket::SocketSink,
+    stream: &mut kble_socket::SocketStream,
+    data: &'static [u8],
+) {
+    use futures_util::{SinkExt, StreamExt};
+
+    tokio::time::timeout(TIMEOUT, sink.send(bytes::Bytes::from_static(data)))
+        .await
+        .expect("send timed out")
+        .expect("send");
+    let got = tokio::time::timeout(TIMEOUT, stream.next())
+        .await
+        .expect("receive timed out")
+        .expect("stream ended")
+        .expect("receive");
+    assert_eq!(got, data);
+}
+
+/// `from_tcp`: a client and a server socket, each over its end of a TCP
+/// connection, complete the HTTP upgrade and exchange frames both ways
+#[cfg(feature = "net")]
+#[tokio::test]
+async fn from_tcp_round_trips_with_a_handshake() {
+    use kble_socket::Handshake;
+    use tokio::net::{TcpListener, TcpStream};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}/plug", listener.local_addr().unwrap());
+    let (client, server) = tokio::join!(
+        async {
+            let tcp = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            kble_socket::from_tcp(tcp, Handshake::Client(url)).await
+        },
+        async {
+            let (tcp, _) = listener.accept().await.unwrap();
+            kble_socket::from_tcp(tcp, Handshake::Server).await
+        },
+    );
+    let (mut client_sink, mut client_stream) = client.expect("client handshake");
+    let (mut server_sink, mut server_stream) = server.expect("server handshake");
+
+    round_trip(&mut client_sink, &mut server_stream, b"to the server").await;
+    round_trip(&mut server_sink, &mut client_stream, b"to the client").await;
+}
+
+/// A server expecting the upgrade fails a peer that sends WebSocket frames
+/// straight away, rather than taking them as a request
+#[cfg(feature = "net")]
+#[tokio::test]
+async fn from_tcp_fails_a_peer_that_skips_the_handshake() {
+    use kble_socket::Handshake;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_tungstenite::tungstenite::protocol::Role;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let (client, server) = tokio::join!(
+        async {
+            let tcp = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (mut sink, _stream) = kble_socket::from_tcp(tcp, Handshake::Skip(Role::Client))
+                .await
+                .expect("no handshake to fail");
+            futures_util::SinkExt::send(&mut sink, bytes::Bytes::from_static(b"raw"))
+                .await
+                .expect("send");
+            sink
+        },
+        async {
+            let (tcp, _) = listener.accept().await.unwrap();
+            tokio::time::timeout(TIMEOUT, kble_socket::from_tcp(tcp, Handshake::Server)).await
+        },
+    );
+    let err = server
+        .expect("handshake timed out")
+        .err()
+        .expect("the handshake fails");
+    assert!(
+        format!("{err:#}").contains("handshake"),
+        "unexpected error: {err:#}"
+    );
+    drop(client);
+}
+
+/// `from_unix`: both ends of a Unix socket pair skip the upgrade, as `kble`
+/// and an `exec:` plug do over stdio, and exchange frames both ways
+#[cfg(all(feature = "net", unix))]
+#[tokio::test]
+async fn from_unix_round_trips_without_a_handshake() {
+    use kble_socket::Handshake;
+    use tokio::net::UnixStream;
+    use tokio_tungstenite::tungstenite::protocol::Role;
+
+    let (client, server) = UnixStream::pair().unwrap();
+    let (mut client_sink, mut client_stream) =
+        kble_socket::from_unix(client, Handshake::Skip(Role::Client))
+            .await
+            .expect("client");
+    let (mut server_sink, mut server_stream) =
+        kble_socket::from_unix(server, Handshake::Skip(Role::Server))
+            .await
+            .expect("server");
+
+    round_trip(&mut client_sink, &mut server_stream, b"to the server").await;
+    round_trip(&mut server_sink, &mut client_stream, b"to the client").await;
+}
+
+/// A driven Builder socket's flush waits for the data to be written out: it
+/// stays pending while the peer reads nothing, and completes once it reads
+#[cfg(feature = "tungstenite")]
+#[tokio::test]
+async fn flush_waits_until_the_data_is_written() {
+    use bytes::Bytes;
+    use futures_util::SinkExt;
+
+    let (io, mut peer) = tokio::io::duplex(1024);
+    let (mut sink, _stream) = kble_socket::Builder::new()
+        .keepalive(DRIVEN)
+        .build_length_prefixed(io)
+        .split();
+
+    tokio::time::timeout(TIMEOUT, sink.feed(Bytes::from(vec![0; 16 * 1024])))
+        .await
+        .expect("feed timed out")
+        .expect("the sink takes the message");
+    let stalled = tokio::time::timeout(std::time::Duration::from_millis(200), sink.flush());
+    assert!(
+        stalled.await.is_err(),
+        "the flush completes before the peer read anything"
+    );
+
+    let drain =
+        tokio::spawn(async move { tokio::io::copy(&mut peer, &mut tokio::io::sink()).await });
+    tokio::time::timeout(TIMEOUT, sink.flush())
+        .await
+        .expect("flush timed out while the peer reads")
+        .expect("flush");
+    drain.abort();
+}
+
+/// `instrument::Stats` on a driven Builder socket whose peer stops reading:
+/// the time spent waiting for room and for the flush is counted
+#[cfg(all(feature = "tungstenite", feature = "instrument"))]
+#[tokio::test]
+async fn instrumented_sink_counts_the_time_a_stalled_peer_costs() {
+    use bytes::Bytes;
+    use futures_util::SinkExt;
+    use kble_socket::instrument::Stats;
+    use std::time::Duration;
+
+    const STALL: Duration = Duration::from_millis(300);
+
+    let (io, mut peer) = tokio::io::duplex(1024);
+    let (sink, _stream) = kble_socket::Builder::new()
+        .keepalive(DRIVEN)
+        .build_length_prefixed(io)
+        .split();
+    let stats = Stats::new();
+    let mut sink = stats.sink(sink);
+
+    let send = tokio::spawn(async move {
+        for _ in 0..3 {
+            sink.feed(Bytes::from(vec![0; 16 * 1024])).await?;
+        }
+        sink.flush().await?;
+        anyhow::Ok(sink)
+    });
+    tokio::time::sleep(STALL).await;
+    assert!(
+        stats.snapshot().in_flight().is_some(),
+        "nothing is in flight"
+    );
+
+    let drain =
+        tokio::spawn(async move { tokio::io::copy(&mut peer, &mut tokio::io::sink()).await });
+    tokio::time::timeout(TIMEOUT, send)
+        .await
+        .expect("send timed out while the peer reads")
+        .expect("sender task")
+        .expect("send");
+    drain.abort();
+    let snapshot = stats.snapshot();
+    assert_eq!(snapshot.sent_messages, 3);
+    // The last message waited for room all along
+    assert!(
+        snapshot.wait_time >= STALL / 2,
+        "the backpressure is missing from {:?}",
+        snapshot.wait_time
+    );
+    assert!(
+        snapshot.send_time >= STALL,
+        "the stall is missing from {:?}",
+        snapshot.send_time
+    );
+    assert!(snapshot.in_flight().is_none());
+}
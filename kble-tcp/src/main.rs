@@ -3,59 +3,73 @@ use std::net::SocketAddr;
 use anyhow::Result;
 use clap::Parser;
 use futures::{SinkExt, StreamExt};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use kble_plug::{BatchArgs, ListenArgs, LogArgs, Plug, PlugArgs, SocketSink, SocketStream};
+use notalawyer_clap::*;
+use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
-use tracing_subscriber::{prelude::*, EnvFilter};
+use tokio_util::io::ReaderStream;
 
-#[derive(Debug, Parser)]
+#[derive(Debug, Clone, Parser)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
+    #[clap(flatten)]
+    log: LogArgs,
+
+    #[clap(flatten)]
+    listen: ListenArgs,
+
+    #[clap(flatten)]
+    batch: BatchArgs,
+
     addr: SocketAddr,
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::fmt::layer()
-                .with_ansi(false)
-                .with_writer(std::io::stderr),
-        )
-        .with(EnvFilter::from_default_env())
-        .init();
-    let args = Args::parse();
-
-    let tcp_stream = TcpStream::connect(args.addr).await?;
-    let (mut tcp_upstream, mut tcp_downstream) = tokio::io::split(tcp_stream);
-    let (mut tx, mut rx) = kble_socket::from_stdio().await;
-    let to_tcp = async {
-        while let Some(body) = rx.next().await {
-            let body = body?;
-            tcp_downstream.write_all(&body).await?;
-        }
-        anyhow::Ok(())
-    };
-    let from_tcp = async {
-        let mut buffer = [0; 8192];
-        loop {
-            match tcp_upstream.read(&mut buffer).await? {
-                0 => break,
-                n => {
-                    tx.send(buffer[..n].to_vec().into()).await?;
-                }
+impl PlugArgs for Args {
+    fn log_args(&self) -> &LogArgs {
+        &self.log
+    }
+
+    fn listen_args(&self) -> &ListenArgs {
+        &self.listen
+    }
+}
+
+fn main() -> Result<()> {
+    kble_plug::main(include_notice!(), |args: &Args| args.clone())
+}
+
+impl Plug for Args {
+    async fn run(self, mut tx: SocketSink, mut rx: SocketStream) -> Result<()> {
+        let tcp_stream = TcpStream::connect(self.addr).await?;
+        let (tcp_upstream, mut tcp_downstream) = tokio::io::split(tcp_stream);
+        let to_tcp = async {
+            while let Some(body) = rx.next().await {
+                let body = body?;
+                tcp_downstream.write_all(&body).await?;
+            }
+            anyhow::Ok(())
+        };
+        let from_tcp = async {
+            let chunks = ReaderStream::with_capacity(tcp_upstream, 8192);
+            let mut messages = self
+                .batch
+                .apply(chunks.map(|chunk| chunk.map_err(Into::into)));
+            while let Some(body) = messages.next().await {
+                tx.send(body?).await?;
             }
+            anyhow::Ok(())
+        };
+
+        // Either side closing ends the bridge; `biased` makes the winner
+        // deterministic when both close in the same poll. With the piped stdin/stdout
+        // a plug has under the orchestrator, kble-socket's stdio is cancellable, so
+        // returning here lets the runtime shut down cleanly. Any `Err` goes to the
+        // orchestrator in the Close frame, and is reported by `main`'s `Termination`
+        // impl.
+        tokio::select! {
+            biased;
+            r = to_tcp => r,
+            r = from_tcp => r,
         }
-        anyhow::Ok(())
-    };
-
-    // Either side closing ends the bridge; `biased` makes the winner
-    // deterministic when both close in the same poll. With the piped stdin/stdout
-    // a plug has under the orchestrator, `kble_socket::from_stdio` is cancellable,
-    // so returning here lets the runtime shut down cleanly. Any `Err` is reported
-    // by `main`'s `Termination` impl.
-    tokio::select! {
-        biased;
-        r = to_tcp => r,
-        r = from_tcp => r,
     }
 }
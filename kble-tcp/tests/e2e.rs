@@ -10,7 +10,8 @@
 //! Unlike the framed plugs (eb90, etc.), TCP is a byte stream: `kble-tcp` does
 //! no framing on the WS→TCP side and emits one frame per TCP read (up to 8 KiB)
 //! on the TCP→WS side. So the tests treat the bridge as a byte pipe — reading a
-//! known number of bytes — rather than asserting on frame boundaries.
+//! known number of bytes — rather than asserting on frame boundaries, except
+//! where `--coalesce-bytes`/`--split-bytes` make the boundaries deterministic.
 
 use std::net::SocketAddr;
 use std::time::Duration;
@@ -37,12 +38,19 @@ fn kble_tcp(addr: &SocketAddr) -> Command {
 /// accepted server-side `TcpStream` — the two ends the bridge shuttles bytes
 /// between.
 async fn spawn_bridge() -> (Plug, TcpStream) {
+    spawn_bridge_with(&[]).await
+}
+
+/// [`spawn_bridge`], with extra command line arguments for `kble-tcp`
+async fn spawn_bridge_with(args: &[&str]) -> (Plug, TcpStream) {
     let listener = TcpListener::bind(("127.0.0.1", 0))
         .await
         .expect("bind tcp server");
     let addr = listener.local_addr().expect("read tcp server addr");
 
-    let plug = Plug::spawn(kble_tcp(&addr)).await.expect("spawn kble-tcp");
+    let mut cmd = kble_tcp(&addr);
+    cmd.args(args);
+    let plug = Plug::spawn(cmd).await.expect("spawn kble-tcp");
     // The bound listener queues the connection, so accepting after the spawn is
     // safe; the deadline turns "the bridge never dialed us" into a failure.
     let (tcp, _peer) = tokio::time::timeout(Duration::from_secs(5), listener.accept())
@@ -155,6 +163,50 @@ async fn exits_when_the_tcp_peer_closes() {
         .expect("bridge should have exited cleanly after the tcp peer closed");
 }
 
+/// With `--coalesce-bytes`, the TCP peer's small writes are joined into one
+/// message, which `--split-bytes` then cuts down to size.
+#[tokio::test]
+async fn coalesces_and_splits_tcp_peer_bytes() {
+    let (mut plug, mut tcp) = spawn_bridge_with(&[
+        "--coalesce-bytes",
+        "16",
+        "--coalesce-latency-ms",
+        "60000",
+        "--split-bytes",
+        "6",
+    ])
+    .await;
+
+    for chunk in [b"abcd", b"efgh", b"ijkl", b"mnop"] {
+        tcp.write_all(chunk).await.expect("tcp peer writes bytes");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    for expected in [&b"abcdef"[..], b"ghijkl", b"mnop"] {
+        let frame = plug.recv().await.expect("bridge emits a frame");
+        assert_eq!(frame, expected);
+    }
+
+    plug.shutdown().await.expect("bridge exits cleanly");
+}
+
+/// What was coalesced is sent after `--coalesce-latency-ms`, even if fewer
+/// than `--coalesce-bytes` bytes arrived.
+#[tokio::test]
+async fn sends_a_short_coalesced_message_after_the_latency() {
+    let (mut plug, mut tcp) =
+        spawn_bridge_with(&["--coalesce-bytes", "1024", "--coalesce-latency-ms", "50"]).await;
+
+    tcp.write_all(b"abc").await.expect("tcp peer writes bytes");
+    let frame = plug
+        .recv_timeout(Duration::from_secs(5))
+        .await
+        .expect("bridge emits the short message");
+    assert_eq!(frame, &b"abc"[..]);
+
+    plug.shutdown().await.expect("bridge exits cleanly");
+}
+
 proptest! {
     // Each case spawns a kble-tcp process, so keep the count modest. Integration
     // tests have no crate-root source dir, so disable the on-disk regression file.
@@ -15,21 +15,33 @@
 //! binary itself. The orchestrator connects to `ws://` plugs as a client (a
 //! real HTTP handshake via `connect_async`), so a test that wants to inject and
 //! observe the bytes crossing a link can register a [`WsPlug`] as the link's
-//! endpoint and drive it from the outside.
+//! endpoint and drive it from the outside. [`WsPlug::bind_tls`] serves the
+//! same thing over TLS, standing in for a `wss://` plug.
 
 use std::{io, pin::Pin, process::Stdio, task, time::Duration};
 
 use anyhow::{anyhow, Context, Result};
 use bytes::Bytes;
-use futures_util::{SinkExt, StreamExt};
-use kble_socket::{from_tungstenite, SocketSink, SocketStream};
+use futures_util::{future, SinkExt, Stream, StreamExt, TryStreamExt};
+use kble_socket::{
+    from_tungstenite, Builder, ControlMessage, ControlStream, EnvelopeSink, EnvelopeStream,
+    Enveloped, SocketSink, SocketStream,
+};
 use pin_project_lite::pin_project;
 use tokio::{
     io::{AsyncRead, AsyncWrite},
     net::TcpListener,
     process::{Child, ChildStdin, ChildStdout, Command},
 };
-use tokio_tungstenite::{tungstenite::protocol::Role, WebSocketStream};
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::{
+    tungstenite::{
+        handshake::server::{Request, Response},
+        http::HeaderMap,
+        protocol::Role,
+    },
+    WebSocketStream,
+};
 
 /// Default deadline for [`Plug::recv`] and grace period for [`Plug::shutdown`].
 ///
@@ -74,6 +86,12 @@ impl Plug {
         })
     }
 
+    /// The OS process ID, e.g. to send the process a signal. `None` once it
+    /// has been reaped.
+    pub fn id(&self) -> Option<u32> {
+        self.child.id()
+    }
+
     /// Send one frame to the process's input stream.
     pub async fn send(&mut self, frame: Bytes) -> Result<()> {
         self.sink.send(frame).await
@@ -128,7 +146,10 @@ impl Plug {
 /// Receive the next frame from `stream`, failing — rather than hanging forever
 /// — if nothing arrives within `timeout` or the peer closes the stream without
 /// sending a frame. `peer` names the other end for the error message.
-async fn next_frame(stream: &mut SocketStream, timeout: Duration, peer: &str) -> Result<Bytes> {
+async fn next_frame<S, T>(stream: &mut Pin<Box<S>>, timeout: Duration, peer: &str) -> Result<T>
+where
+    S: Stream<Item = Result<T>> + ?Sized,
+{
     match tokio::time::timeout(timeout, stream.next()).await {
         Ok(Some(frame)) => frame,
         Ok(None) => Err(anyhow!(
@@ -154,11 +175,24 @@ async fn next_frame(stream: &mut SocketStream, timeout: Duration, peer: &str) ->
 pub struct WsPlug {
     url: String,
     listener: TcpListener,
+    tls: Option<TlsAcceptor>,
 }
 
 impl WsPlug {
     /// Bind a server on an ephemeral `127.0.0.1` port.
     pub async fn bind() -> Result<Self> {
+        Self::bind_inner("ws", None).await
+    }
+
+    /// Bind a `wss://` server on an ephemeral `127.0.0.1` port. Every accepted
+    /// connection runs the TLS handshake through `acceptor` first, so the
+    /// acceptor's server config decides the certificate presented and whether
+    /// a client certificate is required.
+    pub async fn bind_tls(acceptor: TlsAcceptor) -> Result<Self> {
+        Self::bind_inner("wss", Some(acceptor)).await
+    }
+
+    async fn bind_inner(scheme: &str, tls: Option<TlsAcceptor>) -> Result<Self> {
         let listener = TcpListener::bind(("127.0.0.1", 0))
             .await
             .context("failed to bind ws plug listener")?;
@@ -167,8 +201,9 @@ impl WsPlug {
             .context("failed to read ws plug local address")?
             .port();
         Ok(Self {
-            url: format!("ws://127.0.0.1:{port}/"),
+            url: format!("{scheme}://127.0.0.1:{port}/"),
             listener,
+            tls,
         })
     }
 
@@ -185,27 +220,65 @@ impl WsPlug {
     /// URL — this fails the test rather than hanging it forever (libtest has no
     /// per-test timeout).
     pub async fn accept(self) -> Result<WsPlugConn> {
+        self.accept_inner(false).await
+    }
+
+    /// [`accept`](Self::accept), asking the orchestrator for envelopes, as a
+    /// plug built on `kble-socket` may. Unlike a plain connection, this one
+    /// reads (and answers Pings) even while the test does not, and takes
+    /// control messages too (see [`WsPlugConn::recv_control`]).
+    pub async fn accept_enveloped(self) -> Result<WsPlugConn> {
+        self.accept_inner(true).await
+    }
+
+    async fn accept_inner(self, enveloped: bool) -> Result<WsPlugConn> {
         let handshake = async {
             let (tcp, _peer) = self
                 .listener
                 .accept()
                 .await
                 .context("ws plug never accepted a connection")?;
-            tokio_tungstenite::accept_async(tcp)
-                .await
-                .context("ws plug handshake failed")
+            let mut headers = HeaderMap::new();
+            let record_headers = |req: &Request, resp: Response| {
+                headers = req.headers().clone();
+                Ok(resp)
+            };
+            let (sink, stream, control) = match &self.tls {
+                Some(acceptor) => {
+                    let tls = acceptor
+                        .accept(tcp)
+                        .await
+                        .context("ws plug TLS handshake failed")?;
+                    let wss = tokio_tungstenite::accept_hdr_async(tls, record_headers)
+                        .await
+                        .context("ws plug handshake failed")?;
+                    split(wss, enveloped)
+                }
+                None => {
+                    let wss = tokio_tungstenite::accept_hdr_async(tcp, record_headers)
+                        .await
+                        .context("ws plug handshake failed")?;
+                    split(wss, enveloped)
+                }
+            };
+            anyhow::Ok((sink, stream, control, headers))
         };
-        let wss = match tokio::time::timeout(DEFAULT_TIMEOUT, handshake).await {
-            Ok(result) => result?,
-            Err(_) => {
-                return Err(anyhow!(
-                    "ws plug: nothing connected within {DEFAULT_TIMEOUT:?} \
+        let (sink, stream, control, headers) =
+            match tokio::time::timeout(DEFAULT_TIMEOUT, handshake).await {
+                Ok(result) => result?,
+                Err(_) => {
+                    return Err(anyhow!(
+                        "ws plug: nothing connected within {DEFAULT_TIMEOUT:?} \
                      (did the orchestrator fail to start?)"
-                ))
-            }
-        };
-        let (sink, stream) = from_tungstenite(wss);
-        Ok(WsPlugConn { sink, stream })
+                    ))
+                }
+            };
+        Ok(WsPlugConn {
+            sink,
+            stream,
+            control,
+            headers,
+        })
     }
 }
 
@@ -213,21 +286,31 @@ impl WsPlug {
 ///
 /// To signal end-of-stream — the orchestrator's cue that a link's source is
 /// done — **drop** the connection. That closes the TCP transport, which the
-/// orchestrator reads as EOF. A graceful WebSocket Close frame is not enough:
-/// the orchestrator reads each plug through the read half of a *split* stream
-/// (see `kble/src/plug.rs`), and that half cannot complete the Close handshake
-/// (replying needs the write half), so it never observes the close.
+/// orchestrator reads as EOF.
 pub struct WsPlugConn {
-    /// Bytes sent here are delivered to the orchestrator as binary frames.
-    pub sink: SocketSink,
-    /// Yields the binary frames the orchestrator forwards to this endpoint.
-    pub stream: SocketStream,
+    /// Messages sent here are delivered to the orchestrator as binary frames.
+    pub sink: EnvelopeSink,
+    /// Yields the messages the orchestrator forwards to this endpoint.
+    pub stream: EnvelopeStream,
+    /// Of an enveloped connection
+    control: Option<ControlStream>,
+    headers: HeaderMap,
 }
 
 impl WsPlugConn {
+    /// The HTTP headers of the orchestrator's handshake request.
+    pub fn handshake_headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
     /// Send one binary frame to the orchestrator.
     pub async fn send(&mut self, frame: Bytes) -> Result<()> {
-        self.sink.send(frame).await
+        self.sink.send(frame.into()).await
+    }
+
+    /// Send one message with its envelope to the orchestrator.
+    pub async fn send_enveloped(&mut self, msg: Enveloped) -> Result<()> {
+        self.sink.send(msg).await
     }
 
     /// Receive the next frame the orchestrator forwards here, using the default
@@ -239,8 +322,50 @@ impl WsPlugConn {
     /// Receive the next frame, failing rather than hanging if none arrives
     /// within `timeout` or the orchestrator closes this link.
     pub async fn recv_timeout(&mut self, timeout: Duration) -> Result<Bytes> {
+        Ok(self.recv_enveloped_timeout(timeout).await?.data)
+    }
+
+    /// Receive the next message with its envelope, using the default deadline.
+    pub async fn recv_enveloped(&mut self) -> Result<Enveloped> {
+        self.recv_enveloped_timeout(DEFAULT_TIMEOUT).await
+    }
+
+    async fn recv_enveloped_timeout(&mut self, timeout: Duration) -> Result<Enveloped> {
         next_frame(&mut self.stream, timeout, "ws plug").await
     }
+
+    /// Receive the next control message the orchestrator sends, using the
+    /// default deadline. Only for a connection from
+    /// [`WsPlug::accept_enveloped`].
+    pub async fn recv_control(&mut self) -> Result<ControlMessage> {
+        let control = self
+            .control
+            .as_mut()
+            .ok_or_else(|| anyhow!("ws plug: no control channel without envelopes"))?;
+        next_frame(control, DEFAULT_TIMEOUT, "ws plug").await
+    }
+}
+
+/// The halves of an accepted connection, carrying envelopes and control
+/// messages if `enveloped`
+fn split<S>(
+    wss: WebSocketStream<S>,
+    enveloped: bool,
+) -> (EnvelopeSink, EnvelopeStream, Option<ControlStream>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    if enveloped {
+        let (sink, stream, _, control) = Builder::new()
+            .envelopes()
+            .build_tungstenite(wss)
+            .split_enveloped();
+        return (sink, stream, Some(control));
+    }
+    let (sink, stream) = from_tungstenite(wss);
+    let sink = sink.with(|msg: Enveloped| future::ok::<_, anyhow::Error>(msg.data));
+    let stream = stream.map_ok(Enveloped::from);
+    (Box::pin(sink), Box::pin(stream), None)
 }
 
 pin_project! {
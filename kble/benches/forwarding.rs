@@ -0,0 +1,92 @@
+//! Throughput of the `kble` orchestrator forwarding a `source -> sink` link
+//! between two in-process `ws://` plugs (`kble_test_support::WsPlug`), as
+//! the E2E tests wire it.
+//!
+//! Each size runs over a plain link and over one that transforms every
+//! message with `strip_prefix` and `truncate`, which take the payload apart
+//! without copying it. Run with `cargo bench -p kble --bench forwarding`.
+
+use std::{
+    path::PathBuf,
+    process::Stdio,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use kble_test_support::WsPlug;
+use tokio::process::Command;
+
+const SIZES: [usize; 4] = [64, 1024, 16 * 1024, 256 * 1024];
+/// Bytes sent per measurement, so every size runs for a comparable time
+const VOLUME: usize = 64 * 1024 * 1024;
+
+#[tokio::main]
+async fn main() {
+    println!("{:>10} {:>16} {:>16}", "size", "plain", "transformed");
+    for size in SIZES {
+        let plain = measure(size, "").await;
+        let transformed = measure(
+            size,
+            &format!("    transforms:\n      - strip_prefix: 2\n      - truncate: {size}\n"),
+        )
+        .await;
+        println!(
+            "{:>10} {:>11.1} MiB/s {:>11.1} MiB/s",
+            size,
+            mib_per_sec(plain),
+            mib_per_sec(transformed),
+        );
+    }
+}
+
+/// The time for `kble` to forward [`VOLUME`] bytes in `size`-byte messages
+/// over a link with the `link_options` (YAML under the link's `to`)
+async fn measure(size: usize, link_options: &str) -> Duration {
+    let source = WsPlug::bind().await.expect("bind source plug");
+    let sink = WsPlug::bind().await.expect("bind sink plug");
+    let yaml = format!(
+        "plugs:\n  source: {}\n  sink: {}\nlinks:\n  source:\n    to: sink\n{link_options}",
+        source.url(),
+        sink.url(),
+    );
+    let config = write_spaghetti(&yaml);
+    let mut kble = Command::new(env!("CARGO_BIN_EXE_kble"))
+        .arg("--spaghetti")
+        .arg(&config)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .expect("spawn kble orchestrator");
+    // Accepted together: kble dials its plugs in no particular order
+    let (source, sink) = tokio::join!(source.accept(), sink.accept());
+    let mut source = source.expect("kble connects to the source plug");
+    let mut sink = sink.expect("kble connects to the sink plug");
+
+    let count = VOLUME / size;
+    let message = Bytes::from(vec![0x5a; size]);
+    let start = Instant::now();
+    let send = tokio::spawn(async move {
+        for _ in 0..count {
+            source.send(message.clone()).await.expect("send");
+        }
+        source
+    });
+    for _ in 0..count {
+        sink.recv().await.expect("receive");
+    }
+    let elapsed = start.elapsed();
+    drop(send.await.expect("sender task"));
+    kble.kill().await.expect("stop kble");
+    elapsed
+}
+
+fn write_spaghetti(yaml: &str) -> PathBuf {
+    let path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("forwarding.yaml");
+    std::fs::write(&path, yaml).expect("write the spaghetti config");
+    path
+}
+
+fn mib_per_sec(elapsed: Duration) -> f64 {
+    VOLUME as f64 / (1024.0 * 1024.0) / elapsed.as_secs_f64()
+}
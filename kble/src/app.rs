@@ -1,86 +1,465 @@
 use crate::{
+    impairment::{DelayQueue, Impairment},
     plug,
-    spaghetti::{Config, Validated},
+    spaghetti::{self, Config, Diff, Plug},
+    throttle::Throttle,
+    transform::{self, Transform},
 };
-use anyhow::{Context, Result};
-use futures::{future, SinkExt, StreamExt};
-use std::collections::HashMap;
-use tokio::sync::broadcast;
-use tracing::{debug, trace, warn};
+use anyhow::{anyhow, Context, Result};
+use bytes::Bytes;
+use futures::{
+    future::{self, BoxFuture},
+    stream::FuturesUnordered,
+    SinkExt, StreamExt,
+};
+use kble_socket::{
+    instrument::{Snapshot, Stats},
+    logging::LogFormat,
+    ControlMessage, Envelope, Enveloped,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    process::ExitStatus,
+    time::{Duration, SystemTime},
+};
+use tokio::{sync::oneshot, time::Instant};
+use tracing::{debug, error, info, info_span, trace, warn, Instrument};
 
 struct Connection {
+    plug: Plug,
     backend: plug::Backend,
+    /// What went to and from the plug
+    stats: Stats,
     stream: Option<plug::PlugStream>,
     sink: Option<plug::PlugSink>,
+    control: plug::PlugControl,
 }
 
-struct Connections<'a> {
+struct Connections {
     // Some: connections not used yet
     // None: connections is used in a link
-    map: HashMap<&'a str, Connection>,
+    map: HashMap<String, Connection>,
     termination_grace_period_secs: u64,
+    /// Passed on to `exec:` plugs
+    log_format: LogFormat,
 }
 
-struct Link<'a> {
-    source_name: &'a str,
-    dest_name: &'a str,
+struct Link {
+    source_name: String,
+    dest_name: String,
     source: plug::PlugStream,
     dest: plug::PlugSink,
+    transforms: Vec<Transform>,
+    throttle: Option<Throttle>,
+    impairment: Option<Impairment>,
+    /// Of the next envelope the link makes for a message without one
+    sequence: u64,
+    messages: u64,
+    bytes: u64,
+    end: LinkEnd,
+}
+
+/// Why a link stopped forwarding
+enum LinkEnd {
+    /// Another link ended first, which shuts every link down
+    Quit,
+    /// A reload removed or rewired the link, or restarted one of its plugs
+    Reloaded,
+    /// The source plug closed its connection
+    SourceClosed,
+    /// The source plug closed its connection reporting a failure
+    SourceFailed(kble_socket::CloseError),
+    /// The source plug stopped answering keepalive Pings
+    SourceTimedOut(kble_socket::KeepaliveTimeout),
+    ReadError(anyhow::Error),
+    WriteError(anyhow::Error),
+}
+
+impl LinkEnd {
+    /// Whether the link was stopped, rather than ending by itself
+    fn is_stopped(&self) -> bool {
+        matches!(self, LinkEnd::Quit | LinkEnd::Reloaded)
+    }
+}
+
+impl fmt::Display for LinkEnd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkEnd::Quit => write!(f, "stopped after another link ended"),
+            LinkEnd::Reloaded => write!(f, "stopped by a reload"),
+            LinkEnd::SourceClosed => write!(f, "source closed"),
+            LinkEnd::SourceFailed(close) => write!(f, "source {close}"),
+            LinkEnd::SourceTimedOut(timeout) => write!(f, "source timed out: {timeout}"),
+            LinkEnd::ReadError(e) => write!(f, "error reading from source: {e}"),
+            LinkEnd::WriteError(e) => write!(f, "error writing to destination: {e}"),
+        }
+    }
+}
+
+/// How a plug ended, at shutdown or when a reload stopped it
+enum PlugEnd {
+    /// A `ws://`/`wss://` plug: there is no process to wait for
+    Disconnected,
+    Exited(ExitStatus),
+    /// Didn't exit within the termination grace period
+    Killed,
+    /// Couldn't be waited for or killed
+    Failed(anyhow::Error),
+}
+
+impl fmt::Display for PlugEnd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlugEnd::Disconnected => write!(f, "disconnected"),
+            PlugEnd::Exited(status) => write!(f, "exited with {status}"),
+            PlugEnd::Killed => write!(f, "killed after the grace period"),
+            PlugEnd::Failed(e) => write!(f, "failed: {e:#}"),
+        }
+    }
+}
+
+/// Failure to connect to a plug, which `main` reports with its own exit code.
+#[derive(Debug)]
+pub struct ConnectError {
+    plug: String,
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Failed to connect to plug `{}`", self.plug)
+    }
+}
+
+/// What happened to every link and plug, printed at shutdown.
+#[derive(Default)]
+pub struct Summary {
+    links: Vec<LinkSummary>,
+    plugs: Vec<PlugSummary>,
+}
+
+/// How a plug ended, and what went to and from it
+struct PlugSummary {
+    name: String,
+    end: PlugEnd,
+    stats: Snapshot,
+}
+
+/// A finished link's counters and end, without its connections
+struct LinkSummary {
+    source_name: String,
+    dest_name: String,
+    messages: u64,
+    bytes: u64,
+    end: LinkEnd,
+}
+
+impl Summary {
+    /// The failures of plugs that closed their connection with an error,
+    /// went silent, exited unsuccessfully on their own, or couldn't be waited
+    /// for, if any.
+    pub fn plug_failure(&self) -> Option<anyhow::Error> {
+        let links = self.links.iter().filter_map(|link| match &link.end {
+            LinkEnd::SourceFailed(close) => Some(format!("Plug {} {close}", link.source_name)),
+            LinkEnd::SourceTimedOut(timeout) => {
+                Some(format!("Plug {} timed out: {timeout}", link.source_name))
+            }
+            _ => None,
+        });
+        let plugs = self.plugs.iter().filter_map(|plug| match &plug.end {
+            PlugEnd::Exited(status) if !status.success() => {
+                Some(format!("Plug {} exited with {status}", plug.name))
+            }
+            PlugEnd::Failed(e) => Some(format!("Plug {} failed: {e:#}", plug.name)),
+            _ => None,
+        });
+        let failures = links.chain(plugs).collect::<Vec<_>>();
+        if failures.is_empty() {
+            return None;
+        }
+        Some(anyhow!(failures.join("; ")))
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Links:")?;
+        for link in &self.links {
+            writeln!(
+                f,
+                "  {} -> {}: {} messages, {} bytes, {}",
+                link.source_name, link.dest_name, link.messages, link.bytes, link.end
+            )?;
+        }
+        writeln!(f, "Plugs:")?;
+        for PlugSummary { name, end, stats } in &self.plugs {
+            writeln!(
+                f,
+                "  {name}: {end}; {} messages ({} bytes) from it, {} messages ({} bytes) to it in {:?}",
+                stats.received_messages,
+                stats.received_bytes,
+                stats.sent_messages,
+                stats.sent_bytes,
+                stats.busy_time()
+            )?;
+        }
+        Ok(())
+    }
 }
 
-pub async fn run(config: &Config, termination_grace_period_secs: u64) -> Result<()> {
-    let mut conns = connect_to_plugs(config, termination_grace_period_secs).await?;
-    let links = connect_links(&mut conns, config);
+/// Run the plugs and links of `config` until a link ends by itself. On SIGHUP,
+/// `reload` is called for a new configuration to switch to. One that fails to
+/// load is logged, and whatever is running goes on.
+pub async fn run(
+    config: &Config,
+    termination_grace_period_secs: u64,
+    log_format: LogFormat,
+    reload: impl Fn() -> Result<Config>,
+) -> Result<Summary> {
+    let mut hangup = Hangup::new()?;
+    let mut harness = Harness::start(config, termination_grace_period_secs, log_format).await?;
+    while !harness.link_ended {
+        tokio::select! {
+            Some(link) = harness.forwarding.next() => harness.finish_link(link),
+            () = hangup.recv() => match reload() {
+                Ok(config) => harness.reload(&config).await,
+                Err(e) => error!("Not reloading: {e:#}"),
+            },
+        }
+    }
+    Ok(harness.shutdown().await)
+}
+
+/// The connected plugs and the links running between them
+struct Harness {
+    conns: Connections,
+    /// Running links by source
+    links: HashMap<String, RunningLink>,
+    forwarding: FuturesUnordered<BoxFuture<'static, Link>>,
+    /// Set once a link has ended by itself, which shuts every link down
+    link_ended: bool,
+    summary: Summary,
+}
+
+struct RunningLink {
+    link: spaghetti::Link,
+    stop: oneshot::Sender<LinkEnd>,
+}
+
+impl Harness {
+    async fn start(
+        config: &Config,
+        termination_grace_period_secs: u64,
+        log_format: LogFormat,
+    ) -> Result<Self> {
+        let mut harness = Harness {
+            conns: Connections::new(termination_grace_period_secs, log_format),
+            links: HashMap::new(),
+            forwarding: FuturesUnordered::new(),
+            link_ended: false,
+            summary: Summary::default(),
+        };
+        for (name, plug) in config.plugs().iter() {
+            if let Err(e) = harness.conns.connect(name, plug).await {
+                warn!("Error connecting to {name}: {e}");
+                // Failures closing the others are logged: report this one
+                harness.conns.close_and_wait(config.plugs().keys()).await;
+                return Err(e);
+            }
+        }
+        for (source_name, link) in config.links().iter() {
+            harness.start_link(source_name, link);
+        }
+        Ok(harness)
+    }
+
+    fn start_link(&mut self, source_name: &str, link: &spaghetti::Link) {
+        let dest_name = link.to.as_str();
+        // Only after a reload failed to connect one of them
+        if !self.conns.map.contains_key(source_name) || !self.conns.map.contains_key(dest_name) {
+            warn!("Not linking {source_name} to {dest_name}: plug not connected");
+            return;
+        }
+        // Those panics shouldn't happen if config is valid and no link is running from
+        // the source or to the dest
+        let source = self.conns.take_stream(source_name).unwrap_or_else(|| {
+            panic!("stream not found: {source_name}");
+        });
+        let dest = self.conns.take_sink(dest_name).unwrap_or_else(|| {
+            panic!("sink not found: {dest_name}");
+        });
+
+        let (stop_tx, stop_rx) = oneshot::channel();
+        let forwarding = Link {
+            source_name: source_name.to_string(),
+            dest_name: dest_name.to_string(),
+            source,
+            dest,
+            transforms: link.transforms.clone(),
+            throttle: link.throttle.as_ref().map(Throttle::new),
+            impairment: link
+                .impairment
+                .as_ref()
+                .map(|options| Impairment::new(options, &format!("{source_name} -> {dest_name}"))),
+            sequence: 0,
+            messages: 0,
+            bytes: 0,
+            end: LinkEnd::Quit,
+        };
+        let span = info_span!("link", link = %format!("{source_name} -> {dest_name}"));
+        self.forwarding
+            .push(Box::pin(forwarding.forward(stop_rx).instrument(span)));
+        self.links.insert(
+            source_name.to_string(),
+            RunningLink {
+                link: link.clone(),
+                stop: stop_tx,
+            },
+        );
+    }
+
+    fn finish_link(&mut self, link: Link) {
+        if !link.end.is_stopped() {
+            self.links.remove(&link.source_name);
+            self.link_ended = true;
+        }
+        let summary = self.conns.return_link(link);
+        self.summary.links.push(summary);
+    }
+
+    /// Stop the links from `sources` and wait until their connections are back.
+    async fn stop_links(
+        &mut self,
+        sources: impl IntoIterator<Item = String>,
+        end: impl Fn() -> LinkEnd,
+    ) {
+        let mut stopping = HashSet::new();
+        for source in sources {
+            if let Some(link) = self.links.remove(&source) {
+                // Fails if the link has just ended by itself, which is returned all the same
+                let _ = link.stop.send(end());
+                stopping.insert(source);
+            }
+        }
+        while !stopping.is_empty() {
+            let link = self
+                .forwarding
+                .next()
+                .await
+                .expect("stopping links are still forwarding");
+            stopping.remove(&link.source_name);
+            self.finish_link(link);
+        }
+    }
 
-    let (quit_tx, _) = broadcast::channel(1);
-    let link_futs = links.map(|link| {
-        let quit_rx = quit_tx.subscribe();
-        let fut = link.forward(quit_rx);
-        Box::pin(fut)
-    });
+    /// Switch to `config`, leaving the plugs and links it doesn't change
+    /// running. A plug that fails to connect is skipped along with its links,
+    /// and one that fails to stop is reported in the summary.
+    async fn reload(&mut self, config: &Config) {
+        let plugs = self
+            .conns
+            .map
+            .iter()
+            .map(|(name, conn)| (name.clone(), conn.plug.clone()))
+            .collect();
+        let links = self
+            .links
+            .iter()
+            .map(|(source_name, running)| (source_name.clone(), running.link.clone()))
+            .collect();
+        let diff = Diff::new(&plugs, &links, config);
+        if diff.is_empty() {
+            info!("Reloaded: nothing changed");
+            return;
+        }
+        info!(
+            "Reloading: stopping plugs {:?} and links {:?}, starting plugs {:?} and links {:?}",
+            diff.stop_plugs, diff.stop_links, diff.start_plugs, diff.start_links
+        );
 
-    let (terminated_link, _, link_futs) = futures::future::select_all(link_futs).await;
-    // Ignore the no-receiver error: if every other link has already finished,
-    // there is nobody left to signal and nothing left to quit.
-    let _ = quit_tx.send(());
-    let links = future::join_all(link_futs).await;
-    let links = links.into_iter().chain(std::iter::once(terminated_link));
+        self.stop_links(diff.stop_links.into_keys(), || LinkEnd::Reloaded)
+            .await;
+        let stopped = self.conns.close_and_wait(&diff.stop_plugs).await;
+        self.summary.plugs.extend(stopped);
+        for name in &diff.start_plugs {
+            if let Err(e) = self.conns.connect(name, &config.plugs()[name]).await {
+                error!("{e:#}");
+            }
+        }
+        for source_name in diff.start_links.keys() {
+            self.start_link(source_name, &config.links()[source_name]);
+        }
+    }
 
-    for link in links {
-        conns.return_link(link);
+    async fn shutdown(mut self) -> Summary {
+        let sources = self.links.keys().cloned().collect::<Vec<_>>();
+        self.stop_links(sources, || LinkEnd::Quit).await;
+        let names = self.conns.map.keys().cloned().collect::<Vec<_>>();
+        let plugs = self.conns.close_and_wait(&names).await;
+        self.summary.plugs.extend(plugs);
+
+        self.summary
+            .links
+            .sort_by(|a, b| (&a.source_name, &a.dest_name).cmp(&(&b.source_name, &b.dest_name)));
+        self.summary.plugs.sort_by(|a, b| a.name.cmp(&b.name));
+        self.summary
     }
-    conns.close_and_wait().await?;
+}
 
-    Ok(())
+impl Connection {
+    /// Send `msgs` to the plug over its control channel
+    async fn send_control(&mut self, msgs: Vec<ControlMessage>) -> Result<()> {
+        for msg in msgs {
+            debug!(control = %msg, "Sending control message");
+            self.control
+                .send(msg)
+                .await
+                .context("Failed to send a control message")?;
+        }
+        Ok(())
+    }
 }
 
-impl<'a> Connections<'a> {
-    fn new(termination_grace_period_secs: u64) -> Self {
+impl Connections {
+    fn new(termination_grace_period_secs: u64, log_format: LogFormat) -> Self {
         Self {
             map: HashMap::new(),
             termination_grace_period_secs,
+            log_format,
         }
     }
 
-    fn insert(
-        &mut self,
-        name: &'a str,
-        backend: plug::Backend,
-        stream: plug::PlugStream,
-        sink: plug::PlugSink,
-    ) {
-        self.map.insert(
-            name,
-            Connection {
+    async fn connect(&mut self, name: &str, plug: &Plug) -> Result<()> {
+        let connect = async {
+            debug!("Connecting to {name}");
+            let (backend, sink, stream, control) =
+                plug::connect(name, plug, self.log_format).await?;
+            debug!("Connected to {name}");
+            let stats = Stats::new().with_spans(name);
+            let mut conn = Connection {
+                plug: plug.clone(),
                 backend,
-                stream: Some(stream),
-                sink: Some(sink),
-            },
-        );
+                stream: Some(Box::pin(stats.stream(stream))),
+                sink: Some(Box::pin(stats.sink(sink))),
+                stats,
+                control,
+            };
+            conn.send_control(plug.control_messages()?).await?;
+            anyhow::Ok(conn)
+        };
+        let conn = connect
+            .instrument(info_span!("plug", plug = name))
+            .await
+            .with_context(|| ConnectError {
+                plug: name.to_string(),
+            })?;
+        self.map.insert(name.to_string(), conn);
+        Ok(())
     }
 
-    fn return_link(&mut self, link: Link<'a>) {
-        let conn = self.map.get_mut(link.source_name).unwrap_or_else(|| {
+    // hand the link's connections back, keeping what it did
+    fn return_link(&mut self, link: Link) -> LinkSummary {
+        let conn = self.map.get_mut(&link.source_name).unwrap_or_else(|| {
             panic!(
                 "tried to return a invalid link with source name {}",
                 link.source_name,
@@ -88,48 +467,90 @@ impl<'a> Connections<'a> {
         });
         conn.stream = Some(link.source);
 
-        let conn = self.map.get_mut(link.dest_name).unwrap_or_else(|| {
+        let conn = self.map.get_mut(&link.dest_name).unwrap_or_else(|| {
             panic!(
                 "tried to return a invalid link with dest name {}",
                 link.dest_name,
             )
         });
         conn.sink = Some(link.dest);
+
+        LinkSummary {
+            source_name: link.source_name,
+            dest_name: link.dest_name,
+            messages: link.messages,
+            bytes: link.bytes,
+            end: link.end,
+        }
     }
 
-    // close all connections
-    // assume all links are returned
-    async fn close_and_wait(self) -> Result<()> {
-        let futs = self.map.into_iter().map(|(name, mut conn)| async move {
+    // close the named connections and report how each plug ended, failures
+    // included, so that every one is closed
+    // assume their links are returned
+    async fn close_and_wait<'a>(
+        &mut self,
+        names: impl IntoIterator<Item = &'a String>,
+    ) -> Vec<PlugSummary> {
+        let grace_period = Duration::from_secs(self.termination_grace_period_secs);
+        let conns = names
+            .into_iter()
+            .filter_map(|name| Some((name.clone(), self.map.remove(name)?)));
+        let futs = conns.map(|(name, conn)| async move {
+            let Connection {
+                mut backend,
+                stats,
+                stream,
+                sink,
+                ..
+            } = conn;
             let fut = async {
-                if let Some(mut s) = conn.sink {
+                // Hold the stream until the plug exits: dropping it along with
+                // the sink would hang up on the plug before it could answer
+                // the closing handshake.
+                let _stream = stream;
+                if let Some(mut s) = sink {
                     debug!("Closing {name}");
-                    s.close().await?;
-                    debug!("Closed {name}");
+                    match s.close().await {
+                        Ok(()) => debug!("Closed {name}"),
+                        Err(e) => warn!("Error closing {name}: {e}"),
+                    }
                 }
                 debug!("Waiting for plug {name} to exit");
-                conn.backend.wait().await?;
+                let status = backend.wait().await?;
                 debug!("Plug {name} exited");
-                anyhow::Ok(())
+                anyhow::Ok(status)
             };
-            let close_result = tokio::time::timeout(
-                std::time::Duration::from_secs(self.termination_grace_period_secs),
-                fut,
-            )
-            .await;
+            let span = info_span!("plug", plug = %name);
+            let close_result = tokio::time::timeout(grace_period, fut)
+                .instrument(span)
+                .await;
 
-            match close_result {
-                Ok(result) => result,
+            let end = match close_result {
+                Ok(Ok(Some(status))) => PlugEnd::Exited(status),
+                Ok(Ok(None)) => PlugEnd::Disconnected,
+                Ok(Err(e)) => {
+                    error!("Error waiting for plug {name}: {e:#}");
+                    PlugEnd::Failed(e)
+                }
                 Err(_) => {
                     // abandon the connection
                     warn!("Plug {name} didn't exit in time");
-                    conn.backend.kill().await?;
-                    Ok(())
+                    match backend.kill().await {
+                        Ok(()) => PlugEnd::Killed,
+                        Err(e) => {
+                            error!("Error killing plug {name}: {e:#}");
+                            PlugEnd::Failed(e)
+                        }
+                    }
                 }
+            };
+            PlugSummary {
+                name,
+                end,
+                stats: stats.snapshot(),
             }
         });
-        future::try_join_all(futs).await?;
-        Ok(())
+        future::join_all(futs).await
     }
 
     fn take_stream(&mut self, name: &str) -> Option<plug::PlugStream> {
@@ -141,86 +562,164 @@ impl<'a> Connections<'a> {
     }
 }
 
-async fn connect_to_plugs(
-    config: &Config,
-    termination_grace_period_secs: u64,
-) -> Result<Connections> {
-    let mut conns = Connections::new(termination_grace_period_secs);
-    for (name, url) in config.plugs().iter() {
-        debug!("Connecting to {name}");
-        let connect_result = plug::connect(url).await.with_context(move || {
-            format! {
-                "Failed to connect to plug `{name}`"
-            }
-        });
-
-        let (backend, sink, stream) = match connect_result {
-            Ok(p) => p,
-            Err(e) => {
-                warn!("Error connecting to {name}: {e}");
-                conns.close_and_wait().await?;
-                return Err(e);
-            }
-        };
-        debug!("Connected to {name}");
-        conns.insert(name.as_str(), backend, stream, sink);
-    }
-    Ok(conns)
+/// SIGHUP, the cue to reload the spaghetti file. Never arrives off Unix.
+struct Hangup {
+    #[cfg(unix)]
+    signal: tokio::signal::unix::Signal,
 }
 
-fn connect_links<'a, 'conns>(
-    conns: &'conns mut Connections<'a>,
-    config: &'a Config<Validated>, // emphasize that the config is validated
-) -> impl Iterator<Item = Link<'a>> + 'conns {
-    config.links().iter().map(|(source_name, dest_name)| {
-        // Those panics shouldn't happen if config is valid and conns is properly initialized
-        let source = conns.take_stream(source_name).unwrap_or_else(|| {
-            panic!("stream not found: {source_name}");
-        });
-        let dest = conns.take_sink(dest_name).unwrap_or_else(|| {
-            panic!("sink not found: {dest_name}");
-        });
+impl Hangup {
+    fn new() -> Result<Self> {
+        Ok(Self {
+            #[cfg(unix)]
+            signal: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+                .context("Failed to listen for SIGHUP")?,
+        })
+    }
 
-        Link {
-            source_name,
-            dest_name,
-            source,
-            dest,
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        if self.signal.recv().await.is_some() {
+            return;
         }
-    })
+        future::pending().await
+    }
 }
 
-impl<'a> Link<'a> {
-    async fn forward(mut self, mut quit_rx: broadcast::Receiver<()>) -> Self {
+impl Link {
+    async fn forward(mut self, mut stop_rx: oneshot::Receiver<LinkEnd>) -> Self {
+        let link_name = format!("{} -> {}", self.source_name, self.dest_name);
+        // Messages held back by the throttle or the impairment
+        let mut delayed = DelayQueue::default();
+        let mut source_closed = false;
         loop {
+            // Deliver what is still delayed before reporting the source closed
+            if source_closed && delayed.is_empty() {
+                self.end = LinkEnd::SourceClosed;
+                break;
+            }
+            // The next message is read once the throttled link is free
+            let now = Instant::now();
+            let busy_until = self.throttle.as_ref().and_then(|t| t.busy_until(now));
             let recv_result = tokio::select! {
-                _ = quit_rx.recv() => break,
-                recv_result = self.source.next() => match recv_result {
+                end = &mut stop_rx => {
+                    self.end = end.unwrap_or(LinkEnd::Quit);
+                    break;
+                }
+                (envelope, data) = delayed.next() => {
+                    if let Err(end) = self.send(envelope, data).await {
+                        self.end = end;
+                        break;
+                    }
+                    continue;
+                }
+                () = tokio::time::sleep_until(busy_until.unwrap_or(now)), if busy_until.is_some() => {
+                    continue;
+                }
+                recv_result = self.source.next(), if !source_closed && busy_until.is_none() => match recv_result {
                     Some(data) => data,
-                    None => break,
+                    None => {
+                        source_closed = true;
+                        continue;
+                    }
                 },
             };
 
-            let data = match recv_result {
+            let msg = match recv_result {
                 Err(e) => {
-                    warn!("Error reading from {}: {}", self.source_name, e);
+                    self.end = match e.downcast::<kble_socket::CloseError>() {
+                        Ok(close) => {
+                            error!("Plug {} {close}", self.source_name);
+                            LinkEnd::SourceFailed(close)
+                        }
+                        Err(e) => match e.downcast::<kble_socket::KeepaliveTimeout>() {
+                            Ok(timeout) => {
+                                error!("Plug {} timed out: {timeout}", self.source_name);
+                                LinkEnd::SourceTimedOut(timeout)
+                            }
+                            Err(e) => {
+                                warn!("Error reading from {}: {}", self.source_name, e);
+                                LinkEnd::ReadError(e)
+                            }
+                        },
+                    };
                     break;
                 }
-                Ok(data) => data,
+                Ok(msg) => msg,
+            };
+            let received_at = SystemTime::now();
+            let Some(data) = transform::apply_all(&self.transforms, &link_name, msg.data) else {
+                trace!("{link_name}: dropped a message");
+                continue;
             };
 
-            let data_len = data.len();
-            if let Err(e) = self.dest.send(data).await {
-                warn!("Error writing to {}: {}", self.dest_name, e);
-                break;
+            if self.throttle.is_none() && self.impairment.is_none() {
+                let envelope = self.envelope(msg.envelope, received_at);
+                if let Err(end) = self.send(envelope, data).await {
+                    self.end = end;
+                    break;
+                }
+                continue;
+            }
+            let now = Instant::now();
+            let paced = match &mut self.throttle {
+                Some(throttle) => throttle.pace(data, now),
+                None => vec![(now, data)],
+            };
+            for (at, data) in paced {
+                let envelope = self.envelope(msg.envelope.clone(), received_at);
+                match &mut self.impairment {
+                    Some(impairment) => {
+                        for (at, data) in impairment.schedule(data, at) {
+                            delayed.push(at, (envelope.clone(), data));
+                        }
+                    }
+                    None => delayed.push(at, (envelope, data)),
+                }
             }
-            trace!(
-                "{} -> {}: {} bytes",
-                self.source_name,
-                self.dest_name,
-                data_len
-            );
         }
         self
     }
+
+    /// The envelope of a message (or throttle chunk) to deliver: its own, with
+    /// the source's name as the origin if it has none, or a new one from the
+    /// link with the next sequence number. It is made after the transforms, so
+    /// the messages they drop leave no gap in the sequence. An impairment
+    /// duplicate deliberately repeats the sequence number of its original.
+    fn envelope(&mut self, envelope: Option<Envelope>, received_at: SystemTime) -> Envelope {
+        let mut envelope = envelope.unwrap_or_else(|| {
+            let sequence = self.sequence;
+            self.sequence += 1;
+            Envelope {
+                received_at,
+                sequence,
+                origin: None,
+            }
+        });
+        envelope
+            .origin
+            .get_or_insert_with(|| self.source_name.clone());
+        envelope
+    }
+
+    async fn send(&mut self, envelope: Envelope, data: Bytes) -> Result<(), LinkEnd> {
+        let data_len = data.len();
+        let msg = Enveloped {
+            envelope: Some(envelope),
+            data,
+        };
+        if let Err(e) = self.dest.send(msg).await {
+            warn!("Error writing to {}: {}", self.dest_name, e);
+            return Err(LinkEnd::WriteError(e));
+        }
+        self.messages += 1;
+        self.bytes += data_len as u64;
+        trace!(
+            "{} -> {}: {} bytes",
+            self.source_name,
+            self.dest_name,
+            data_len
+        );
+        Ok(())
+    }
 }
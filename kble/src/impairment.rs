@@ -0,0 +1,359 @@
+use anyhow::{anyhow, Result};
+use bytes::{Bytes, BytesMut};
+use std::{collections::BTreeMap, time::Duration};
+
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use rand_distr::{Distribution, Normal};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+use tracing::info;
+
+/// The longest delay, and the most `latency_ms` and a jitter's `ms` may be:
+/// an hour, well within what an `Instant` can be pushed back by
+const MAX_DELAY_MS: u64 = 60 * 60 * 1000;
+
+/// Emulation of an unreliable link, e.g. a lossy RF link, for testing FDIR
+/// and retransmission logic. Probabilities are per message, except
+/// `bit_error_rate` which is per bit.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ImpairmentOptions {
+    /// Seed of the random number generator, for reproducible runs. Without
+    /// it, a random seed is picked and logged.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    /// Delay added to every message, at most an hour
+    #[serde(default)]
+    pub latency_ms: u64,
+    /// Random variation of the delay, at most an hour. The delay never goes
+    /// below zero or above an hour, and messages still arrive in order unless
+    /// `reorder_probability` says so.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jitter: Option<Jitter>,
+    #[serde(default)]
+    pub drop_probability: f64,
+    #[serde(default)]
+    pub bit_error_rate: f64,
+    #[serde(default)]
+    pub duplicate_probability: f64,
+    /// Probability that a message is sent without delay, overtaking the
+    /// delayed messages before it
+    #[serde(default)]
+    pub reorder_probability: f64,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq)]
+#[serde(tag = "distribution", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Jitter {
+    /// Uniform between -`ms` and +`ms`
+    Uniform { ms: u64 },
+    /// Normal with a standard deviation of `ms`
+    Normal { ms: u64 },
+}
+
+impl ImpairmentOptions {
+    pub fn validate(&self) -> Result<()> {
+        let probabilities = [
+            ("drop_probability", self.drop_probability),
+            ("bit_error_rate", self.bit_error_rate),
+            ("duplicate_probability", self.duplicate_probability),
+            ("reorder_probability", self.reorder_probability),
+        ];
+        for (name, p) in probabilities {
+            if !(0.0..=1.0).contains(&p) {
+                return Err(anyhow!("{name} must be between 0 and 1"));
+            }
+        }
+        if self.latency_ms > MAX_DELAY_MS {
+            return Err(anyhow!("latency_ms must be at most {MAX_DELAY_MS}"));
+        }
+        if let Some(Jitter::Uniform { ms } | Jitter::Normal { ms }) = self.jitter {
+            if ms > MAX_DELAY_MS {
+                return Err(anyhow!("jitter ms must be at most {MAX_DELAY_MS}"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The impairments of a running link
+pub struct Impairment {
+    options: ImpairmentOptions,
+    rng: ChaCha8Rng,
+    /// When the last message not reordered is delivered, which later ones may
+    /// not overtake
+    last_delivery: Option<Instant>,
+}
+
+impl Impairment {
+    pub fn new(options: &ImpairmentOptions, link: &str) -> Self {
+        let seed = options.seed.unwrap_or_else(|| {
+            let seed = rand::random();
+            info!("{link}: impairment seed {seed}");
+            seed
+        });
+        Impairment {
+            options: options.clone(),
+            rng: ChaCha8Rng::seed_from_u64(seed),
+            last_delivery: None,
+        }
+    }
+
+    /// When to deliver the copies of `data` received at `now`: none if it is
+    /// dropped, two if it is duplicated.
+    pub fn schedule(&mut self, data: Bytes, now: Instant) -> Vec<(Instant, Bytes)> {
+        if self.rng.gen_bool(self.options.drop_probability) {
+            return Vec::new();
+        }
+        let data = self.corrupt(data);
+        let at = if self.rng.gen_bool(self.options.reorder_probability) {
+            now
+        } else {
+            let at = (now + self.delay()).max(self.last_delivery.unwrap_or(now));
+            self.last_delivery = Some(at);
+            at
+        };
+        if self.rng.gen_bool(self.options.duplicate_probability) {
+            vec![(at, data.clone()), (at, data)]
+        } else {
+            vec![(at, data)]
+        }
+    }
+
+    fn delay(&mut self) -> Duration {
+        let jitter_ms = match self.options.jitter {
+            None => 0.0,
+            Some(Jitter::Uniform { ms }) => {
+                let ms = ms as f64;
+                self.rng.gen_range(-ms..=ms)
+            }
+            Some(Jitter::Normal { ms }) => Normal::new(0.0, ms as f64)
+                .expect("a standard deviation in u64 is finite")
+                .sample(&mut self.rng),
+        };
+        let ms = (self.options.latency_ms as f64 + jitter_ms).clamp(0.0, MAX_DELAY_MS as f64);
+        Duration::from_secs_f64(ms / 1000.0)
+    }
+
+    /// Flip each bit with probability `bit_error_rate`. Rather than drawing
+    /// for every bit, the gap to the next error is drawn from the geometric
+    /// distribution. `data` is only copied if a bit of it flips.
+    fn corrupt(&mut self, data: Bytes) -> Bytes {
+        let ber = self.options.bit_error_rate;
+        if ber <= 0.0 {
+            return data;
+        }
+        let bits = data.len() * 8;
+        let mut corrupted: Option<BytesMut> = None;
+        let mut bit: usize = 0;
+        loop {
+            let gap = if ber >= 1.0 {
+                0
+            } else {
+                let u: f64 = self.rng.gen();
+                ((1.0 - u).ln() / (1.0 - ber).ln()) as usize
+            };
+            bit = bit.saturating_add(gap);
+            if bit >= bits {
+                break;
+            }
+            let corrupted = corrupted.get_or_insert_with(|| BytesMut::from(&data[..]));
+            corrupted[bit / 8] ^= 0x80 >> (bit % 8);
+            bit += 1;
+        }
+        corrupted.map_or(data, BytesMut::freeze)
+    }
+}
+
+/// Messages waiting for their delivery time. Messages due at the same time
+/// leave in the order they were pushed.
+pub struct DelayQueue<T> {
+    queue: BTreeMap<(Instant, u64), T>,
+    pushed: u64,
+}
+
+impl<T> Default for DelayQueue<T> {
+    fn default() -> Self {
+        DelayQueue {
+            queue: BTreeMap::new(),
+            pushed: 0,
+        }
+    }
+}
+
+impl<T> DelayQueue<T> {
+    pub fn push(&mut self, at: Instant, data: T) {
+        self.queue.insert((at, self.pushed), data);
+        self.pushed += 1;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Wait for the next message to be due, and take it. Never resolves while
+    /// the queue is empty.
+    pub async fn next(&mut self) -> T {
+        let Some(&(at, _)) = self.queue.keys().next() else {
+            return std::future::pending().await;
+        };
+        tokio::time::sleep_until(at).await;
+        self.queue.pop_first().expect("only next takes messages").1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn impairment(options: ImpairmentOptions) -> Impairment {
+        Impairment::new(
+            &ImpairmentOptions {
+                seed: Some(42),
+                ..options
+            },
+            "test",
+        )
+    }
+
+    #[test]
+    fn test_seed_reproduces() {
+        let options = ImpairmentOptions {
+            latency_ms: 100,
+            jitter: Some(Jitter::Normal { ms: 30 }),
+            drop_probability: 0.2,
+            bit_error_rate: 0.01,
+            duplicate_probability: 0.2,
+            reorder_probability: 0.2,
+            ..Default::default()
+        };
+        let now = Instant::now();
+        let run = || {
+            let mut impairment = impairment(options.clone());
+            (0..100u8)
+                .flat_map(|i| impairment.schedule(Bytes::from(vec![i; 16]), now))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(run(), run());
+    }
+
+    #[test]
+    fn test_drop_and_duplicate() {
+        let now = Instant::now();
+        let mut dropping = impairment(ImpairmentOptions {
+            drop_probability: 1.0,
+            ..Default::default()
+        });
+        assert!(dropping
+            .schedule(Bytes::from_static(b"abc"), now)
+            .is_empty());
+        let mut duplicating = impairment(ImpairmentOptions {
+            duplicate_probability: 1.0,
+            ..Default::default()
+        });
+        assert_eq!(
+            duplicating.schedule(Bytes::from_static(b"abc"), now),
+            [
+                (now, Bytes::from_static(b"abc")),
+                (now, Bytes::from_static(b"abc"))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_jitter_keeps_order() {
+        let mut impairment = impairment(ImpairmentOptions {
+            latency_ms: 50,
+            jitter: Some(Jitter::Uniform { ms: 50 }),
+            ..Default::default()
+        });
+        let start = Instant::now();
+        let times = (0..100)
+            .map(|i| {
+                let now = start + Duration::from_millis(i);
+                let [(at, _)] = impairment.schedule(Bytes::new(), now)[..] else {
+                    panic!("one copy");
+                };
+                assert!(at >= now && at <= now + Duration::from_millis(100));
+                at
+            })
+            .collect::<Vec<_>>();
+        assert!(times.windows(2).all(|w| w[0] <= w[1]));
+    }
+
+    #[test]
+    fn test_reorder_overtakes() {
+        let now = Instant::now();
+        let mut impairment = impairment(ImpairmentOptions {
+            latency_ms: 100,
+            reorder_probability: 1.0,
+            ..Default::default()
+        });
+        assert_eq!(
+            impairment.schedule(Bytes::from_static(&[1]), now),
+            [(now, Bytes::from_static(&[1]))]
+        );
+    }
+
+    #[test]
+    fn test_bit_errors() {
+        let now = Instant::now();
+        let mut flipping = impairment(ImpairmentOptions {
+            bit_error_rate: 1.0,
+            ..Default::default()
+        });
+        assert_eq!(
+            flipping.schedule(Bytes::from_static(&[0x0f, 0x00]), now),
+            [(now, Bytes::from_static(&[0xf0, 0xff]))]
+        );
+
+        let mut noisy = impairment(ImpairmentOptions {
+            bit_error_rate: 0.01,
+            ..Default::default()
+        });
+        let flipped: u32 = (0..100)
+            .flat_map(|_| noisy.schedule(Bytes::from(vec![0; 125]), now))
+            .map(|(_, data)| data.iter().map(|byte| byte.count_ones()).sum::<u32>())
+            .sum();
+        // 100 000 bits at 1%
+        assert!((800..1200).contains(&flipped), "{flipped} bits flipped");
+    }
+
+    #[test]
+    fn test_validate() {
+        let options = ImpairmentOptions {
+            drop_probability: 1.5,
+            ..Default::default()
+        };
+        assert!(options.validate().is_err());
+        let options = ImpairmentOptions {
+            latency_ms: u64::MAX,
+            ..Default::default()
+        };
+        assert!(options.validate().is_err());
+        let options = ImpairmentOptions {
+            jitter: Some(Jitter::Normal { ms: u64::MAX }),
+            ..Default::default()
+        };
+        assert!(options.validate().is_err());
+        assert!(ImpairmentOptions::default().validate().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_delay_queue() {
+        let start = Instant::now();
+        let mut queue = DelayQueue::default();
+        queue.push(start + Duration::from_millis(20), b"late".to_vec());
+        queue.push(start + Duration::from_millis(10), b"first".to_vec());
+        queue.push(start + Duration::from_millis(10), b"second".to_vec());
+        assert_eq!(queue.next().await, b"first");
+        assert!(Instant::now() >= start + Duration::from_millis(10));
+        assert_eq!(queue.next().await, b"second");
+        assert_eq!(queue.next().await, b"late");
+        assert!(queue.is_empty());
+        let pending = tokio::time::timeout(Duration::from_millis(50), queue.next()).await;
+        assert!(pending.is_err());
+    }
+}
@@ -1,21 +1,61 @@
-use std::path::PathBuf;
+use std::{
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
 
 use anyhow::{Context, Result};
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use kble_socket::logging::LogArgs;
 use notalawyer_clap::*;
-use tracing_subscriber::{prelude::*, EnvFilter};
 
 mod app;
+mod impairment;
 mod plug;
 mod spaghetti;
+mod throttle;
+mod tls;
+mod transform;
 
-use spaghetti::{Config, Raw};
+use spaghetti::{Config, Format, Raw};
+
+/// Exit code for an invalid spaghetti configuration (clap uses it for
+/// invalid arguments too)
+const EXIT_CONFIG_ERROR: u8 = 2;
+/// Exit code for failing to connect to a plug
+const EXIT_CONNECT_FAILURE: u8 = 3;
+/// Exit code for a plug that failed while the links ran
+const EXIT_PLUG_FAILURE: u8 = 4;
+
+const EXIT_STATUS_HELP: &str = "\
+Exit status:
+  0  All links ended and no plug failed
+  1  An unexpected error
+  2  Invalid arguments or spaghetti configuration
+  3  Failed to connect to a plug
+  4  A plug failed: it closed its connection with an error, stopped answering
+     keepalive Pings, or exited with a non-zero status";
 
 #[derive(Parser, Debug)]
-#[clap(author, version, about, long_about = None)]
+#[clap(
+    author,
+    version,
+    about,
+    long_about = None,
+    after_help = EXIT_STATUS_HELP,
+    subcommand_negates_reqs = true
+)]
 struct Args {
-    #[clap(long, short)]
-    spaghetti: PathBuf,
+    #[clap(flatten)]
+    log: LogArgs,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// Spaghetti file wiring the plugs together: YAML, or TOML or JSON by its
+    /// `.toml`/`.json` extension. It is read again on SIGHUP, and only the
+    /// plugs and links that changed are restarted.
+    #[clap(long, short, required = true)]
+    spaghetti: Option<PathBuf>,
 
     /// Period to wait for each child process to exit after a closing handshake
     /// before killing it
@@ -23,34 +63,64 @@ struct Args {
     termination_grace_period_secs: u64,
 }
 
-impl Args {
-    fn load_spaghetti_config(&self) -> Result<spaghetti::Config> {
-        let spaghetti_file = std::fs::OpenOptions::new()
-            .read(true)
-            .open(&self.spaghetti)
-            .with_context(|| format!("Failed to open {:?}", &self.spaghetti))?;
-        let spagetthi_rdr = std::io::BufReader::new(spaghetti_file);
-        let raw: Config<Raw> = serde_yaml::from_reader(spagetthi_rdr)
-            .with_context(|| format!("Unable to parse {:?}", self.spaghetti))?;
-        raw.validate()
-            .with_context(|| format!("Invalid configuration in {:?}", self.spaghetti))
-    }
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print the JSON Schema of spaghetti files
+    Schema,
+}
+
+fn load_spaghetti_config(path: &Path) -> Result<spaghetti::Config> {
+    let spaghetti =
+        std::fs::read_to_string(path).with_context(|| format!("Failed to open {path:?}"))?;
+    let raw = Config::<Raw>::parse(&spaghetti, Format::from_path(path))
+        .with_context(|| format!("Unable to parse {path:?}"))?;
+    let config = raw
+        .validate()
+        .with_context(|| format!("Invalid configuration in {path:?}"))?;
+    Ok(config.relative_to(path.parent().unwrap_or(Path::new(""))))
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::fmt::layer()
-                .with_ansi(false)
-                .with_writer(std::io::stderr),
-        )
-        .with(EnvFilter::from_default_env())
-        .init();
+async fn main() -> ExitCode {
+    let args = Args::parse_with_license_notice(include_notice!());
+    args.log.init();
+    if let Some(Command::Schema) = args.command {
+        let schema = serde_json::to_string_pretty(&spaghetti::schema())
+            .expect("a JSON Schema serializes to JSON");
+        println!("{schema}");
+        return ExitCode::SUCCESS;
+    }
+    let path = args
+        .spaghetti
+        .as_deref()
+        .expect("clap requires --spaghetti without a subcommand");
     tracing::info!("Starting");
+    let config = match load_spaghetti_config(path) {
+        Ok(config) => config,
+        Err(e) => return fail(EXIT_CONFIG_ERROR, e),
+    };
+    match app::run(
+        &config,
+        args.termination_grace_period_secs,
+        args.log.log_format,
+        || load_spaghetti_config(path),
+    )
+    .await
+    {
+        Ok(summary) => {
+            eprint!("{summary}");
+            match summary.plug_failure() {
+                Some(e) => fail(EXIT_PLUG_FAILURE, e),
+                None => ExitCode::SUCCESS,
+            }
+        }
+        Err(e) if e.downcast_ref::<app::ConnectError>().is_some() => fail(EXIT_CONNECT_FAILURE, e),
+        Err(e) => fail(1, e),
+    }
+}
 
-    let args = Args::parse_with_license_notice(include_notice!());
-    let config = args.load_spaghetti_config()?;
-    app::run(&config, args.termination_grace_period_secs).await?;
-    Ok(())
+/// Report `e` the way returning it from `main` would, and exit with `code`.
+fn fail(code: u8, e: anyhow::Error) -> ExitCode {
+    eprintln!("Error: {e:?}");
+    ExitCode::from(code)
 }
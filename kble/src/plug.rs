@@ -1,20 +1,41 @@
-use std::{io, pin::Pin, process::Stdio, task};
+use std::{
+    io,
+    pin::Pin,
+    process::{ExitStatus, Stdio},
+    task,
+};
 
 use anyhow::{anyhow, ensure, Context, Result};
-use futures::{future, stream, Sink, SinkExt, Stream, StreamExt, TryStreamExt};
+use futures::StreamExt;
+use kble_socket::{
+    logging::{self, LogFormat},
+    ControlStream,
+};
 use pin_project::pin_project;
 use tokio::{
     io::{AsyncRead, AsyncWrite},
     process::{Child, ChildStdin, ChildStdout},
 };
 use tokio_tungstenite::{
-    tungstenite::{protocol::Role, Message},
+    tungstenite::{
+        client::IntoClientRequest,
+        handshake::client::{Request, Response},
+        http::{header::SEC_WEBSOCKET_PROTOCOL, HeaderName, HeaderValue},
+        protocol::Role,
+    },
     WebSocketStream,
 };
-use url::Url;
+use tracing::{debug, info, warn, Instrument};
 
-pub type PlugSink = Pin<Box<dyn Sink<Vec<u8>, Error = anyhow::Error> + Send + 'static>>;
-pub type PlugStream = Pin<Box<dyn Stream<Item = Result<Vec<u8>>> + Send + 'static>>;
+use crate::{
+    spaghetti::{Framing, Plug},
+    tls,
+};
+
+pub type PlugSink = kble_socket::EnvelopeSink;
+pub type PlugStream = kble_socket::EnvelopeStream;
+/// Control messages to the plug, sent as Text frames beside the data
+pub type PlugControl = kble_socket::ControlSink;
 
 pub enum Backend {
     WebSocketClient,
@@ -22,14 +43,17 @@ pub enum Backend {
 }
 
 impl Backend {
-    pub async fn wait(&mut self) -> Result<()> {
+    /// Wait for the plug's process to exit, returning its exit status, or
+    /// `None` for a plug without one.
+    pub async fn wait(&mut self) -> Result<Option<ExitStatus>> {
         match self {
-            Backend::WebSocketClient => Ok(()),
+            Backend::WebSocketClient => Ok(None),
             Backend::StdioProcess(proc) => {
-                proc.wait()
+                let status = proc
+                    .wait()
                     .await
                     .with_context(|| format!("Failed to wait for {proc:?}"))?;
-                Ok(())
+                Ok(Some(status))
             }
         }
     }
@@ -42,15 +66,29 @@ impl Backend {
     }
 }
 
-pub async fn connect(url: &Url) -> Result<(Backend, PlugSink, PlugStream)> {
+/// Connect to the plug `name`. An `exec:` plug is told its name and the log
+/// format through the environment, and offered length-prefixed framing if its
+/// `framing` says so.
+pub async fn connect(
+    name: &str,
+    plug: &Plug,
+    log_format: LogFormat,
+) -> Result<(Backend, PlugSink, PlugStream, PlugControl)> {
+    let url = &plug.url;
     match url.scheme() {
-        "exec" => connect_exec(url).await,
-        "ws" | "wss" => connect_ws(url).await,
+        "exec" => connect_exec(name, plug, log_format).await,
+        "ws" => connect_ws(plug).await,
+        "wss" => connect_wss(plug).await,
         _ => Err(anyhow!("Unsupported scheme: {}", url.scheme())),
     }
 }
 
-async fn connect_exec(url: &Url) -> Result<(Backend, PlugSink, PlugStream)> {
+async fn connect_exec(
+    name: &str,
+    plug: &Plug,
+    log_format: LogFormat,
+) -> Result<(Backend, PlugSink, PlugStream, PlugControl)> {
+    let url = &plug.url;
     assert_eq!(url.scheme(), "exec");
     ensure!(url.username().is_empty());
     ensure!(url.password().is_none());
@@ -64,11 +102,18 @@ async fn connect_exec(url: &Url) -> Result<(Backend, PlugSink, PlugStream)> {
     // the space, so this is a no-op for them.) Decode it back to the intended
     // shell command, which is what makes an absolute-path `exec:` plug with
     // arguments work.
-    let command = percent_encoding::percent_decode_str(url.path())
+    let command_line = percent_encoding::percent_decode_str(url.path())
         .decode_utf8()
         .with_context(|| format!("exec command is not valid UTF-8: {url}"))?;
-    let mut proc = tokio::process::Command::new("sh")
-        .args(["-c", command.as_ref()])
+    let mut command = tokio::process::Command::new("sh");
+    command
+        .args(["-c", command_line.as_ref()])
+        .env(logging::PLUG_NAME_ENV, name)
+        .env(logging::LOG_FORMAT_ENV, log_format.as_str());
+    if plug.framing == Framing::LengthPrefixed {
+        command.env(kble_socket::FRAMING_ENV, kble_socket::LENGTH_PREFIXED);
+    }
+    let mut proc = command
         .stderr(Stdio::inherit())
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
@@ -77,9 +122,24 @@ async fn connect_exec(url: &Url) -> Result<(Backend, PlugSink, PlugStream)> {
     let stdin = proc.stdin.take().unwrap();
     let stdout = proc.stdout.take().unwrap();
     let stdio = ChildStdio { stdin, stdout };
-    let wss = WebSocketStream::from_raw_socket(stdio, Role::Client, None).await;
-    let (stream, sink) = wss_to_pair(wss);
-    Ok((Backend::StdioProcess(proc), stream, sink))
+    let builder = socket_builder(plug);
+    let (sink, stream, control) = match plug.framing {
+        Framing::Websocket => {
+            let config = Some(builder.websocket_config());
+            let wss = WebSocketStream::from_raw_socket(stdio, Role::Client, config).await;
+            wss_to_pair(wss, &builder)
+        }
+        Framing::LengthPrefixed => {
+            let (sink, stream, control, control_stream) = builder
+                .build_child(stdio)
+                .await
+                .with_context(|| format!("Failed to connect to {url}"))?
+                .split_enveloped();
+            tokio::spawn(log_control(control_stream).in_current_span());
+            (sink, stream, control)
+        }
+    };
+    Ok((Backend::StdioProcess(proc), sink, stream, control))
 }
 
 #[pin_project]
@@ -125,27 +185,160 @@ impl AsyncRead for ChildStdio {
     }
 }
 
-async fn connect_ws(url: &Url) -> Result<(Backend, PlugSink, PlugStream)> {
-    let (wss, _resp) = tokio_tungstenite::connect_async(url)
+async fn connect_ws(plug: &Plug) -> Result<(Backend, PlugSink, PlugStream, PlugControl)> {
+    let url = &plug.url;
+    let builder = socket_builder(plug);
+    let config = Some(builder.websocket_config());
+    let (wss, resp) =
+        tokio_tungstenite::connect_async_with_config(handshake_request(plug)?, config, false)
+            .await
+            .with_context(|| format!("Failed to connect to {url}"))?;
+    check_subprotocol(plug, &resp)?;
+    let (sink, stream, control) = wss_to_pair(wss, &builder);
+    Ok((Backend::WebSocketClient, sink, stream, control))
+}
+
+async fn connect_wss(plug: &Plug) -> Result<(Backend, PlugSink, PlugStream, PlugControl)> {
+    let url = &plug.url;
+    let request = handshake_request(plug)?;
+    let tls_stream = tls::connect(url, &plug.tls.clone().unwrap_or_default()).await?;
+    let builder = socket_builder(plug);
+    let config = Some(builder.websocket_config());
+    let (wss, resp) = tokio_tungstenite::client_async_with_config(request, tls_stream, config)
         .await
         .with_context(|| format!("Failed to connect to {url}"))?;
-    let (stream, sink) = wss_to_pair(wss);
-    Ok((Backend::WebSocketClient, stream, sink))
+    check_subprotocol(plug, &resp)?;
+    let (sink, stream, control) = wss_to_pair(wss, &builder);
+    Ok((Backend::WebSocketClient, sink, stream, control))
+}
+
+/// The WebSocket handshake request for a `ws://`/`wss://` plug, carrying its
+/// configured headers (with `${VAR}` expanded) and offered subprotocols.
+fn handshake_request(plug: &Plug) -> Result<Request> {
+    let mut request = plug.url.as_str().into_client_request()?;
+    let headers = request.headers_mut();
+    for (name, value) in &plug.headers {
+        let value = expand_env(value).with_context(|| format!("Invalid header {name}"))?;
+        headers.insert(
+            HeaderName::from_bytes(name.as_bytes())?,
+            HeaderValue::from_str(&value).with_context(|| format!("Invalid header {name}"))?,
+        );
+    }
+    if !plug.subprotocols.is_empty() {
+        let protocols = plug.subprotocols.join(", ");
+        headers.insert(
+            SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_str(&protocols)
+                .with_context(|| format!("Invalid subprotocols: {protocols}"))?,
+        );
+    }
+    Ok(request)
+}
+
+/// Fail the connection if the server picked a subprotocol that was not
+/// offered (RFC 6455, 4.1), which tungstenite leaves to the caller.
+fn check_subprotocol(plug: &Plug, resp: &Response) -> Result<()> {
+    let Some(selected) = resp.headers().get(SEC_WEBSOCKET_PROTOCOL) else {
+        return Ok(());
+    };
+    let selected = selected.to_str().unwrap_or_default();
+    ensure!(
+        plug.subprotocols.iter().any(|p| p == selected),
+        "{} selected subprotocol {selected:?}, which was not offered",
+        plug.url,
+    );
+    debug!("{} selected subprotocol {selected}", plug.url);
+    Ok(())
+}
+
+/// Replace every `${VAR}` in `value` with the environment variable `VAR`.
+/// A `$` not followed by `{` is kept as is.
+fn expand_env(value: &str) -> Result<String> {
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after
+            .find('}')
+            .ok_or_else(|| anyhow!("Unterminated ${{ in {value:?}"))?;
+        let name = &after[..end];
+        let var = std::env::var(name)
+            .with_context(|| format!("Environment variable {name} is not available"))?;
+        expanded.push_str(&var);
+        rest = &after[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Log the control messages a plug sends, e.g. its status, until it closes
+async fn log_control(mut control: ControlStream) {
+    while let Some(msg) = control.next().await {
+        match msg {
+            Ok(msg) => info!(control = %msg, "Control message"),
+            Err(e) => warn!("{e:#}"),
+        }
+    }
+}
+
+/// The socket options of `plug`. Its size limits go into the WebSocket when it
+/// is opened, through [`kble_socket::Builder::websocket_config`].
+fn socket_builder(plug: &Plug) -> kble_socket::Builder {
+    let mut builder = kble_socket::Builder::new().offer_envelopes();
+    if let Some(keepalive) = plug.keepalive {
+        builder = builder.keepalive(keepalive.into());
+    }
+    if let Some(max) = plug.max_message_size {
+        builder = builder.max_message_size(max);
+    }
+    if let Some(max) = plug.max_frame_size {
+        builder = builder.max_frame_size(max);
+    }
+    builder
 }
 
-fn wss_to_pair<S>(wss: WebSocketStream<S>) -> (PlugSink, PlugStream)
+/// Plugs are driven through kble-socket, which surfaces a plug's abnormal
+/// Close frame (e.g. a plug that failed) as a [`kble_socket::CloseError`] on
+/// the stream, and a message over the size limit as a
+/// [`kble_socket::MessageTooLong`].
+fn wss_to_pair<S>(
+    wss: WebSocketStream<S>,
+    builder: &kble_socket::Builder,
+) -> (PlugSink, PlugStream, PlugControl)
 where
     S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
 {
-    let (sink, stream) = wss.split();
-    let sink = sink
-        .with_flat_map(|b| stream::iter([Ok(Message::Binary(b))]))
-        .sink_map_err(Into::into);
-    let stream = stream
-        .try_filter_map(|msg| match msg {
-            Message::Binary(b) => future::ok(Some(b)),
-            _ => future::ok(None),
-        })
-        .map_err(Into::into);
-    (Box::pin(sink), Box::pin(stream))
+    let (sink, stream, control, control_stream) = builder.build_tungstenite(wss).split_enveloped();
+    tokio::spawn(log_control(control_stream).in_current_span());
+    (sink, stream, control)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand_env() {
+        std::env::set_var("KBLE_PLUG_TEST_TOKEN", "s3cret");
+        assert_eq!(
+            expand_env("Bearer ${KBLE_PLUG_TEST_TOKEN}").unwrap(),
+            "Bearer s3cret"
+        );
+        assert_eq!(
+            expand_env("${KBLE_PLUG_TEST_TOKEN}:${KBLE_PLUG_TEST_TOKEN}").unwrap(),
+            "s3cret:s3cret"
+        );
+        assert_eq!(expand_env("costs $5").unwrap(), "costs $5");
+    }
+
+    #[test]
+    fn test_expand_env_unset() {
+        assert!(expand_env("${KBLE_PLUG_TEST_SURELY_UNSET}").is_err());
+    }
+
+    #[test]
+    fn test_expand_env_unterminated() {
+        assert!(expand_env("${KBLE_PLUG_TEST_TOKEN").is_err());
+    }
 }
@@ -1,20 +1,320 @@
 use anyhow::{anyhow, Result};
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    convert::Infallible,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
 
+use kble_socket::ControlMessage;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr, PickFirst};
+use tokio_tungstenite::tungstenite::http::HeaderName;
 use url::Url;
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+use crate::{impairment::ImpairmentOptions, throttle::ThrottleOptions, transform::Transform};
+
+/// A kble spaghetti file: the plugs to connect, and the links forwarding
+/// messages between them.
+#[serde_as]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+#[schemars(title = "Spaghetti")]
 pub struct Inner {
-    plugs: HashMap<String, Url>,
-    links: HashMap<String, String>,
+    /// Plugs by name. A plug is either a bare URL or a map with `url` and
+    /// per-plug options.
+    #[serde_as(as = "HashMap<_, PickFirst<(_, DisplayFromStr)>>")]
+    plugs: HashMap<String, Plug>,
+    /// Links by source plug: either the name of the plug its messages are
+    /// forwarded to, or a map with `to` and the `transforms` to apply. Each
+    /// plug is the destination of at most one link.
+    #[serde_as(as = "HashMap<_, PickFirst<(_, DisplayFromStr)>>")]
+    links: HashMap<String, Link>,
+    /// Plugs and links instantiated once per index, e.g. for each spacecraft
+    /// of a constellation
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    templates: Vec<Template>,
+}
+
+/// The most instances a template may have
+const MAX_TEMPLATE_COUNT: u64 = 10_000;
+
+/// Plugs and links written once and instantiated `count` times. In each
+/// instance, `{index}` in their names and values is replaced with the index,
+/// and `{index+N}` or `{index-N}` with the index offset by N.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Template {
+    /// At most 10000
+    pub count: u64,
+    /// The first index
+    #[serde(default)]
+    pub start: u64,
+    /// As `plugs`
+    #[serde(default)]
+    pub plugs: serde_json::Map<String, serde_json::Value>,
+    /// As `links`
+    #[serde(default)]
+    pub links: serde_json::Map<String, serde_json::Value>,
+}
+
+/// The plugs and links of a template instance
+#[serde_as]
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Instance {
+    #[serde_as(as = "HashMap<_, PickFirst<(_, DisplayFromStr)>>")]
+    plugs: HashMap<String, Plug>,
+    #[serde_as(as = "HashMap<_, PickFirst<(_, DisplayFromStr)>>")]
+    links: HashMap<String, Link>,
+}
+
+impl Template {
+    fn instantiate(&self, index: u64) -> Result<Instance> {
+        let instance = serde_json::json!({ "plugs": self.plugs, "links": self.links });
+        let instance = substitute_index(instance, index)?;
+        Ok(serde_json::from_value(instance)?)
+    }
+}
+
+/// Replace `{index}`, `{index+N}` and `{index-N}` in every string and key of
+/// `value`
+fn substitute_index(value: serde_json::Value, index: u64) -> Result<serde_json::Value> {
+    use serde_json::Value;
+    Ok(match value {
+        Value::String(s) => Value::String(substitute_index_str(&s, index)?),
+        Value::Array(values) => Value::Array(
+            values
+                .into_iter()
+                .map(|value| substitute_index(value, index))
+                .collect::<Result<_>>()?,
+        ),
+        Value::Object(map) => Value::Object(
+            map.into_iter()
+                .map(|(key, value)| {
+                    Ok((
+                        substitute_index_str(&key, index)?,
+                        substitute_index(value, index)?,
+                    ))
+                })
+                .collect::<Result<_>>()?,
+        ),
+        value => value,
+    })
+}
+
+fn substitute_index_str(s: &str, index: u64) -> Result<String> {
+    const VAR: &str = "{index";
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find(VAR) {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + VAR.len()..];
+        let end = after
+            .find('}')
+            .ok_or_else(|| anyhow!("Unclosed {{index}} in {s:?}"))?;
+        let offset = after[..end].trim();
+        let value = if offset.is_empty() {
+            Some(index)
+        } else if let Some(n) = offset.strip_prefix('+') {
+            n.trim().parse().ok().and_then(|n| index.checked_add(n))
+        } else if let Some(n) = offset.strip_prefix('-') {
+            n.trim().parse().ok().and_then(|n| index.checked_sub(n))
+        } else {
+            None
+        };
+        let value = value.ok_or_else(|| {
+            anyhow!(
+                "Invalid {{index{}}} in {s:?} at index {index}",
+                &after[..end]
+            )
+        })?;
+        out.push_str(&value.to_string());
+        rest = &after[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// A plug: where to connect, and how
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Plug {
+    /// `exec:<command>`, `ws://…` or `wss://…`
+    pub url: Url,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls: Option<TlsOptions>,
+    /// Extra HTTP headers sent with the WebSocket handshake. `${VAR}` in a
+    /// value is replaced with the environment variable `VAR` when connecting,
+    /// so secrets need not be written into the spaghetti file.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    /// WebSocket subprotocols offered in the handshake, most preferred first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub subprotocols: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keepalive: Option<KeepaliveOptions>,
+    /// Largest message, in bytes, accepted from the plug, instead of 64 MiB.
+    /// A larger one fails the plug's links.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_message_size: Option<usize>,
+    /// Largest WebSocket frame, in bytes, accepted from the plug. Defaults to
+    /// `max_message_size` when that is set, else to 16 MiB.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_frame_size: Option<usize>,
+    /// How to talk to an `exec:` plug over its stdin/stdout
+    #[serde(default, skip_serializing_if = "Framing::is_websocket")]
+    pub framing: Framing,
+    /// Control messages sent to the plug once connected, in order, e.g.
+    /// `{type: set_baud_rate, baud_rate: 115200}`. Each is a map whose `type`
+    /// says what it is; a plug ignores a type it does not know.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub control: Vec<serde_json::Map<String, serde_json::Value>>,
+}
+
+/// The framing `kble` speaks with an `exec:` plug
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Framing {
+    /// WebSocket, which every plug speaks
+    #[default]
+    Websocket,
+    /// Offer the plug length-prefixed framing, lighter on a pipe, through
+    /// `KBLE_STDIO_FRAMING`. A plug built on `kble-socket` takes it; any other
+    /// gets WebSocket after a second of waiting for it to.
+    LengthPrefixed,
+}
+
+impl Framing {
+    fn is_websocket(&self) -> bool {
+        *self == Framing::Websocket
+    }
+}
+
+impl Plug {
+    /// The control messages sent on connect
+    pub fn control_messages(&self) -> Result<Vec<ControlMessage>> {
+        self.control
+            .iter()
+            .map(|fields| {
+                serde_json::Value::Object(fields.clone())
+                    .to_string()
+                    .parse()
+            })
+            .collect()
+    }
+}
+
+impl FromStr for Plug {
+    type Err = url::ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Plug {
+            url: s.parse()?,
+            tls: None,
+            headers: BTreeMap::new(),
+            subprotocols: Vec::new(),
+            keepalive: None,
+            max_message_size: None,
+            max_frame_size: None,
+            framing: Framing::Websocket,
+            control: Vec::new(),
+        })
+    }
+}
+
+/// A link from a source plug: where its messages go, and what is done to them
+/// on the way
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Link {
+    /// The sink plug
+    pub to: String,
+    /// Applied to each message in order before it is sent to the sink
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub transforms: Vec<Transform>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub throttle: Option<ThrottleOptions>,
+    /// Delay, loss and corruption applied to each message after `transforms`
+    /// and `throttle`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub impairment: Option<ImpairmentOptions>,
+}
+
+impl FromStr for Link {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Link {
+            to: s.to_string(),
+            transforms: Vec::new(),
+            throttle: None,
+            impairment: None,
+        })
+    }
+}
+
+/// TLS settings for a `wss://` plug. Without them, the server certificate is
+/// verified against the built-in web PKI roots and no client certificate is
+/// presented. Relative paths are resolved against the directory of the
+/// spaghetti file, not kble's working directory.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct TlsOptions {
+    /// PEM bundle of CA certificates to trust instead of the built-in roots
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca: Option<PathBuf>,
+    /// PEM certificate chain presented to the server (mTLS)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_cert: Option<PathBuf>,
+    /// PEM private key for `client_cert`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_key: Option<PathBuf>,
+    /// Name sent as SNI and checked against the server certificate, instead of
+    /// the URL host
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_name: Option<String>,
+    /// Skip server certificate verification entirely. For lab use only.
+    #[serde(default)]
+    pub accept_invalid_certs: bool,
+}
+
+impl TlsOptions {
+    fn relative_to(&mut self, dir: &Path) {
+        for path in [&mut self.ca, &mut self.client_cert, &mut self.client_key]
+            .into_iter()
+            .flatten()
+        {
+            *path = dir.join(&*path);
+        }
+    }
+}
+
+/// Keepalive for a plug: a WebSocket Ping is sent every `interval_secs`, and
+/// the plug's links fail once nothing has been heard from it for
+/// `timeout_secs`. Without it, a silently dropped connection hangs forever.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct KeepaliveOptions {
+    pub interval_secs: u64,
+    pub timeout_secs: u64,
+}
+
+impl From<KeepaliveOptions> for kble_socket::Keepalive {
+    fn from(opts: KeepaliveOptions) -> Self {
+        kble_socket::Keepalive {
+            interval: Duration::from_secs(opts.interval_secs),
+            timeout: Duration::from_secs(opts.timeout_secs),
+        }
+    }
 }
 
 #[derive(PartialEq, Debug)]
 pub enum Raw {}
 pub enum Validated {}
 
-#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Debug, Clone, PartialEq)]
 pub struct Config<State = Validated> {
     #[serde(flatten)]
     inner: Inner,
@@ -40,12 +340,136 @@ impl<State> Config<State> {
     }
 }
 
+/// The formats a spaghetti file can be written in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Yaml,
+    Toml,
+    Json,
+}
+
+impl Format {
+    /// The format of the file at `path`, by its extension: `.toml`, `.json`,
+    /// or YAML for anything else.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Format::Toml,
+            Some("json") => Format::Json,
+            _ => Format::Yaml,
+        }
+    }
+}
+
+/// JSON Schema of a spaghetti file, for editors and config generators.
+pub fn schema() -> schemars::Schema {
+    schemars::schema_for!(Inner)
+}
+
 impl Config<Raw> {
-    pub fn validate(self) -> Result<Config<Validated>> {
+    pub fn parse(s: &str, format: Format) -> Result<Self> {
+        let config = match format {
+            // Enums (transforms) as `variant: value` maps, as in TOML and JSON,
+            // rather than YAML tags
+            Format::Yaml => serde_yaml::with::singleton_map_recursive::deserialize(
+                serde_yaml::Deserializer::from_str(s),
+            )?,
+            Format::Toml => toml::from_str(s)?,
+            Format::Json => serde_json::from_str(s)?,
+        };
+        Ok(config)
+    }
+
+    pub fn validate(mut self) -> Result<Config<Validated>> {
         use std::collections::HashSet;
         let mut seen_sinks = HashSet::new();
 
-        for (stream_name, sink_name) in self.inner.links.iter() {
+        for (n, template) in std::mem::take(&mut self.inner.templates).iter().enumerate() {
+            if template.count > MAX_TEMPLATE_COUNT {
+                return Err(anyhow!(
+                    "Template {n}: count must be at most {MAX_TEMPLATE_COUNT}"
+                ));
+            }
+            let end = template
+                .start
+                .checked_add(template.count)
+                .ok_or_else(|| anyhow!("Template {n}: start + count overflows"))?;
+            for index in template.start..end {
+                let instance = template
+                    .instantiate(index)
+                    .map_err(|e| anyhow!("Template {n} at index {index}: {e}"))?;
+                for (name, plug) in instance.plugs {
+                    if self.inner.plugs.contains_key(&name) {
+                        return Err(anyhow!("Plug {name} defined more than once"));
+                    }
+                    self.inner.plugs.insert(name, plug);
+                }
+                for (name, link) in instance.links {
+                    if self.inner.links.contains_key(&name) {
+                        return Err(anyhow!("Link from {name} defined more than once"));
+                    }
+                    self.inner.links.insert(name, link);
+                }
+            }
+        }
+
+        for (name, plug) in self.inner.plugs.iter() {
+            if let Some(tls) = &plug.tls {
+                if plug.url.scheme() != "wss" {
+                    return Err(anyhow!("Plug {name}: tls is only supported for wss://"));
+                }
+                if tls.client_cert.is_some() != tls.client_key.is_some() {
+                    return Err(anyhow!(
+                        "Plug {name}: client_cert and client_key must be given together"
+                    ));
+                }
+            }
+            let has_ws_options = !plug.headers.is_empty() || !plug.subprotocols.is_empty();
+            if has_ws_options && !matches!(plug.url.scheme(), "ws" | "wss") {
+                return Err(anyhow!(
+                    "Plug {name}: headers and subprotocols are only supported for ws:// and wss://"
+                ));
+            }
+            for header in plug.headers.keys() {
+                HeaderName::from_bytes(header.as_bytes())
+                    .map_err(|_| anyhow!("Plug {name}: invalid header name: {header}"))?;
+            }
+            if let Some(keepalive) = &plug.keepalive {
+                if keepalive.interval_secs == 0 {
+                    return Err(anyhow!(
+                        "Plug {name}: keepalive interval_secs must be positive"
+                    ));
+                }
+                if keepalive.timeout_secs <= keepalive.interval_secs {
+                    return Err(anyhow!(
+                        "Plug {name}: keepalive timeout_secs must be longer than interval_secs"
+                    ));
+                }
+            }
+            if plug.framing != Framing::Websocket && plug.url.scheme() != "exec" {
+                return Err(anyhow!("Plug {name}: framing is only supported for exec:"));
+            }
+            if plug.max_message_size == Some(0) {
+                return Err(anyhow!("Plug {name}: max_message_size must be positive"));
+            }
+            if plug.max_frame_size == Some(0) {
+                return Err(anyhow!("Plug {name}: max_frame_size must be positive"));
+            }
+            plug.control_messages()
+                .map_err(|e| anyhow!("Plug {name}: {e}"))?;
+        }
+
+        for (stream_name, link) in self.inner.links.iter() {
+            let sink_name = &link.to;
+            if let Some(throttle) = &link.throttle {
+                throttle
+                    .validate()
+                    .map_err(|e| anyhow!("Link {stream_name}: throttle {e}"))?;
+            }
+            if let Some(impairment) = &link.impairment {
+                impairment
+                    .validate()
+                    .map_err(|e| anyhow!("Link {stream_name}: impairment {e}"))?;
+            }
             if !self.inner.plugs.contains_key(stream_name) {
                 return Err(anyhow!("No such plug: {stream_name}"));
             }
@@ -63,33 +487,101 @@ impl Config<Raw> {
 }
 
 impl Config<Validated> {
-    pub fn plugs(&self) -> &HashMap<String, Url> {
+    pub fn plugs(&self) -> &HashMap<String, Plug> {
         &self.inner.plugs
     }
 
-    pub fn links(&self) -> &HashMap<String, String> {
+    pub fn links(&self) -> &HashMap<String, Link> {
         &self.inner.links
     }
+
+    /// Resolve the relative paths in the plugs' options against `dir`, the
+    /// directory of the spaghetti file.
+    pub fn relative_to(mut self, dir: &Path) -> Self {
+        for plug in self.inner.plugs.values_mut() {
+            if let Some(tls) = &mut plug.tls {
+                tls.relative_to(dir);
+            }
+        }
+        self
+    }
+}
+
+/// What to stop and start to turn the running plugs and links into those of a
+/// new configuration. Plugs and links it doesn't mention are left running.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Diff {
+    /// Plugs removed or changed
+    pub stop_plugs: BTreeSet<String>,
+    /// Plugs added or changed
+    pub start_plugs: BTreeSet<String>,
+    /// Links (source to sink) removed, rewired, reconfigured, or between
+    /// plugs to restart
+    pub stop_links: BTreeMap<String, String>,
+    /// Links added, rewired, reconfigured, or between plugs to restart
+    pub start_links: BTreeMap<String, String>,
+}
+
+impl Diff {
+    pub fn new(
+        plugs: &HashMap<String, Plug>,
+        links: &HashMap<String, Link>,
+        config: &Config<Validated>,
+    ) -> Self {
+        let changed = |name: &String| plugs.get(name) != config.plugs().get(name);
+        let stop_plugs: BTreeSet<_> = plugs.keys().filter(|name| changed(name)).cloned().collect();
+        let start_plugs: BTreeSet<_> = config
+            .plugs()
+            .keys()
+            .filter(|name| changed(name))
+            .cloned()
+            .collect();
+        let stop_links = links
+            .iter()
+            .filter(|(source, link)| {
+                config.links().get(*source) != Some(link)
+                    || stop_plugs.contains(*source)
+                    || stop_plugs.contains(&link.to)
+            })
+            .map(|(source, link)| (source.clone(), link.to.clone()))
+            .collect();
+        let start_links = config
+            .links()
+            .iter()
+            .filter(|(source, link)| {
+                links.get(*source) != Some(link)
+                    || start_plugs.contains(*source)
+                    || start_plugs.contains(&link.to)
+            })
+            .map(|(source, link)| (source.clone(), link.to.clone()))
+            .collect();
+        Diff {
+            stop_plugs,
+            start_plugs,
+            stop_links,
+            start_links,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self == &Diff::default()
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    use url::Url;
-
     #[test]
     fn test_de() {
         let yaml = "plugs:\n  tfsync: exec:tfsync foo\n  seriald: ws://seriald.local/\nlinks:\n  tfsync: seriald\n";
         let inner = Inner {
             plugs: HashMap::from_iter([
-                ("tfsync".to_string(), Url::parse("exec:tfsync foo").unwrap()),
-                (
-                    "seriald".to_string(),
-                    Url::parse("ws://seriald.local").unwrap(),
-                ),
+                ("tfsync".to_string(), "exec:tfsync foo".parse().unwrap()),
+                ("seriald".to_string(), "ws://seriald.local".parse().unwrap()),
             ]),
-            links: HashMap::from_iter([("tfsync".to_string(), "seriald".to_string())]),
+            links: HashMap::from_iter([("tfsync".to_string(), "seriald".parse().unwrap())]),
+            templates: Vec::new(),
         };
         let expected = Config {
             inner,
@@ -120,4 +612,374 @@ mod tests {
         let actual: Config<Raw> = serde_yaml::from_str(yaml).unwrap();
         assert!(actual.validate().is_err());
     }
+
+    #[test]
+    fn test_de_tls() {
+        let yaml = "plugs:\n  remote:\n    url: wss://serial.lab/open\n    tls:\n      ca: ca.pem\n      client_cert: client.pem\n      client_key: client.key\n      server_name: serial.internal\n  local: exec:cat\nlinks:\n  remote: local\n";
+        let actual: Config<Raw> = serde_yaml::from_str(yaml).unwrap();
+        let actual = actual.validate().unwrap();
+        let remote = &actual.plugs()["remote"];
+        assert_eq!(remote.url, Url::parse("wss://serial.lab/open").unwrap());
+        let tls = remote.tls.as_ref().unwrap();
+        assert_eq!(tls.ca, Some(PathBuf::from("ca.pem")));
+        assert_eq!(tls.client_cert, Some(PathBuf::from("client.pem")));
+        assert_eq!(tls.client_key, Some(PathBuf::from("client.key")));
+        assert_eq!(tls.server_name.as_deref(), Some("serial.internal"));
+        assert!(!tls.accept_invalid_certs);
+        assert_eq!(actual.plugs()["local"].tls, None);
+    }
+
+    #[test]
+    fn test_tls_paths_relative_to_the_spaghetti_file() {
+        let yaml = "plugs:\n  remote:\n    url: wss://serial.lab/open\n    tls:\n      ca: /etc/kble/ca.pem\n      client_cert: client.pem\n      client_key: keys/client.key\n  local: exec:cat\nlinks:\n  remote: local\n";
+        let actual: Config<Raw> = serde_yaml::from_str(yaml).unwrap();
+        let actual = actual
+            .validate()
+            .unwrap()
+            .relative_to(Path::new("/srv/lab"));
+        let tls = actual.plugs()["remote"].tls.as_ref().unwrap();
+        assert_eq!(tls.ca, Some(PathBuf::from("/etc/kble/ca.pem")));
+        assert_eq!(tls.client_cert, Some(PathBuf::from("/srv/lab/client.pem")));
+        assert_eq!(
+            tls.client_key,
+            Some(PathBuf::from("/srv/lab/keys/client.key"))
+        );
+    }
+
+    #[test]
+    fn test_de_tls_on_plain_ws() {
+        let yaml = "plugs:\n  remote:\n    url: ws://serial.lab/open\n    tls:\n      accept_invalid_certs: true\nlinks: {}\n";
+        let actual: Config<Raw> = serde_yaml::from_str(yaml).unwrap();
+        assert!(actual.validate().is_err());
+    }
+
+    #[test]
+    fn test_de_tls_client_cert_without_key() {
+        let yaml = "plugs:\n  remote:\n    url: wss://serial.lab/open\n    tls:\n      client_cert: client.pem\nlinks: {}\n";
+        let actual: Config<Raw> = serde_yaml::from_str(yaml).unwrap();
+        assert!(actual.validate().is_err());
+    }
+
+    #[test]
+    fn test_de_headers_and_subprotocols() {
+        let yaml = "plugs:\n  remote:\n    url: ws://serial.lab/open\n    headers:\n      Authorization: Bearer ${TOKEN}\n    subprotocols: [kble.v1, kble.v0]\n  local: exec:cat\nlinks:\n  remote: local\n";
+        let actual: Config<Raw> = serde_yaml::from_str(yaml).unwrap();
+        let actual = actual.validate().unwrap();
+        let remote = &actual.plugs()["remote"];
+        assert_eq!(
+            remote.headers,
+            BTreeMap::from_iter([("Authorization".to_string(), "Bearer ${TOKEN}".to_string())])
+        );
+        assert_eq!(remote.subprotocols, ["kble.v1", "kble.v0"]);
+    }
+
+    #[test]
+    fn test_de_headers_on_exec() {
+        let yaml = "plugs:\n  local:\n    url: exec:cat\n    headers:\n      Authorization: secret\nlinks: {}\n";
+        let actual: Config<Raw> = serde_yaml::from_str(yaml).unwrap();
+        assert!(actual.validate().is_err());
+    }
+
+    #[test]
+    fn test_de_invalid_header_name() {
+        let yaml = "plugs:\n  remote:\n    url: ws://serial.lab/open\n    headers:\n      \"Bad Header\": x\nlinks: {}\n";
+        let actual: Config<Raw> = serde_yaml::from_str(yaml).unwrap();
+        assert!(actual.validate().is_err());
+    }
+
+    #[test]
+    fn test_de_keepalive() {
+        let yaml = "plugs:\n  remote:\n    url: ws://serial.lab/open\n    keepalive:\n      interval_secs: 5\n      timeout_secs: 15\n  local: exec:cat\nlinks:\n  remote: local\n";
+        let actual: Config<Raw> = serde_yaml::from_str(yaml).unwrap();
+        let actual = actual.validate().unwrap();
+        assert_eq!(
+            actual.plugs()["remote"].keepalive,
+            Some(KeepaliveOptions {
+                interval_secs: 5,
+                timeout_secs: 15,
+            })
+        );
+        assert_eq!(actual.plugs()["local"].keepalive, None);
+    }
+
+    #[test]
+    fn test_de_keepalive_timeout_not_after_interval() {
+        let yaml = "plugs:\n  remote:\n    url: ws://serial.lab/open\n    keepalive:\n      interval_secs: 5\n      timeout_secs: 5\nlinks: {}\n";
+        let actual: Config<Raw> = serde_yaml::from_str(yaml).unwrap();
+        assert!(actual.validate().is_err());
+    }
+
+    #[test]
+    fn test_de_keepalive_zero_interval() {
+        let yaml = "plugs:\n  remote:\n    url: ws://serial.lab/open\n    keepalive:\n      interval_secs: 0\n      timeout_secs: 5\nlinks: {}\n";
+        let actual: Config<Raw> = serde_yaml::from_str(yaml).unwrap();
+        assert!(actual.validate().is_err());
+    }
+
+    #[test]
+    fn test_de_max_message_size() {
+        let yaml = "plugs:\n  dump:\n    url: exec:kble-dump record dump.bin\n    max_message_size: 268435456\n  local: exec:cat\nlinks:\n  local: dump\n";
+        let actual: Config<Raw> = serde_yaml::from_str(yaml).unwrap();
+        let actual = actual.validate().unwrap();
+        assert_eq!(actual.plugs()["dump"].max_message_size, Some(256 << 20));
+        assert_eq!(actual.plugs()["dump"].max_frame_size, None);
+        assert_eq!(actual.plugs()["local"].max_message_size, None);
+
+        let yaml = "plugs:\n  dump:\n    url: exec:cat\n    max_frame_size: 0\nlinks: {}\n";
+        let actual: Config<Raw> = serde_yaml::from_str(yaml).unwrap();
+        assert!(actual.validate().is_err());
+    }
+
+    #[test]
+    fn test_de_framing() {
+        let yaml = "plugs:\n  enc:\n    url: exec:kble-eb90 encode\n    framing: length_prefixed\n  local: exec:cat\nlinks:\n  local: enc\n";
+        let actual: Config<Raw> = serde_yaml::from_str(yaml).unwrap();
+        let actual = actual.validate().unwrap();
+        assert_eq!(actual.plugs()["enc"].framing, Framing::LengthPrefixed);
+        assert_eq!(actual.plugs()["local"].framing, Framing::Websocket);
+
+        let yaml = "plugs:\n  remote:\n    url: ws://serial.lab/open\n    framing: length_prefixed\nlinks: {}\n";
+        let actual: Config<Raw> = serde_yaml::from_str(yaml).unwrap();
+        assert!(actual.validate().is_err());
+    }
+
+    #[test]
+    fn test_de_control() {
+        let yaml = "plugs:\n  serial:\n    url: exec:kble-serialport\n    control:\n      - type: set_baud_rate\n        baud_rate: 115200\n      - type: flush\nlinks: {}\n";
+        let actual: Config<Raw> = serde_yaml::from_str(yaml).unwrap();
+        let actual = actual.validate().unwrap();
+        assert_eq!(
+            actual.plugs()["serial"].control_messages().unwrap(),
+            [
+                ControlMessage::new("set_baud_rate").with("baud_rate", 115200),
+                ControlMessage::new("flush"),
+            ]
+        );
+
+        let yaml = "plugs:\n  serial:\n    url: exec:kble-serialport\n    control:\n      - baud_rate: 115200\nlinks: {}\n";
+        let actual: Config<Raw> = serde_yaml::from_str(yaml).unwrap();
+        assert!(actual.validate().is_err());
+    }
+
+    #[test]
+    fn test_parse_formats() {
+        let yaml = "plugs:\n  tfsync: exec:tfsync foo\n  seriald:\n    url: ws://seriald.local/\n    subprotocols: [kble.v1]\nlinks:\n  tfsync: seriald\n";
+        let toml = "[plugs]\ntfsync = \"exec:tfsync foo\"\n\n[plugs.seriald]\nurl = \"ws://seriald.local/\"\nsubprotocols = [\"kble.v1\"]\n\n[links]\ntfsync = \"seriald\"\n";
+        let json = r#"{"plugs": {"tfsync": "exec:tfsync foo", "seriald": {"url": "ws://seriald.local/", "subprotocols": ["kble.v1"]}}, "links": {"tfsync": "seriald"}}"#;
+        let expected = Config::parse(yaml, Format::Yaml).unwrap();
+        assert_eq!(Config::parse(toml, Format::Toml).unwrap(), expected);
+        assert_eq!(Config::parse(json, Format::Json).unwrap(), expected);
+        expected.validate().unwrap();
+    }
+
+    #[test]
+    fn test_de_link_transforms() {
+        let yaml = "plugs:\n  a: exec:a\n  b: exec:b\nlinks:\n  a:\n    to: b\n    transforms:\n      - strip_prefix: 4\n      - hex_log\n  b: a\n";
+        let toml = "[plugs]\na = \"exec:a\"\nb = \"exec:b\"\n\n[links]\nb = \"a\"\n\n[links.a]\nto = \"b\"\ntransforms = [{ strip_prefix = 4 }, \"hex_log\"]\n";
+        let actual = Config::parse(yaml, Format::Yaml).unwrap();
+        assert_eq!(Config::parse(toml, Format::Toml).unwrap(), actual);
+        let actual = actual.validate().unwrap();
+        assert_eq!(
+            actual.links()["a"].transforms,
+            [Transform::StripPrefix(4), Transform::HexLog]
+        );
+        assert_eq!(actual.links()["b"], "a".parse().unwrap());
+    }
+
+    #[test]
+    fn test_de_link_to_unknown_plug() {
+        let yaml = "plugs:\n  a: exec:a\nlinks:\n  a:\n    to: b\n    transforms: [hex_log]\n";
+        let actual: Config<Raw> = serde_yaml::from_str(yaml).unwrap();
+        assert!(actual.validate().is_err());
+    }
+
+    #[test]
+    fn test_de_link_impairment() {
+        let yaml = "plugs:\n  a: exec:a\n  b: exec:b\nlinks:\n  a:\n    to: b\n    impairment:\n      seed: 7\n      latency_ms: 250\n      jitter:\n        distribution: normal\n        ms: 20\n      drop_probability: 0.01\n      bit_error_rate: 1e-6\n";
+        let actual = Config::parse(yaml, Format::Yaml)
+            .unwrap()
+            .validate()
+            .unwrap();
+        assert_eq!(
+            actual.links()["a"].impairment,
+            Some(ImpairmentOptions {
+                seed: Some(7),
+                latency_ms: 250,
+                jitter: Some(crate::impairment::Jitter::Normal { ms: 20 }),
+                drop_probability: 0.01,
+                bit_error_rate: 1e-6,
+                ..Default::default()
+            })
+        );
+
+        let yaml = "plugs:\n  a: exec:a\n  b: exec:b\nlinks:\n  a:\n    to: b\n    impairment:\n      drop_probability: 2\n";
+        let actual = Config::parse(yaml, Format::Yaml).unwrap();
+        assert!(actual.validate().is_err());
+    }
+
+    #[test]
+    fn test_de_link_throttle() {
+        let yaml = "plugs:\n  a: exec:a\n  b: exec:b\nlinks:\n  a:\n    to: b\n    throttle:\n      bits_per_second: 9600\n      overhead_bits_per_byte: 2\n";
+        let actual = Config::parse(yaml, Format::Yaml)
+            .unwrap()
+            .validate()
+            .unwrap();
+        assert_eq!(
+            actual.links()["a"].throttle,
+            Some(ThrottleOptions {
+                bits_per_second: 9600,
+                overhead_bits_per_byte: 2,
+                chunk_bytes: None,
+            })
+        );
+
+        let yaml = "plugs:\n  a: exec:a\n  b: exec:b\nlinks:\n  a:\n    to: b\n    throttle:\n      bits_per_second: 0\n";
+        let actual = Config::parse(yaml, Format::Yaml).unwrap();
+        assert!(actual.validate().is_err());
+    }
+
+    #[test]
+    fn test_templates() {
+        let yaml = "plugs:\n  gs: exec:gs\nlinks: {}\ntemplates:\n  - count: 2\n    start: 1\n    plugs:\n      sat{index}: exec:kble-c2a --scid {index}\n      tcp{index}:\n        url: ws://localhost:{index+9000}/\n    links:\n      sat{index}:\n        to: tcp{index}\n        transforms: [hex_log]\n";
+        let actual = Config::parse(yaml, Format::Yaml)
+            .unwrap()
+            .validate()
+            .unwrap();
+        let mut names = actual.plugs().keys().collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["gs", "sat1", "sat2", "tcp1", "tcp2"]);
+        assert_eq!(
+            actual.plugs()["sat2"].url,
+            Url::parse("exec:kble-c2a --scid 2").unwrap()
+        );
+        assert_eq!(
+            actual.plugs()["tcp1"].url,
+            Url::parse("ws://localhost:9001/").unwrap()
+        );
+        assert_eq!(actual.links()["sat1"].to, "tcp1");
+        assert_eq!(actual.links()["sat2"].transforms, [Transform::HexLog]);
+    }
+
+    #[test]
+    fn test_templates_validated_as_a_whole() {
+        // Names clashing with the top level
+        let yaml = "plugs:\n  sat1: exec:sat\nlinks: {}\ntemplates:\n  - count: 2\n    plugs:\n      sat{index}: exec:sat {index}\n";
+        let actual = Config::parse(yaml, Format::Yaml).unwrap();
+        assert!(actual.validate().is_err());
+        // Every instance linked to the same sink
+        let yaml = "plugs:\n  gs: exec:gs\nlinks: {}\ntemplates:\n  - count: 2\n    plugs:\n      sat{index}: exec:sat {index}\n    links:\n      sat{index}: gs\n";
+        let actual = Config::parse(yaml, Format::Yaml).unwrap();
+        assert!(actual.validate().is_err());
+        // Negative index
+        let yaml = "plugs: {}\nlinks: {}\ntemplates:\n  - count: 1\n    plugs:\n      sat{index-1}: exec:sat\n";
+        let actual = Config::parse(yaml, Format::Yaml).unwrap();
+        assert!(actual.validate().is_err());
+        // Too many instances, or indices past u64::MAX
+        let yaml = "plugs: {}\nlinks: {}\ntemplates:\n  - count: 10001\n    plugs:\n      sat{index}: exec:sat\n";
+        let actual = Config::parse(yaml, Format::Yaml).unwrap();
+        assert!(actual.validate().is_err());
+        let yaml = "plugs: {}\nlinks: {}\ntemplates:\n  - count: 2\n    start: 18446744073709551615\n    plugs:\n      sat{index}: exec:sat\n";
+        let actual = Config::parse(yaml, Format::Yaml).unwrap();
+        assert!(actual.validate().is_err());
+    }
+
+    #[test]
+    fn test_substitute_index() {
+        assert_eq!(
+            substitute_index_str("a{index}b{index + 10}c{index-2}", 5).unwrap(),
+            "a5b15c3"
+        );
+        assert_eq!(
+            substitute_index_str("Bearer ${TOKEN}", 5).unwrap(),
+            "Bearer ${TOKEN}"
+        );
+        assert!(substitute_index_str("{index", 5).is_err());
+        assert!(substitute_index_str("{index*2}", 5).is_err());
+    }
+
+    #[test]
+    fn test_format_from_path() {
+        assert_eq!(Format::from_path(Path::new("a.toml")), Format::Toml);
+        assert_eq!(Format::from_path(Path::new("a.json")), Format::Json);
+        assert_eq!(Format::from_path(Path::new("a.yml")), Format::Yaml);
+        assert_eq!(Format::from_path(Path::new("spaghetti")), Format::Yaml);
+    }
+
+    #[test]
+    fn test_schema() {
+        let schema = serde_json::to_value(schema()).unwrap();
+        assert_eq!(schema["title"], "Spaghetti");
+        let plug = &schema["properties"]["plugs"]["additionalProperties"]["$ref"];
+        let plug = &schema["$defs"][plug.as_str().unwrap().trim_start_matches("#/$defs/")];
+        // A map or a bare URL
+        assert_eq!(plug["anyOf"].as_array().unwrap().len(), 2, "{plug}");
+    }
+
+    fn validated(yaml: &str) -> Config<Validated> {
+        let raw: Config<Raw> = serde_yaml::from_str(yaml).unwrap();
+        raw.validate().unwrap()
+    }
+
+    #[test]
+    fn test_diff_rewired_link() {
+        let running =
+            validated("plugs:\n  a: exec:a\n  b: exec:b\n  c: exec:c\nlinks:\n  a: b\n  b: a\n");
+        let config =
+            validated("plugs:\n  a: exec:a\n  b: exec:b\n  c: exec:c\nlinks:\n  a: c\n  b: a\n");
+        let diff = Diff::new(running.plugs(), running.links(), &config);
+        assert_eq!(
+            diff,
+            Diff {
+                stop_links: BTreeMap::from_iter([("a".to_string(), "b".to_string())]),
+                start_links: BTreeMap::from_iter([("a".to_string(), "c".to_string())]),
+                ..Diff::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_diff_changed_and_removed_plugs() {
+        let running =
+            validated("plugs:\n  a: exec:a\n  b: exec:b\n  c: exec:c\nlinks:\n  a: b\n  c: a\n");
+        let config = validated(
+            "plugs:\n  a: exec:a --verbose\n  b: exec:b\n  d: exec:d\nlinks:\n  a: b\n  d: a\n",
+        );
+        let diff = Diff::new(running.plugs(), running.links(), &config);
+        let names = |names: &[&str]| names.iter().map(|name| name.to_string()).collect();
+        let links = |links: &[(&str, &str)]| {
+            links
+                .iter()
+                .map(|(source, sink)| (source.to_string(), sink.to_string()))
+                .collect()
+        };
+        assert_eq!(diff.stop_plugs, names(&["a", "c"]));
+        assert_eq!(diff.start_plugs, names(&["a", "d"]));
+        assert_eq!(diff.stop_links, links(&[("a", "b"), ("c", "a")]));
+        assert_eq!(diff.start_links, links(&[("a", "b"), ("d", "a")]));
+    }
+
+    #[test]
+    fn test_diff_retransformed_link() {
+        let running = validated("plugs:\n  a: exec:a\n  b: exec:b\nlinks:\n  a: b\n  b: a\n");
+        let config = validated(
+            "plugs:\n  a: exec:a\n  b: exec:b\nlinks:\n  a:\n    to: b\n    transforms: [hex_log]\n  b: a\n",
+        );
+        let diff = Diff::new(running.plugs(), running.links(), &config);
+        assert_eq!(
+            diff,
+            Diff {
+                stop_links: BTreeMap::from_iter([("a".to_string(), "b".to_string())]),
+                start_links: BTreeMap::from_iter([("a".to_string(), "b".to_string())]),
+                ..Diff::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_diff_unchanged() {
+        let running = validated("plugs:\n  a: exec:a\n  b: ws://b.local/\nlinks:\n  a: b\n");
+        let config =
+            validated("plugs:\n  b:\n    url: ws://b.local/\n  a: exec:a\nlinks:\n  a: b\n");
+        assert!(Diff::new(running.plugs(), running.links(), &config).is_empty());
+    }
 }
@@ -0,0 +1,169 @@
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use std::time::Duration;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+/// Rate limit of a link, to forward at the pace of a real serial or RF link
+/// rather than as fast as the sink accepts. A message is delivered once its
+/// last byte would have been transmitted, and the next one is not read from
+/// the source before then.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ThrottleOptions {
+    pub bits_per_second: u64,
+    /// Bits sent per byte on top of its 8 data bits, e.g. 2 for the start and
+    /// stop bits of 8N1 UART
+    #[serde(default)]
+    pub overhead_bits_per_byte: u64,
+    /// Split messages into chunks of at most this many bytes, each delivered
+    /// once transmitted, instead of delivering whole messages
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunk_bytes: Option<usize>,
+}
+
+impl ThrottleOptions {
+    pub fn validate(&self) -> Result<()> {
+        if self.bits_per_second == 0 {
+            return Err(anyhow!("bits_per_second must be positive"));
+        }
+        if self.chunk_bytes == Some(0) {
+            return Err(anyhow!("chunk_bytes must be positive"));
+        }
+        Ok(())
+    }
+}
+
+/// The rate limit of a running link
+pub struct Throttle {
+    options: ThrottleOptions,
+    /// When the last message is transmitted
+    free_at: Option<Instant>,
+}
+
+impl Throttle {
+    pub fn new(options: &ThrottleOptions) -> Self {
+        Throttle {
+            options: options.clone(),
+            free_at: None,
+        }
+    }
+
+    /// Transmit `data` received at `now`, after what is still being
+    /// transmitted: when each chunk of it is through. Chunks share `data`'s
+    /// buffer.
+    pub fn pace(&mut self, mut data: Bytes, now: Instant) -> Vec<(Instant, Bytes)> {
+        let mut at = self.free_at.map_or(now, |free_at| free_at.max(now));
+        let chunks = match self.options.chunk_bytes {
+            Some(chunk_bytes) if data.len() > chunk_bytes => {
+                let mut chunks = Vec::with_capacity(data.len().div_ceil(chunk_bytes));
+                while data.len() > chunk_bytes {
+                    chunks.push(data.split_to(chunk_bytes));
+                }
+                chunks.push(data);
+                chunks
+            }
+            _ => vec![data],
+        };
+        let paced = chunks
+            .into_iter()
+            .map(|chunk| {
+                at += self.transmit_time(chunk.len());
+                (at, chunk)
+            })
+            .collect();
+        self.free_at = Some(at);
+        paced
+    }
+
+    /// Until when the link is still transmitting, if it is at `now`
+    pub fn busy_until(&self, now: Instant) -> Option<Instant> {
+        self.free_at.filter(|free_at| *free_at > now)
+    }
+
+    fn transmit_time(&self, bytes: usize) -> Duration {
+        let bits = bytes as u128 * (8 + self.options.overhead_bits_per_byte) as u128;
+        let nanos = bits * 1_000_000_000 / self.options.bits_per_second as u128;
+        Duration::from_nanos(nanos.try_into().unwrap_or(u64::MAX))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(overhead_bits_per_byte: u64, chunk_bytes: Option<usize>) -> ThrottleOptions {
+        ThrottleOptions {
+            bits_per_second: 9600,
+            overhead_bits_per_byte,
+            chunk_bytes,
+        }
+    }
+
+    #[test]
+    fn test_pace_back_to_back() {
+        let now = Instant::now();
+        let mut throttle = Throttle::new(&options(2, None));
+        // 96 bytes of 10 bits at 9600 bps
+        let first = throttle.pace(Bytes::from(vec![0; 96]), now);
+        assert_eq!(
+            first,
+            [(now + Duration::from_millis(100), Bytes::from(vec![0; 96]))]
+        );
+        assert_eq!(
+            throttle.busy_until(now),
+            Some(now + Duration::from_millis(100))
+        );
+        // Queued behind the first
+        let second = throttle.pace(Bytes::from(vec![1; 48]), now + Duration::from_millis(10));
+        assert_eq!(
+            second,
+            [(now + Duration::from_millis(150), Bytes::from(vec![1; 48]))]
+        );
+        // After an idle wire
+        let later = now + Duration::from_secs(1);
+        assert_eq!(throttle.busy_until(later), None);
+        let third = throttle.pace(Bytes::from(vec![2; 12]), later);
+        assert_eq!(
+            third,
+            [(
+                later + Duration::from_micros(12_500),
+                Bytes::from(vec![2; 12])
+            )]
+        );
+    }
+
+    #[test]
+    fn test_pace_chunks() {
+        let now = Instant::now();
+        let mut throttle = Throttle::new(&options(0, Some(4)));
+        let paced = throttle.pace(Bytes::from_static(b"abcdefghij"), now);
+        // 32 bits at 9600 bps
+        let chunk = Duration::from_nanos(32 * 1_000_000_000 / 9600);
+        assert_eq!(
+            paced,
+            [
+                (now + chunk, Bytes::from_static(b"abcd")),
+                (now + chunk * 2, Bytes::from_static(b"efgh")),
+                (now + chunk * 2 + chunk / 2, Bytes::from_static(b"ij")),
+            ]
+        );
+        assert_eq!(
+            throttle.pace(Bytes::new(), now),
+            [(now + chunk * 2 + chunk / 2, Bytes::new())]
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(options(0, None).validate().is_ok());
+        assert!(options(0, Some(0)).validate().is_err());
+        let options = ThrottleOptions {
+            bits_per_second: 0,
+            ..options(0, None)
+        };
+        assert!(options.validate().is_err());
+    }
+}
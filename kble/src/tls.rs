@@ -0,0 +1,127 @@
+use std::{fs, io, path::Path, sync::Arc, time::SystemTime};
+
+use anyhow::{anyhow, Context, Result};
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier},
+    Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerName,
+};
+use tokio::net::TcpStream;
+use tokio_rustls::{client::TlsStream, TlsConnector};
+use url::{Host, Url};
+
+use crate::spaghetti::TlsOptions;
+
+/// Open a TCP connection to the host of a `wss://` URL and run the TLS
+/// handshake over it, as configured by `opts`.
+pub async fn connect(url: &Url, opts: &TlsOptions) -> Result<TlsStream<TcpStream>> {
+    let host = match url.host() {
+        Some(Host::Domain(domain)) => domain.to_string(),
+        Some(Host::Ipv4(ip)) => ip.to_string(),
+        Some(Host::Ipv6(ip)) => ip.to_string(),
+        None => return Err(anyhow!("No host in {url}")),
+    };
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| anyhow!("No port in {url}"))?;
+    let server_name = opts.server_name.as_deref().unwrap_or(&host);
+    let server_name = ServerName::try_from(server_name)
+        .with_context(|| format!("Invalid TLS server name: {server_name}"))?;
+
+    let connector = TlsConnector::from(client_config(opts)?);
+    let tcp = TcpStream::connect((host.as_str(), port))
+        .await
+        .with_context(|| format!("Failed to connect to {host}:{port}"))?;
+    connector
+        .connect(server_name, tcp)
+        .await
+        .with_context(|| format!("TLS handshake with {url} failed"))
+}
+
+fn client_config(opts: &TlsOptions) -> Result<Arc<ClientConfig>> {
+    let verifier: Arc<dyn ServerCertVerifier> = if opts.accept_invalid_certs {
+        Arc::new(AcceptAnyServerCert)
+    } else {
+        Arc::new(WebPkiVerifier::new(root_store(opts.ca.as_deref())?, None))
+    };
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(verifier);
+    let config = match (&opts.client_cert, &opts.client_key) {
+        (Some(cert), Some(key)) => builder
+            .with_client_auth_cert(load_certs(cert)?, load_key(key)?)
+            .context("Invalid client certificate or key")?,
+        // `Config<Raw>::validate` rejects one without the other
+        _ => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+fn root_store(ca: Option<&Path>) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    match ca {
+        Some(ca) => {
+            for cert in load_certs(ca)? {
+                roots
+                    .add(&cert)
+                    .with_context(|| format!("Invalid CA certificate in {ca:?}"))?;
+            }
+        }
+        None => {
+            roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+                OwnedTrustAnchor::from_subject_spki_name_constraints(
+                    ta.subject,
+                    ta.spki,
+                    ta.name_constraints,
+                )
+            }));
+        }
+    }
+    Ok(roots)
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let mut rdr = open(path)?;
+    let certs = rustls_pemfile::certs(&mut rdr)
+        .with_context(|| format!("Failed to read certificates from {path:?}"))?;
+    if certs.is_empty() {
+        return Err(anyhow!("No certificates found in {path:?}"));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &Path) -> Result<PrivateKey> {
+    let mut rdr = open(path)?;
+    loop {
+        let item = rustls_pemfile::read_one(&mut rdr)
+            .with_context(|| format!("Failed to read private key from {path:?}"))?;
+        match item {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => return Err(anyhow!("No private key found in {path:?}")),
+        }
+    }
+}
+
+fn open(path: &Path) -> Result<io::BufReader<fs::File>> {
+    let file = fs::File::open(path).with_context(|| format!("Failed to open {path:?}"))?;
+    Ok(io::BufReader::new(file))
+}
+
+/// Verifier for `accept_invalid_certs`: trusts whatever the server presents.
+struct AcceptAnyServerCert;
+
+impl ServerCertVerifier for AcceptAnyServerCert {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
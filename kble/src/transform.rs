@@ -0,0 +1,220 @@
+use anyhow::{anyhow, Result};
+use bytes::{Buf, Bytes, BytesMut};
+use std::{fmt, str::FromStr};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+use tracing::info;
+
+/// A built-in manipulation a link applies to each message before sending it
+/// to the sink. Byte strings are written in hex, e.g. `"eb90"`.
+#[serde_as]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum Transform {
+    /// Remove the first N bytes. A shorter message becomes empty.
+    StripPrefix(usize),
+    /// Put these bytes in front of the message
+    Prepend(#[serde_as(as = "DisplayFromStr")] HexBytes),
+    /// Log the message in hex, and pass it on unchanged
+    HexLog,
+    /// Drop the message if it starts with this pattern. `??` matches any byte,
+    /// e.g. `"eb90 ?? 01"`.
+    DropMatching(#[serde_as(as = "DisplayFromStr")] Pattern),
+    /// Cut the message down to at most N bytes
+    Truncate(usize),
+}
+
+impl Transform {
+    /// Apply the transform to a message on `link`. None if it is dropped.
+    /// Only `Prepend` copies the message.
+    pub fn apply(&self, link: &str, mut data: Bytes) -> Option<Bytes> {
+        match self {
+            Transform::StripPrefix(n) => data.advance(data.len().min(*n)),
+            Transform::Prepend(prefix) => {
+                let mut prepended = BytesMut::with_capacity(prefix.0.len() + data.len());
+                prepended.extend_from_slice(&prefix.0);
+                prepended.extend_from_slice(&data);
+                data = prepended.freeze();
+            }
+            Transform::HexLog => info!("{link}: {}", hex::encode(&data)),
+            Transform::DropMatching(pattern) => {
+                if pattern.matches(&data) {
+                    return None;
+                }
+            }
+            Transform::Truncate(n) => data.truncate(*n),
+        }
+        Some(data)
+    }
+}
+
+/// Apply `transforms` in order. None if one of them drops the message.
+pub fn apply_all(transforms: &[Transform], link: &str, data: Bytes) -> Option<Bytes> {
+    transforms
+        .iter()
+        .try_fold(data, |data, transform| transform.apply(link, data))
+}
+
+/// A byte string written in hex. Whitespace between bytes is ignored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HexBytes(pub Vec<u8>);
+
+impl FromStr for HexBytes {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let hex = s.split_whitespace().collect::<String>();
+        let bytes = hex::decode(&hex).map_err(|e| anyhow!("Invalid hex {s:?}: {e}"))?;
+        Ok(HexBytes(bytes))
+    }
+}
+
+impl fmt::Display for HexBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(&self.0))
+    }
+}
+
+/// A byte pattern written in hex, with `??` for a byte that matches anything
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pattern(pub Vec<Option<u8>>);
+
+impl Pattern {
+    /// Whether `data` starts with the pattern
+    pub fn matches(&self, data: &[u8]) -> bool {
+        data.len() >= self.0.len()
+            && self
+                .0
+                .iter()
+                .zip(data)
+                .all(|(expected, byte)| expected.is_none_or(|expected| expected == *byte))
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let digits = s.split_whitespace().collect::<String>();
+        if digits.is_empty() || digits.len() % 2 != 0 {
+            return Err(anyhow!("Invalid pattern {s:?}: expected whole bytes"));
+        }
+        let bytes = digits
+            .as_bytes()
+            .chunks(2)
+            .map(|byte| match byte {
+                b"??" => Ok(None),
+                _ => std::str::from_utf8(byte)
+                    .ok()
+                    .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                    .map(Some)
+                    .ok_or_else(|| anyhow!("Invalid pattern {s:?}: bad byte {byte:?}")),
+            })
+            .collect::<Result<_>>()?;
+        Ok(Pattern(bytes))
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in &self.0 {
+            match byte {
+                Some(byte) => write!(f, "{byte:02x}")?,
+                None => f.write_str("??")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(transforms: &[Transform], data: &[u8]) -> Option<Vec<u8>> {
+        apply_all(transforms, "test", Bytes::copy_from_slice(data)).map(Vec::from)
+    }
+
+    #[test]
+    fn test_slicing_does_not_copy() {
+        let data = Bytes::from_static(b"eb90abcd");
+        let transforms = [Transform::StripPrefix(2), Transform::Truncate(4)];
+        let sliced = apply_all(&transforms, "test", data.clone()).unwrap();
+        assert_eq!(sliced, "90ab");
+        assert_eq!(sliced.as_ptr(), data[2..].as_ptr());
+    }
+
+    #[test]
+    fn test_strip_prefix() {
+        assert_eq!(
+            apply(&[Transform::StripPrefix(2)], b"abcd"),
+            Some(b"cd".to_vec())
+        );
+        assert_eq!(apply(&[Transform::StripPrefix(8)], b"abcd"), Some(vec![]));
+    }
+
+    #[test]
+    fn test_prepend_and_truncate_in_order() {
+        let transforms = [
+            Transform::Prepend("eb90".parse().unwrap()),
+            Transform::Truncate(3),
+        ];
+        assert_eq!(apply(&transforms, b"ab"), Some(vec![0xeb, 0x90, b'a']));
+        let transforms = [
+            Transform::Truncate(3),
+            Transform::Prepend("eb90".parse().unwrap()),
+        ];
+        assert_eq!(
+            apply(&transforms, b"ab"),
+            Some(vec![0xeb, 0x90, b'a', b'b'])
+        );
+    }
+
+    #[test]
+    fn test_drop_matching() {
+        let transforms = [
+            Transform::DropMatching("eb ?? 01".parse().unwrap()),
+            Transform::HexLog,
+        ];
+        assert_eq!(apply(&transforms, &[0xeb, 0x90, 0x01, 0xff]), None);
+        assert_eq!(
+            apply(&transforms, &[0xeb, 0x90, 0x02]),
+            Some(vec![0xeb, 0x90, 0x02])
+        );
+        assert_eq!(apply(&transforms, &[0xeb, 0x90]), Some(vec![0xeb, 0x90]));
+    }
+
+    #[test]
+    fn test_pattern_roundtrip() {
+        let pattern: Pattern = "EB 90 ??".parse().unwrap();
+        assert_eq!(pattern, Pattern(vec![Some(0xeb), Some(0x90), None]));
+        assert_eq!(pattern.to_string(), "eb90??");
+        assert!("eb9".parse::<Pattern>().is_err());
+        assert!("eb?0".parse::<Pattern>().is_err());
+        assert!("".parse::<Pattern>().is_err());
+    }
+
+    #[test]
+    fn test_de() {
+        let yaml = "- strip_prefix: 4\n- prepend: eb90\n- hex_log\n- drop_matching: \"ff ??\"\n- truncate: 1024\n";
+        let de = |yaml| {
+            serde_yaml::with::singleton_map_recursive::deserialize::<Vec<Transform>, _>(
+                serde_yaml::Deserializer::from_str(yaml),
+            )
+        };
+        let transforms = de(yaml).unwrap();
+        assert_eq!(
+            transforms,
+            [
+                Transform::StripPrefix(4),
+                Transform::Prepend(HexBytes(vec![0xeb, 0x90])),
+                Transform::HexLog,
+                Transform::DropMatching(Pattern(vec![Some(0xff), None])),
+                Transform::Truncate(1024),
+            ]
+        );
+        assert!(de("- prepend: xyz\n").is_err());
+    }
+}
@@ -15,16 +15,23 @@
 //! `exec:` plugs (`kble-eb90 encode`/`decode`) so a payload round-trips through
 //! two orchestrator-launched processes, exercising the `exec:` spawn path and
 //! multi-link wiring end to end.
+//!
+//! The `wss://` tests serve the source plug over TLS with certificates minted
+//! per run by a throwaway CA, and check each per-plug `tls:` option (custom CA,
+//! SNI override, client certificate, accept-invalid-certs) against it.
 
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 
 use bytes::Bytes;
+use kble_socket::{ControlMessage, Envelope, Enveloped};
 use kble_test_support::{WsPlug, WsPlugConn};
 use proptest::prelude::*;
 use tokio::process::{Child, Command};
 use tokio::runtime::Runtime;
+use tokio_rustls::TlsAcceptor;
 
 /// Build a `Command` running the freshly-built `kble` orchestrator against the
 /// spaghetti config at `path`. Cargo exposes the binary path to this crate's
@@ -47,10 +54,16 @@ fn kble(path: &Path) -> Command {
 /// Cargo provides, and return its path. Cargo owns that dir, so no cleanup is
 /// needed; the unique counter keeps parallel tests from clobbering each other.
 fn write_spaghetti(yaml: &str) -> PathBuf {
+    write_tmp("spaghetti", "yaml", yaml)
+}
+
+/// Write `contents` to a uniquely-named `{stem}-{n}.{ext}` file under the
+/// per-test-binary temp dir (see [`write_spaghetti`]).
+fn write_tmp(stem: &str, ext: &str, contents: &str) -> PathBuf {
     static COUNTER: AtomicU64 = AtomicU64::new(0);
     let n = COUNTER.fetch_add(1, Ordering::Relaxed);
-    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!("spaghetti-{n}.yaml"));
-    std::fs::write(&path, yaml).expect("write spaghetti config");
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!("{stem}-{n}.{ext}"));
+    std::fs::write(&path, contents).expect("write temp file");
     path
 }
 
@@ -109,6 +122,12 @@ fn plug_bin(name: &str) -> PathBuf {
 /// Bytes sent on `gen` are EB90-framed by `enc`, de-framed by `dec`, and must
 /// arrive on `sink` unchanged.
 async fn spawn_pipeline() -> (Child, WsPlugConn, WsPlugConn) {
+    spawn_pipeline_with("", "websocket").await
+}
+
+/// [`spawn_pipeline`], running the encoder as `exec:{prefix}{eb90} encode`,
+/// and both `exec:` plugs with `framing`
+async fn spawn_pipeline_with(prefix: &str, framing: &str) -> (Child, WsPlugConn, WsPlugConn) {
     let gen = WsPlug::bind().await.expect("bind gen plug");
     let sink = WsPlug::bind().await.expect("bind sink plug");
     let gen_url = gen.url();
@@ -122,7 +141,8 @@ async fn spawn_pipeline() -> (Child, WsPlugConn, WsPlugConn) {
     // target path has no spaces or URL-reserved chars (`#`/`?`), which holds in
     // CI and typical checkouts; otherwise `sh -c` would word-split the path.
     let yaml = format!(
-        "plugs:\n  gen: {gen_url}\n  enc: exec:{eb90} encode\n  dec: exec:{eb90} decode\n  \
+        "plugs:\n  gen: {gen_url}\n  enc:\n    url: exec:{prefix}{eb90} encode\n    framing: {framing}\n  \
+         dec:\n    url: exec:{eb90} decode\n    framing: {framing}\n  \
          sink: {sink_url}\nlinks:\n  gen: enc\n  enc: dec\n  dec: sink\n"
     );
     let config = write_spaghetti(&yaml);
@@ -162,6 +182,132 @@ async fn shutdown_and_assert_clean_exit(
     assert!(status.success(), "orchestrator exited with {status}");
 }
 
+/// Certificates for the `wss://` tests, minted fresh by a throwaway CA. The
+/// server certificate names only `localhost`, *not* the `127.0.0.1` that
+/// [`WsPlug::bind_tls`] serves on, so verifying it needs the `server_name`
+/// override as well as the custom CA. The PEM files are what a spaghetti
+/// config points at; the DER forms configure the in-process server.
+struct TestPki {
+    ca_pem: PathBuf,
+    client_cert_pem: PathBuf,
+    client_key_pem: PathBuf,
+    ca: rustls::Certificate,
+    server_cert: rustls::Certificate,
+    server_key: rustls::PrivateKey,
+}
+
+impl TestPki {
+    fn generate() -> Self {
+        fn params(common_name: &str, sans: &[&str]) -> rcgen::CertificateParams {
+            let sans = sans.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+            let mut params = rcgen::CertificateParams::new(sans);
+            params.distinguished_name = rcgen::DistinguishedName::new();
+            params
+                .distinguished_name
+                .push(rcgen::DnType::CommonName, common_name);
+            params
+        }
+
+        let mut ca_params = params("kble test CA", &[]);
+        ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let ca = rcgen::Certificate::from_params(ca_params).expect("generate CA");
+        let server = rcgen::Certificate::from_params(params("kble test server", &["localhost"]))
+            .expect("generate server certificate");
+        let client = rcgen::Certificate::from_params(params("kble test client", &[]))
+            .expect("generate client certificate");
+
+        Self {
+            ca_pem: write_tmp("ca", "pem", &ca.serialize_pem().expect("CA PEM")),
+            client_cert_pem: write_tmp(
+                "client",
+                "pem",
+                &client.serialize_pem_with_signer(&ca).expect("client PEM"),
+            ),
+            client_key_pem: write_tmp("client", "key", &client.serialize_private_key_pem()),
+            ca: rustls::Certificate(ca.serialize_der().expect("CA DER")),
+            server_cert: rustls::Certificate(
+                server.serialize_der_with_signer(&ca).expect("server DER"),
+            ),
+            server_key: rustls::PrivateKey(server.serialize_private_key_der()),
+        }
+    }
+
+    /// A TLS acceptor presenting the server certificate. With
+    /// `require_client_cert`, it also demands a client certificate issued by
+    /// the CA (mTLS).
+    fn acceptor(&self, require_client_cert: bool) -> TlsAcceptor {
+        let builder = rustls::ServerConfig::builder().with_safe_defaults();
+        let builder = if require_client_cert {
+            let mut roots = rustls::RootCertStore::empty();
+            roots.add(&self.ca).expect("trust the test CA");
+            builder.with_client_cert_verifier(
+                rustls::server::AllowAnyAuthenticatedClient::new(roots).boxed(),
+            )
+        } else {
+            builder.with_no_client_auth()
+        };
+        let config = builder
+            .with_single_cert(vec![self.server_cert.clone()], self.server_key.clone())
+            .expect("valid server certificate");
+        TlsAcceptor::from(Arc::new(config))
+    }
+}
+
+/// Spawn the orchestrator for a `source -> sink` link whose source is served
+/// over TLS by `acceptor`, with `tls` (a YAML block, indented for the plug's
+/// `tls:` key, or empty for none) as the source's TLS options.
+///
+/// Both plugs are accepted concurrently with the orchestrator's exit (bounded),
+/// and every result is handed back unchecked: a test expecting the connection
+/// to be refused asserts on the exit status, one expecting it to succeed on the
+/// connections.
+async fn spawn_wss_forwarder(
+    acceptor: TlsAcceptor,
+    tls: &str,
+) -> (
+    Child,
+    anyhow::Result<WsPlugConn>,
+    anyhow::Result<WsPlugConn>,
+) {
+    let source = WsPlug::bind_tls(acceptor).await.expect("bind wss source");
+    let sink = WsPlug::bind().await.expect("bind sink plug");
+    let yaml = format!(
+        "plugs:\n  source:\n    url: {}\n{tls}  sink: {}\nlinks:\n  source: sink\n",
+        source.url(),
+        sink.url(),
+    );
+    let config = write_spaghetti(&yaml);
+
+    let child = kble(&config).spawn().expect("spawn kble orchestrator");
+    let (source_conn, sink_conn) = tokio::join!(source.accept(), sink.accept());
+    (child, source_conn, sink_conn)
+}
+
+/// Forward one frame across a `wss://` source link and shut down cleanly.
+async fn assert_forwards_over_wss(acceptor: TlsAcceptor, tls: &str) {
+    let (child, source, sink) = spawn_wss_forwarder(acceptor, tls).await;
+    let mut source = source.expect("orchestrator connects to wss source");
+    let mut sink = sink.expect("orchestrator connects to sink plug");
+
+    let payload = Bytes::from_static(b"over tls");
+    source.send(payload.clone()).await.expect("source send");
+    let got = sink.recv().await.expect("sink recv");
+    assert_eq!(got, payload);
+
+    shutdown_and_assert_clean_exit(child, source, sink).await;
+}
+
+/// The orchestrator must refuse a `wss://` plug it cannot (or may not)
+/// establish TLS with, and exit with a failure status.
+async fn assert_refuses_wss(acceptor: TlsAcceptor, tls: &str) {
+    let (mut child, _source, _sink) = spawn_wss_forwarder(acceptor, tls).await;
+    let status = tokio::time::timeout(Duration::from_secs(10), child.wait())
+        .await
+        .expect("orchestrator should exit after failing to connect")
+        .expect("wait for orchestrator");
+    assert!(!status.success(), "orchestrator exited with {status}");
+}
+
 /// A small current-thread runtime to drive the async body of `proptest!` cases.
 fn runtime() -> Runtime {
     tokio::runtime::Builder::new_current_thread()
@@ -231,6 +377,786 @@ async fn roundtrips_through_an_exec_plug_pipeline() {
     shutdown_and_assert_clean_exit(child, gen, sink).await;
 }
 
+/// The pipeline, with both `exec:` plugs offered length-prefixed framing
+#[tokio::test]
+async fn roundtrips_through_length_prefixed_exec_plugs() {
+    let (child, mut gen, mut sink) = spawn_pipeline_with("", "length_prefixed").await;
+
+    let payload = Bytes::from_static(b"through two length-prefixed plugs");
+    gen.send(payload.clone()).await.expect("gen send");
+    let got = sink.recv().await.expect("sink recv");
+    assert_eq!(got, payload);
+
+    shutdown_and_assert_clean_exit(child, gen, sink).await;
+}
+
+/// An `exec:` plug that never sees the length-prefixed framing offer (as a plug
+/// built against an older `kble-socket` wouldn't) still talks WebSocket: `kble`
+/// falls back once the offer goes unanswered, beside a plug that took it.
+#[tokio::test]
+async fn falls_back_to_websocket_for_a_plug_without_framing() {
+    let (child, mut gen, mut sink) =
+        spawn_pipeline_with("env -u KBLE_STDIO_FRAMING ", "length_prefixed").await;
+
+    let payload = Bytes::from_static(b"through a WebSocket and a length-prefixed plug");
+    gen.send(payload.clone()).await.expect("gen send");
+    let got = sink.recv().await.expect("sink recv");
+    assert_eq!(got, payload);
+
+    shutdown_and_assert_clean_exit(child, gen, sink).await;
+}
+
+/// Headers configured on a `ws://` plug reach its handshake request, with
+/// `${VAR}` expanded from the orchestrator's environment, and the configured
+/// subprotocols are offered in preference order.
+#[tokio::test]
+async fn sends_configured_headers_and_subprotocols() {
+    let source = WsPlug::bind().await.expect("bind source plug");
+    let sink = WsPlug::bind().await.expect("bind sink plug");
+    let yaml = format!(
+        "plugs:\n  source:\n    url: {}\n    headers:\n      Authorization: Bearer ${{KBLE_E2E_TOKEN}}\n      \
+         X-Harness: e2e\n    subprotocols: [kble.v1, kble.v0]\n  sink: {}\nlinks:\n  source: sink\n",
+        source.url(),
+        sink.url(),
+    );
+    let config = write_spaghetti(&yaml);
+
+    let child = kble(&config)
+        .env("KBLE_E2E_TOKEN", "s3cret")
+        .spawn()
+        .expect("spawn kble orchestrator");
+    let (source, sink) = tokio::join!(source.accept(), sink.accept());
+    let source = source.expect("orchestrator connects to source plug");
+    let sink = sink.expect("orchestrator connects to sink plug");
+
+    let headers = source.handshake_headers();
+    assert_eq!(headers["authorization"], "Bearer s3cret");
+    assert_eq!(headers["x-harness"], "e2e");
+    assert_eq!(headers["sec-websocket-protocol"], "kble.v1, kble.v0");
+
+    shutdown_and_assert_clean_exit(child, source, sink).await;
+}
+
+/// A plug's `control` messages are sent to it once connected, in order, as
+/// control messages beside the data.
+#[tokio::test]
+async fn sends_configured_control_messages_on_connect() {
+    let source = WsPlug::bind().await.expect("bind source plug");
+    let sink = WsPlug::bind().await.expect("bind sink plug");
+    let yaml = format!(
+        "plugs:\n  source: {}\n  sink:\n    url: {}\n    control:\n      - type: set_baud_rate\n        \
+         baud_rate: 115200\n      - type: flush\nlinks:\n  source: sink\n",
+        source.url(),
+        sink.url(),
+    );
+    let child = kble(&write_spaghetti(&yaml))
+        .spawn()
+        .expect("spawn kble orchestrator");
+    let (source, sink) = tokio::join!(source.accept_enveloped(), sink.accept_enveloped());
+    let source = source.expect("orchestrator connects to source plug");
+    let mut sink = sink.expect("orchestrator connects to sink plug");
+
+    let control = sink.recv_control().await.expect("a control message");
+    assert_eq!(
+        control,
+        ControlMessage::new("set_baud_rate").with("baud_rate", 115200)
+    );
+    let control = sink.recv_control().await.expect("a second control message");
+    assert_eq!(control, ControlMessage::new("flush"));
+
+    shutdown_and_assert_clean_exit(child, source, sink).await;
+}
+
+/// A header referencing an unset environment variable is a connect failure,
+/// not a silently empty secret.
+#[tokio::test]
+async fn refuses_a_header_referencing_an_unset_variable() {
+    let source = WsPlug::bind().await.expect("bind source plug");
+    let sink = WsPlug::bind().await.expect("bind sink plug");
+    let yaml = format!(
+        "plugs:\n  source:\n    url: {}\n    headers:\n      Authorization: Bearer ${{KBLE_E2E_UNSET}}\n  \
+         sink: {}\nlinks:\n  source: sink\n",
+        source.url(),
+        sink.url(),
+    );
+    let config = write_spaghetti(&yaml);
+
+    let mut child = kble(&config)
+        .env_remove("KBLE_E2E_UNSET")
+        .spawn()
+        .expect("spawn kble orchestrator");
+    let wait = async {
+        tokio::time::timeout(Duration::from_secs(10), child.wait())
+            .await
+            .expect("orchestrator should exit after failing to connect")
+            .expect("wait for orchestrator")
+    };
+    // The sink may be dialled (and must then be accepted) before the failure.
+    let (_, _, status) = tokio::join!(source.accept(), sink.accept(), wait);
+    assert_eq!(status.code(), Some(3), "orchestrator exited with {status}");
+}
+
+/// A plug with keepalive that stops answering Pings fails its link: the test
+/// holds the source's connection open but never reads it, so no Pong ever
+/// comes back, and the orchestrator gives up on it instead of hanging.
+#[tokio::test]
+async fn fails_a_link_whose_plug_stops_answering_pings() {
+    let source = WsPlug::bind().await.expect("bind source plug");
+    let sink = WsPlug::bind().await.expect("bind sink plug");
+    let yaml = format!(
+        "plugs:\n  source:\n    url: {}\n    keepalive:\n      interval_secs: 1\n      \
+         timeout_secs: 2\n  sink: {}\nlinks:\n  source: sink\n",
+        source.url(),
+        sink.url(),
+    );
+    let config = write_spaghetti(&yaml);
+
+    let mut child = kble(&config).spawn().expect("spawn kble orchestrator");
+    let (source, sink) = tokio::join!(source.accept(), sink.accept());
+    let _source = source.expect("orchestrator connects to source plug");
+    let mut sink = sink.expect("orchestrator connects to sink plug");
+
+    let drain_sink = async { while sink.recv().await.is_ok() {} };
+    let wait = async {
+        tokio::time::timeout(Duration::from_secs(10), child.wait())
+            .await
+            .expect("orchestrator should give up on the silent plug")
+            .expect("wait for orchestrator")
+    };
+    let (_, status) = tokio::join!(drain_sink, wait);
+    assert_eq!(status.code(), Some(4), "orchestrator exited with {status}");
+}
+
+/// Keepalive leaves a live plug alone: both plugs answer Pings while the link
+/// idles for longer than the timeout, and a frame still gets through.
+#[tokio::test]
+async fn keeps_an_idle_link_whose_plugs_answer_pings() {
+    let source = WsPlug::bind().await.expect("bind source plug");
+    let sink = WsPlug::bind().await.expect("bind sink plug");
+    let keepalive = "    keepalive:\n      interval_secs: 1\n      timeout_secs: 2\n";
+    let yaml = format!(
+        "plugs:\n  source:\n    url: {}\n{keepalive}  sink:\n    url: {}\n{keepalive}links:\n  source: sink\n",
+        source.url(),
+        sink.url(),
+    );
+    let config = write_spaghetti(&yaml);
+
+    let child = kble(&config).spawn().expect("spawn kble orchestrator");
+    let (source, sink) = tokio::join!(source.accept(), sink.accept());
+    let mut source = source.expect("orchestrator connects to source plug");
+    let mut sink = sink.expect("orchestrator connects to sink plug");
+
+    // Reading is what answers the Pings; nothing else arrives meanwhile
+    let idle = Duration::from_secs(3);
+    let (from_source, from_sink) = tokio::join!(source.recv_timeout(idle), sink.recv_timeout(idle));
+    assert!(from_source.is_err() && from_sink.is_err());
+
+    let payload = Bytes::from_static(b"after a quiet spell");
+    source.send(payload.clone()).await.expect("source send");
+    let got = sink.recv().await.expect("sink recv");
+    assert_eq!(got, payload);
+
+    shutdown_and_assert_clean_exit(child, source, sink).await;
+}
+
+/// A plug that fails reports why in its Close frame, and the orchestrator
+/// passes that on: `kble-c2a spacepacket from-tc-tf` rejects a frame too short
+/// to be a TC Transfer Frame, and kble exits with the plug failure code, naming
+/// the plug and the reason.
+#[tokio::test]
+async fn exits_with_the_reason_a_plug_failed() {
+    let gen = WsPlug::bind().await.expect("bind gen plug");
+    let sink = WsPlug::bind().await.expect("bind sink plug");
+    let c2a = plug_bin("kble-c2a");
+    let c2a = c2a.display();
+    let yaml = format!(
+        "plugs:\n  gen: {}\n  unwrap: exec:{c2a} spacepacket from-tc-tf\n  sink: {}\n\
+         links:\n  gen: unwrap\n  unwrap: sink\n",
+        gen.url(),
+        sink.url(),
+    );
+    let config = write_spaghetti(&yaml);
+
+    let child = kble(&config)
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .expect("spawn kble orchestrator");
+    let (gen, sink) = tokio::join!(gen.accept(), sink.accept());
+    let mut gen = gen.expect("orchestrator connects to gen plug");
+    let mut sink = sink.expect("orchestrator connects to sink plug");
+
+    gen.send(Bytes::from_static(b"\x01\x02"))
+        .await
+        .expect("gen send");
+    let drain_sink = async { while sink.recv().await.is_ok() {} };
+    let output = async {
+        tokio::time::timeout(Duration::from_secs(10), child.wait_with_output())
+            .await
+            .expect("orchestrator should exit after the plug failed")
+            .expect("wait for orchestrator")
+    };
+    let (_, output) = tokio::join!(drain_sink, output);
+    assert_eq!(
+        output.status.code(),
+        Some(4),
+        "orchestrator exited with {}",
+        output.status
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Plug unwrap closed with code 1011: TC Transfer Frame is too short"),
+        "{stderr}"
+    );
+}
+
+/// At shutdown the orchestrator prints what each link forwarded and why it
+/// ended, and how each plug ended and what went to and from it.
+#[tokio::test]
+async fn prints_a_shutdown_summary() {
+    let source = WsPlug::bind().await.expect("bind source plug");
+    let sink = WsPlug::bind().await.expect("bind sink plug");
+    let config = forward_config(&source, &sink);
+
+    let child = kble(&config)
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .expect("spawn kble orchestrator");
+    let (source, sink) = tokio::join!(source.accept(), sink.accept());
+    let mut source = source.expect("orchestrator connects to source plug");
+    let mut sink = sink.expect("orchestrator connects to sink plug");
+
+    for payload in [&b"first"[..], &b"second"[..]] {
+        source
+            .send(Bytes::from_static(payload))
+            .await
+            .expect("source send");
+        sink.recv().await.expect("sink recv");
+    }
+    drop(source);
+    let drain_sink = async { while sink.recv().await.is_ok() {} };
+    let output = async {
+        tokio::time::timeout(Duration::from_secs(10), child.wait_with_output())
+            .await
+            .expect("orchestrator should exit after its source link closes")
+            .expect("wait for orchestrator")
+    };
+    let (_, output) = tokio::join!(drain_sink, output);
+    assert!(
+        output.status.success(),
+        "orchestrator exited with {}",
+        output.status
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("source -> sink: 2 messages, 11 bytes, "),
+        "{stderr}"
+    );
+    assert!(
+        stderr.contains(
+            "  source: disconnected; 2 messages (11 bytes) from it, 0 messages (0 bytes) to it in "
+        ),
+        "{stderr}"
+    );
+    assert!(
+        stderr.contains(
+            "  sink: disconnected; 0 messages (0 bytes) from it, 2 messages (11 bytes) to it in "
+        ),
+        "{stderr}"
+    );
+}
+
+/// SIGHUP reloads the spaghetti file: a link rewired to a newly added plug
+/// carries the next frame there, while the source plug, untouched, keeps its
+/// connection.
+#[cfg(unix)]
+#[tokio::test]
+async fn rewires_a_link_on_sighup() {
+    let source = WsPlug::bind().await.expect("bind source plug");
+    let old = WsPlug::bind().await.expect("bind old sink plug");
+    let new = WsPlug::bind().await.expect("bind new sink plug");
+    let (source_url, old_url) = (source.url().to_string(), old.url().to_string());
+    let config = write_spaghetti(&format!(
+        "plugs:\n  source: {source_url}\n  old: {old_url}\nlinks:\n  source: old\n"
+    ));
+
+    let child = kble(&config)
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .expect("spawn kble orchestrator");
+    let (source_conn, old) = tokio::join!(source.accept(), old.accept());
+    let mut source_conn = source_conn.expect("orchestrator connects to source plug");
+    let mut old = old.expect("orchestrator connects to old sink plug");
+
+    source_conn
+        .send(Bytes::from_static(b"before"))
+        .await
+        .expect("source send");
+    assert_eq!(old.recv().await.expect("old sink recv"), "before");
+
+    std::fs::write(
+        &config,
+        format!(
+            "plugs:\n  source: {source_url}\n  old: {old_url}\n  new: {}\nlinks:\n  source: new\n",
+            new.url(),
+        ),
+    )
+    .expect("rewrite spaghetti config");
+    let pid = child.id().expect("orchestrator is running").to_string();
+    let kill = Command::new("kill")
+        .args(["-HUP", &pid])
+        .status()
+        .await
+        .expect("run kill");
+    assert!(kill.success());
+    let mut new = new
+        .accept()
+        .await
+        .expect("orchestrator connects to new sink plug");
+
+    source_conn
+        .send(Bytes::from_static(b"after"))
+        .await
+        .expect("source send");
+    assert_eq!(new.recv().await.expect("new sink recv"), "after");
+
+    drop(source_conn);
+    let drain_sinks = async {
+        tokio::join!(async { while old.recv().await.is_ok() {} }, async {
+            while new.recv().await.is_ok() {}
+        },)
+    };
+    let output = async {
+        tokio::time::timeout(Duration::from_secs(10), child.wait_with_output())
+            .await
+            .expect("orchestrator should exit after its source link closes")
+            .expect("wait for orchestrator")
+    };
+    let (_, output) = tokio::join!(drain_sinks, output);
+    assert!(
+        output.status.success(),
+        "orchestrator exited with {}",
+        output.status
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("source -> old: 1 messages, 6 bytes, stopped by a reload"),
+        "{stderr}"
+    );
+    assert!(
+        stderr.contains("source -> new: 1 messages, 5 bytes, "),
+        "{stderr}"
+    );
+}
+
+/// A spaghetti file that doesn't validate exits with the config error code,
+/// before connecting to anything.
+#[tokio::test]
+async fn exits_with_the_config_error_code() {
+    let config =
+        write_spaghetti("plugs:\n  source: ws://127.0.0.1:1/\nlinks:\n  source: missing\n");
+
+    let status = tokio::time::timeout(Duration::from_secs(10), kble(&config).status())
+        .await
+        .expect("orchestrator should exit on an invalid config")
+        .expect("wait for orchestrator");
+    assert_eq!(status.code(), Some(2), "orchestrator exited with {status}");
+}
+
+/// A `.toml` spaghetti file is parsed as TOML, not YAML.
+#[tokio::test]
+async fn forwards_with_a_toml_spaghetti_file() {
+    let source = WsPlug::bind().await.expect("bind source plug");
+    let sink = WsPlug::bind().await.expect("bind sink plug");
+    let toml = format!(
+        "[plugs]\nsource = \"{}\"\nsink = \"{}\"\n\n[links]\nsource = \"sink\"\n",
+        source.url(),
+        sink.url(),
+    );
+    let config = write_tmp("spaghetti", "toml", &toml);
+
+    let child = kble(&config).spawn().expect("spawn kble orchestrator");
+    let (source, sink) = tokio::join!(source.accept(), sink.accept());
+    let mut source = source.expect("orchestrator connects to source plug");
+    let mut sink = sink.expect("orchestrator connects to sink plug");
+
+    let payload = Bytes::from_static(b"hello, toml");
+    source.send(payload.clone()).await.expect("source send");
+    assert_eq!(sink.recv().await.expect("sink recv"), payload);
+
+    shutdown_and_assert_clean_exit(child, source, sink).await;
+}
+
+/// A link applies its transforms in order, and a dropped message never reaches
+/// the sink.
+#[tokio::test]
+async fn applies_a_links_transforms() {
+    let source = WsPlug::bind().await.expect("bind source plug");
+    let sink = WsPlug::bind().await.expect("bind sink plug");
+    let yaml = format!(
+        "plugs:\n  source: {}\n  sink: {}\nlinks:\n  source:\n    to: sink\n    transforms:\n      - drop_matching: \"ff\"\n      - strip_prefix: 2\n      - prepend: \"eb90\"\n      - truncate: 4\n",
+        source.url(),
+        sink.url(),
+    );
+    let config = write_spaghetti(&yaml);
+
+    let child = kble(&config).spawn().expect("spawn kble orchestrator");
+    let (source, sink) = tokio::join!(source.accept(), sink.accept());
+    let mut source = source.expect("orchestrator connects to source plug");
+    let mut sink = sink.expect("orchestrator connects to sink plug");
+
+    for frame in [&b"\xffdropped"[..], b"hdabcdef"] {
+        source
+            .send(Bytes::copy_from_slice(frame))
+            .await
+            .expect("source send");
+    }
+    let got = sink.recv().await.expect("sink recv");
+    assert_eq!(got, Bytes::from_static(b"\xeb\x90ab"));
+
+    shutdown_and_assert_clean_exit(child, source, sink).await;
+}
+
+/// An impaired link delays each message by its latency, and a message that is
+/// duplicated reaches the sink twice.
+#[tokio::test]
+async fn delays_and_duplicates_on_an_impaired_link() {
+    let source = WsPlug::bind().await.expect("bind source plug");
+    let sink = WsPlug::bind().await.expect("bind sink plug");
+    let yaml = format!(
+        "plugs:\n  source: {}\n  sink: {}\nlinks:\n  source:\n    to: sink\n    impairment:\n      seed: 1\n      latency_ms: 300\n      duplicate_probability: 1\n",
+        source.url(),
+        sink.url(),
+    );
+    let config = write_spaghetti(&yaml);
+
+    let child = kble(&config).spawn().expect("spawn kble orchestrator");
+    let (source, sink) = tokio::join!(source.accept(), sink.accept());
+    let mut source = source.expect("orchestrator connects to source plug");
+    let mut sink = sink.expect("orchestrator connects to sink plug");
+
+    let payload = Bytes::from_static(b"hello, lossy link");
+    let sent = std::time::Instant::now();
+    source.send(payload.clone()).await.expect("source send");
+    assert_eq!(sink.recv().await.expect("sink recv"), payload);
+    assert!(
+        sent.elapsed() >= Duration::from_millis(300),
+        "delivered after {:?}",
+        sent.elapsed()
+    );
+    assert_eq!(sink.recv().await.expect("sink recv duplicate"), payload);
+
+    shutdown_and_assert_clean_exit(child, source, sink).await;
+}
+
+/// A throttled link splits messages into chunks and delivers each once it
+/// would have been transmitted at the configured rate.
+#[tokio::test]
+async fn paces_a_throttled_link() {
+    let source = WsPlug::bind().await.expect("bind source plug");
+    let sink = WsPlug::bind().await.expect("bind sink plug");
+    let yaml = format!(
+        "plugs:\n  source: {}\n  sink: {}\nlinks:\n  source:\n    to: sink\n    throttle:\n      bits_per_second: 8000\n      chunk_bytes: 50\n",
+        source.url(),
+        sink.url(),
+    );
+    let config = write_spaghetti(&yaml);
+
+    let child = kble(&config).spawn().expect("spawn kble orchestrator");
+    let (source, sink) = tokio::join!(source.accept(), sink.accept());
+    let mut source = source.expect("orchestrator connects to source plug");
+    let mut sink = sink.expect("orchestrator connects to sink plug");
+
+    // Two chunks of 400 bits at 8000 bps: 50ms each
+    let payload = Bytes::from(vec![0x55; 100]);
+    let sent = std::time::Instant::now();
+    source.send(payload.clone()).await.expect("source send");
+    assert_eq!(sink.recv().await.expect("sink recv"), payload.slice(..50));
+    assert_eq!(sink.recv().await.expect("sink recv"), payload.slice(50..));
+    assert!(
+        sent.elapsed() >= Duration::from_millis(100),
+        "delivered after {:?}",
+        sent.elapsed()
+    );
+
+    shutdown_and_assert_clean_exit(child, source, sink).await;
+}
+
+/// `--log-format json` makes the orchestrator log JSON lines carrying the
+/// plug and link they concern, and hands the format and the plug name on to
+/// `exec:` plugs.
+#[tokio::test]
+async fn logs_json_lines_with_plug_and_link_names() {
+    let source = WsPlug::bind().await.expect("bind source plug");
+    let sink = WsPlug::bind().await.expect("bind sink plug");
+    let eb90 = plug_bin("kble-eb90");
+    let yaml = format!(
+        "plugs:\n  source: {}\n  enc: exec:echo $KBLE_PLUG_NAME $KBLE_LOG_FORMAT >&2; exec {} encode\n  sink: {}\nlinks:\n  source: enc\n  enc: sink\n",
+        source.url(),
+        eb90.display(),
+        sink.url(),
+    );
+    let config = write_spaghetti(&yaml);
+
+    let child = kble(&config)
+        .args(["--log-format", "json"])
+        .env("RUST_LOG", "kble=trace")
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .expect("spawn kble orchestrator");
+    let (source, sink) = tokio::join!(source.accept(), sink.accept());
+    let mut source = source.expect("orchestrator connects to source plug");
+    let mut sink = sink.expect("orchestrator connects to sink plug");
+
+    source
+        .send(Bytes::from_static(b"hello"))
+        .await
+        .expect("source send");
+    sink.recv().await.expect("sink recv");
+    drop(source);
+    let drain_sink = async { while sink.recv().await.is_ok() {} };
+    let output = async {
+        tokio::time::timeout(Duration::from_secs(10), child.wait_with_output())
+            .await
+            .expect("orchestrator should exit after its source link closes")
+            .expect("wait for orchestrator")
+    };
+    let (_, output) = tokio::join!(drain_sink, output);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert!(stderr.lines().any(|line| line == "enc json"), "{stderr}");
+    let logs = stderr
+        .lines()
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .collect::<Vec<_>>();
+    assert!(
+        logs.iter().any(|log| log["span"]["plug"] == "enc"),
+        "{stderr}"
+    );
+    assert!(
+        logs.iter()
+            .any(|log| log["span"]["link"] == "source -> enc"),
+        "{stderr}"
+    );
+}
+
+/// A message's envelope survives the orchestrator and a `kble-dump record`
+/// plug: one the source sent is kept, and one for a bare message is made by
+/// the orchestrator, with the source plug's name as the origin.
+#[tokio::test]
+async fn preserves_envelopes_end_to_end() {
+    let source = WsPlug::bind().await.expect("bind source plug");
+    let sink = WsPlug::bind().await.expect("bind sink plug");
+    let dump_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("envelope-dumps");
+    let yaml = format!(
+        "plugs:\n  source: {}\n  rec: exec:{} record {}\n  sink: {}\nlinks:\n  source: rec\n  rec: sink\n",
+        source.url(),
+        plug_bin("kble-dump").display(),
+        dump_dir.display(),
+        sink.url(),
+    );
+    let child = kble(&write_spaghetti(&yaml))
+        .spawn()
+        .expect("spawn kble orchestrator");
+    let (source, sink) = tokio::join!(source.accept_enveloped(), sink.accept_enveloped());
+    let mut source = source.expect("orchestrator connects to source plug");
+    let mut sink = sink.expect("orchestrator connects to sink plug");
+
+    // Messages sent before each hop has agreed on envelopes get a new one
+    // along the way, or none on the last hop, so retry until ours makes it
+    // through.
+    let received_at = std::time::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    let stamped = Enveloped {
+        envelope: Some(Envelope {
+            received_at,
+            sequence: 7,
+            origin: None,
+        }),
+        data: Bytes::from_static(b"stamped"),
+    };
+    let envelope = loop {
+        source
+            .send_enveloped(stamped.clone())
+            .await
+            .expect("source send");
+        let got = sink.recv_enveloped().await.expect("sink recv");
+        assert_eq!(got.data, stamped.data);
+        if let Some(envelope) = got.envelope.filter(|e| e.received_at == received_at) {
+            break envelope;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    };
+    assert_eq!(envelope.sequence, 7);
+    assert_eq!(envelope.origin.as_deref(), Some("source"));
+
+    let before = std::time::SystemTime::now();
+    let mut sequences = vec![];
+    for _ in 0..2 {
+        source
+            .send(Bytes::from_static(b"bare"))
+            .await
+            .expect("source send");
+        let envelope = sink
+            .recv_enveloped()
+            .await
+            .expect("sink recv")
+            .envelope
+            .expect("an envelope made by kble");
+        assert_eq!(envelope.origin.as_deref(), Some("source"));
+        assert!(envelope.received_at >= before);
+        sequences.push(envelope.sequence);
+    }
+    assert_eq!(sequences[1], sequences[0] + 1);
+
+    shutdown_and_assert_clean_exit(child, source, sink).await;
+}
+
+/// The orchestrator numbers what it delivers: each throttle chunk gets its own
+/// sequence number, and a message dropped by a transform leaves no gap.
+#[tokio::test]
+async fn numbers_each_delivered_chunk_in_sequence() {
+    let source = WsPlug::bind().await.expect("bind source plug");
+    let sink = WsPlug::bind().await.expect("bind sink plug");
+    let yaml = format!(
+        "plugs:\n  source: {}\n  sink: {}\nlinks:\n  source:\n    to: sink\n    transforms:\n      - drop_matching: \"ff\"\n    throttle:\n      bits_per_second: 80000\n      chunk_bytes: 50\n",
+        source.url(),
+        sink.url(),
+    );
+    let child = kble(&write_spaghetti(&yaml))
+        .spawn()
+        .expect("spawn kble orchestrator");
+    let (source, sink) = tokio::join!(source.accept(), sink.accept_enveloped());
+    let mut source = source.expect("orchestrator connects to source plug");
+    let mut sink = sink.expect("orchestrator connects to sink plug");
+
+    // Messages sent before the sink has agreed on envelopes arrive bare
+    let mut recv_sequence = async || {
+        let got = sink.recv_enveloped().await.expect("sink recv");
+        got.envelope.map(|envelope| envelope.sequence)
+    };
+    let first = loop {
+        source
+            .send(Bytes::from_static(b"hello"))
+            .await
+            .expect("source send");
+        if let Some(sequence) = recv_sequence().await {
+            break sequence;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    };
+    for msg in [vec![0x55; 100], vec![0xff], b"after".to_vec()] {
+        source.send(Bytes::from(msg)).await.expect("source send");
+    }
+    let mut sequences = vec![];
+    for _ in 0..3 {
+        sequences.push(recv_sequence().await.expect("an envelope"));
+    }
+    assert_eq!(sequences, [first + 1, first + 2, first + 3]);
+
+    shutdown_and_assert_clean_exit(child, source, sink).await;
+}
+
+/// `kble schema` prints a JSON Schema of the spaghetti file without needing
+/// `--spaghetti`.
+#[tokio::test]
+async fn prints_the_spaghetti_json_schema() {
+    let output = Command::new(env!("CARGO_BIN_EXE_kble"))
+        .arg("schema")
+        .output()
+        .await
+        .expect("run kble schema");
+    assert!(
+        output.status.success(),
+        "kble schema exited with {}",
+        output.status
+    );
+
+    let schema: serde_json::Value = serde_json::from_slice(&output.stdout).expect("schema is JSON");
+    assert_eq!(schema["title"], "Spaghetti");
+    assert!(schema["properties"]["plugs"].is_object());
+    assert!(schema["properties"]["links"].is_object());
+}
+
+/// A `wss://` plug whose certificate is issued by a private CA for a name other
+/// than the URL host connects once the spaghetti file supplies the CA bundle
+/// and the SNI override.
+#[tokio::test]
+async fn forwards_over_wss_with_custom_ca_and_server_name() {
+    let pki = TestPki::generate();
+    let tls = format!(
+        "    tls:\n      ca: {}\n      server_name: localhost\n",
+        pki.ca_pem.display()
+    );
+    assert_forwards_over_wss(pki.acceptor(false), &tls).await;
+}
+
+/// Against a server that requires mTLS, the configured client certificate and
+/// key are presented and accepted.
+#[tokio::test]
+async fn forwards_over_wss_with_a_client_certificate() {
+    let pki = TestPki::generate();
+    let tls = format!(
+        "    tls:\n      ca: {}\n      server_name: localhost\n      client_cert: {}\n      \
+         client_key: {}\n",
+        pki.ca_pem.display(),
+        pki.client_cert_pem.display(),
+        pki.client_key_pem.display(),
+    );
+    assert_forwards_over_wss(pki.acceptor(true), &tls).await;
+}
+
+/// Relative TLS paths are resolved against the spaghetti file's directory
+/// (where the test PKI is written), not the orchestrator's working directory.
+#[tokio::test]
+async fn resolves_tls_paths_relative_to_the_spaghetti_file() {
+    let pki = TestPki::generate();
+    let file_name = |path: &Path| path.file_name().unwrap().to_str().unwrap().to_owned();
+    let tls = format!(
+        "    tls:\n      ca: {}\n      server_name: localhost\n      client_cert: {}\n      \
+         client_key: {}\n",
+        file_name(&pki.ca_pem),
+        file_name(&pki.client_cert_pem),
+        file_name(&pki.client_key_pem),
+    );
+    assert_forwards_over_wss(pki.acceptor(true), &tls).await;
+}
+
+/// `accept_invalid_certs` connects to an untrusted server without any CA.
+#[tokio::test]
+async fn forwards_over_wss_accepting_invalid_certs() {
+    let pki = TestPki::generate();
+    let tls = "    tls:\n      accept_invalid_certs: true\n";
+    assert_forwards_over_wss(pki.acceptor(false), tls).await;
+}
+
+/// Without TLS options the server is checked against the built-in roots, which
+/// do not include the private CA: the connection must be refused.
+#[tokio::test]
+async fn refuses_wss_signed_by_an_untrusted_ca() {
+    let pki = TestPki::generate();
+    assert_refuses_wss(pki.acceptor(false), "").await;
+}
+
+/// Trusting the CA is not enough when the certificate does not cover the URL
+/// host and no `server_name` override is given.
+#[tokio::test]
+async fn refuses_wss_with_a_mismatched_server_name() {
+    let pki = TestPki::generate();
+    let tls = format!("    tls:\n      ca: {}\n", pki.ca_pem.display());
+    assert_refuses_wss(pki.acceptor(false), &tls).await;
+}
+
+/// A server requiring mTLS rejects the orchestrator when no client certificate
+/// is configured.
+#[tokio::test]
+async fn refuses_wss_without_a_required_client_certificate() {
+    let pki = TestPki::generate();
+    let tls = format!(
+        "    tls:\n      ca: {}\n      server_name: localhost\n",
+        pki.ca_pem.display()
+    );
+    assert_refuses_wss(pki.acceptor(true), &tls).await;
+}
+
 proptest! {
     // Each case spawns an orchestrator process plus two in-process ws servers,
     // so keep the count modest. Integration tests have no crate-root source